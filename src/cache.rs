@@ -0,0 +1,223 @@
+use std::collections::{BTreeSet, HashMap};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use trust_dns_client::op::{Message, ResponseCode};
+use trust_dns_client::proto::rr::dnssec::rdata::DNSSECRecordType;
+use trust_dns_client::rr;
+
+/// Longest a response is kept, whatever its TTLs.
+const MAX_CACHE_TTL: Duration = Duration::from_secs(3600);
+
+/// What a cached response answers.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+  /// Referral from a server of a zone to a zone below it, good for any
+  /// name in the zone referred to.
+  Referral { server: IpAddr, zone: rr::Name, referred: rr::Name },
+  /// Answer of a zone's server for the zone's SOA or NS.
+  Apex { server: IpAddr, zone: rr::Name, rtype: rr::RecordType },
+}
+
+/// Referrals and zone apex answers shared between checks, so names under
+/// zones already seen aren't resolved from the root again.
+///
+/// Apex answers are only used for zones above the ones being checked, the
+/// zones holding answer targets are always asked again. DS queries, which
+/// parent servers answer themselves, aren't cached.
+#[derive(Debug)]
+pub struct ReferralCache {
+  responses: Mutex<HashMap<Key, (Message, Instant)>>,
+}
+
+impl ReferralCache {
+  pub fn new() -> ReferralCache {
+    ReferralCache { responses: Mutex::new(HashMap::new()) }
+  }
+
+  /// Response of a server to a query for a name in a zone, if one can be
+  /// given without asking.
+  pub fn get(&self, server: IpAddr, name: &rr::Name, rtype: rr::RecordType,
+             zone: Option<&rr::Name>, target_zones: &BTreeSet<rr::Name>) -> Option<Message> {
+    if rtype == rr::RecordType::DNSSEC(DNSSECRecordType::DS) {
+      return None;
+    }
+
+    let mut responses = self.responses.lock().unwrap();
+    let now = Instant::now();
+    responses.retain(|_, (_, expires)| *expires > now);
+
+    match zone {
+      // The deepest referral known for the name.
+      Some(zone) => {
+        let mut referred = name.clone();
+        while zone.zone_of(&referred) && referred != *zone {
+          let key = Key::Referral { server, zone: zone.clone(), referred: referred.clone() };
+          if let Some((message, _)) = responses.get(&key) {
+            return Some(message.clone());
+          }
+          referred = referred.base_name();
+        }
+        None
+      },
+      None if !target_zones.contains(name) => {
+        let key = Key::Apex { server, zone: name.clone(), rtype };
+        responses.get(&key).map(|(message, _)| message.clone())
+      },
+      None => None,
+    }
+  }
+
+  /// Keep a response, if it's a referral or an apex answer.
+  pub fn add(&self, server: IpAddr, name: &rr::Name, rtype: rr::RecordType,
+             zone: Option<&rr::Name>, message: &Message) {
+    if rtype == rr::RecordType::DNSSEC(DNSSECRecordType::DS)
+        || message.response_code() != ResponseCode::NoError {
+      return;
+    }
+
+    let key = match zone {
+      Some(zone) => {
+        if message.authoritative() || !message.answers().is_empty() {
+          return;
+        }
+        let referred = message.name_servers().iter()
+          .find(|rec| rec.rr_type() == rr::RecordType::NS)
+          .map(|rec| rec.name().clone());
+        match referred {
+          Some(referred) if referred != *zone && zone.zone_of(&referred)
+              && referred.zone_of(name) =>
+            Key::Referral { server, zone: zone.clone(), referred },
+          _ => return,
+        }
+      },
+      None => {
+        let apex = rtype == rr::RecordType::SOA || rtype == rr::RecordType::NS;
+        if !apex || !message.authoritative()
+            || !message.answers().iter().any(|rec| rec.name() == name && rec.rr_type() == rtype) {
+          return;
+        }
+        Key::Apex { server, zone: name.clone(), rtype }
+      },
+    };
+
+    let ttl = message.answers().iter()
+      .chain(message.name_servers())
+      .chain(message.additionals())
+      .map(|rec| Duration::from_secs(rec.ttl() as u64))
+      .min()
+      .unwrap_or_default()
+      .min(MAX_CACHE_TTL);
+    if ttl > Duration::from_secs(0) {
+      self.responses.lock().unwrap().insert(key, (message.clone(), Instant::now() + ttl));
+    }
+  }
+}
+
+impl Default for ReferralCache {
+  fn default() -> ReferralCache {
+    ReferralCache::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::str::FromStr;
+  use std::thread;
+
+  use trust_dns_client::op::MessageType;
+
+  use super::*;
+
+  fn name(name: &str) -> rr::Name {
+    rr::Name::from_str(name).unwrap()
+  }
+
+  fn server() -> IpAddr {
+    "192.0.2.1".parse().unwrap()
+  }
+
+  /// Referral to example.com from a com server.
+  fn referral(ttl: u32) -> Message {
+    let mut msg = Message::new();
+    msg.set_message_type(MessageType::Response);
+    msg.add_name_server(rr::Record::from_rdata(name("example.com."), ttl,
+                                               rr::RData::NS(name("ns1.example.com."))));
+    msg.add_additional(rr::Record::from_rdata(name("ns1.example.com."), ttl,
+                                              rr::RData::A("192.0.2.53".parse().unwrap())));
+    msg
+  }
+
+  fn apex_answer() -> Message {
+    let mut msg = Message::new();
+    msg.set_message_type(MessageType::Response);
+    msg.set_authoritative(true);
+    msg.add_answer(rr::Record::from_rdata(name("com."), 900, rr::RData::SOA(rr::rdata::SOA::new(
+      name("a.gtld-servers.net."), name("nstld.verisign-grs.com."), 1, 1800, 900, 604800, 86400))));
+    msg
+  }
+
+  #[test]
+  fn referrals_only_for_names_under_referred_zone() {
+    let cache = ReferralCache::new();
+    let com = name("com.");
+    let none = BTreeSet::new();
+    cache.add(server(), &name("www.example.com."), rr::RecordType::A, Some(&com), &referral(3600));
+
+    assert!(cache.get(server(), &name("mail.example.com."), rr::RecordType::MX, Some(&com), &none)
+            .is_some());
+    assert!(cache.get(server(), &name("example.com."), rr::RecordType::SOA, Some(&com), &none)
+            .is_some());
+    assert!(cache.get(server(), &name("example.net."), rr::RecordType::A, Some(&com), &none)
+            .is_none());
+    assert!(cache.get(server(), &name("www.example.org."), rr::RecordType::A, Some(&name("org.")),
+                      &none).is_none());
+    assert!(cache.get("192.0.2.2".parse().unwrap(), &name("www.example.com."), rr::RecordType::A,
+                      Some(&com), &none).is_none());
+  }
+
+  #[test]
+  fn ds_never_cached() {
+    let cache = ReferralCache::new();
+    let com = name("com.");
+    let none = BTreeSet::new();
+    let ds = rr::RecordType::DNSSEC(DNSSECRecordType::DS);
+    cache.add(server(), &name("example.com."), ds, Some(&com), &referral(3600));
+    assert!(cache.get(server(), &name("www.example.com."), rr::RecordType::A, Some(&com), &none)
+            .is_none());
+
+    // Parent servers answer DS themselves, a referral isn't the answer.
+    cache.add(server(), &name("www.example.com."), rr::RecordType::A, Some(&com), &referral(3600));
+    assert!(cache.get(server(), &name("example.com."), ds, Some(&com), &none).is_none());
+  }
+
+  #[test]
+  fn target_zone_apex_asked_again() {
+    let cache = ReferralCache::new();
+    cache.add(server(), &name("com."), rr::RecordType::SOA, None, &apex_answer());
+
+    assert!(cache.get(server(), &name("com."), rr::RecordType::SOA, None, &BTreeSet::new())
+            .is_some());
+    let targets = vec![name("com.")].into_iter().collect();
+    assert!(cache.get(server(), &name("com."), rr::RecordType::SOA, None, &targets).is_none());
+    assert!(cache.get(server(), &name("com."), rr::RecordType::NS, None, &BTreeSet::new())
+            .is_none());
+  }
+
+  #[test]
+  fn responses_expire() {
+    let cache = ReferralCache::new();
+    let com = name("com.");
+    let none = BTreeSet::new();
+    let www = name("www.example.com.");
+
+    cache.add(server(), &www, rr::RecordType::A, Some(&com), &referral(0));
+    assert!(cache.get(server(), &www, rr::RecordType::A, Some(&com), &none).is_none());
+
+    cache.add(server(), &www, rr::RecordType::A, Some(&com), &referral(1));
+    assert!(cache.get(server(), &www, rr::RecordType::A, Some(&com), &none).is_some());
+    thread::sleep(Duration::from_millis(1100));
+    assert!(cache.get(server(), &www, rr::RecordType::A, Some(&com), &none).is_none());
+  }
+}
@@ -0,0 +1,234 @@
+use trust_dns_client::rr;
+
+use crate::db::{self, AliasKind, REntry, RecordDB};
+use crate::findings::{Check, Finding, Severity};
+
+/// A path through aliases, starting at an answer target.
+struct Chain {
+  names: Vec<rr::Name>,
+  kinds: Vec<AliasKind>,
+  /// Whether the last name was already in the chain.
+  looped: bool,
+}
+
+impl Chain {
+  fn describe(&self) -> String {
+    let mut out = self.names[0].to_string();
+    for (kind, name) in self.kinds.iter().zip(self.names.iter().skip(1)) {
+      let kind = match kind {
+        AliasKind::CName => "CNAME",
+        AliasKind::DName => "DNAME",
+      };
+      out.push_str(&format!(" {} {}", kind, name));
+    }
+    out
+  }
+}
+
+/// Find all alias chains starting at the given name.
+fn find_chains(record_db: &RecordDB, name: &rr::Name, rtype: rr::RecordType) -> Vec<Chain> {
+  let mut chains = Vec::new();
+  let mut stack = vec![Chain { names: vec![name.clone()], kinds: Vec::new(), looped: false }];
+
+  while let Some(chain) = stack.pop() {
+    let last = chain.names.last().unwrap();
+    let targets = record_db.get_aliases(last, rtype);
+
+    if targets.is_empty() {
+      chains.push(chain);
+      continue;
+    }
+
+    for (target, kind) in targets {
+      let mut next = Chain {
+        names: chain.names.clone(),
+        kinds: chain.kinds.clone(),
+        looped: chain.names.contains(&target),
+      };
+      next.names.push(target);
+      next.kinds.push(kind);
+
+      if next.looped || next.kinds.len() > db::MAX_ALIAS_CHAIN {
+        chains.push(next);
+      } else {
+        stack.push(next);
+      }
+    }
+  }
+
+  chains
+}
+
+/// Check CNAME and DNAME chains of answer targets.
+///
+/// Every chain is reported, along with loops, chains longer than we're
+/// willing to follow, and chains that end at a name without records of the
+/// requested type.
+pub fn check_chains(record_db: &RecordDB) -> Vec<Finding> {
+  let mut findings = Vec::new();
+
+  for (name, rtype) in record_db.get_answer_targets() {
+    let targets = record_db.get_aliases(&name, rtype);
+    if targets.is_empty() {
+      continue;
+    }
+
+    if targets.len() > 1 {
+      let targets: Vec<_> = targets.keys().map(|t| t.to_string()).collect();
+      findings.push(Finding::new(
+        Severity::Warning, Check::Alias, &name, None,
+        format!("servers disagree on the alias target: {}", targets.join(", "))
+      ));
+    }
+
+    let is_start = !record_db.is_alias_target(&name, rtype);
+
+    for chain in find_chains(record_db, &name, rtype) {
+      let last = chain.names.last().unwrap();
+
+      if chain.looped {
+        // Report a loop with no way in once, from its smallest name.
+        let in_loop = last == &name;
+        let smallest = chain.names.iter().min().unwrap() == &name;
+        if is_start || (in_loop && smallest) {
+          findings.push(Finding::new(
+            Severity::Error, Check::Alias, &name, None,
+            format!("alias loop: {}", chain.describe())
+          ));
+        }
+        continue;
+      }
+
+      if !is_start {
+        continue;
+      }
+
+      findings.push(Finding::new(
+        Severity::Info, Check::Alias, &name, None,
+        format!("alias chain for {}: {}", rtype, chain.describe())
+      ));
+
+      if chain.kinds.len() > db::MAX_ALIAS_CHAIN {
+        findings.push(Finding::new(
+          Severity::Error, Check::Alias, &name, None,
+          format!("alias chain is longer than {} hops", db::MAX_ALIAS_CHAIN)
+        ));
+        continue;
+      }
+
+      let answered = record_db.get_records(last).values()
+        .any(|entries| matches!(entries.get(&rtype), Some(REntry::Entries(_))));
+      if answered {
+        continue;
+      }
+
+      if let Some(servers) = record_db.get_negative_answers().get(&(last.clone(), rtype)) {
+        if servers.values().any(|negative| negative.nxdomain) {
+          findings.push(Finding::new(
+            Severity::Error, Check::Alias, &name, None,
+            format!("dangling alias, {} doesn't exist", last)
+          ));
+        } else {
+          findings.push(Finding::new(
+            Severity::Warning, Check::Alias, &name, None,
+            format!("alias chain ends at {}, which has no {} records", last, rtype)
+          ));
+        }
+      }
+    }
+  }
+
+  findings
+}
+
+#[cfg(test)]
+mod tests {
+  use std::net::IpAddr;
+  use std::str::FromStr;
+
+  use super::*;
+  use crate::db::NegativeAnswer;
+
+  fn name(name: &str) -> rr::Name {
+    rr::Name::from_str(name).unwrap()
+  }
+
+  /// Database asking for the A records of the first name, which aliases
+  /// each following name in turn.
+  fn record_db(names: &[&str]) -> RecordDB {
+    let mut db = RecordDB::new();
+    db.add_answer_target(&name(names[0]), rr::RecordType::A);
+    for pair in names.windows(2) {
+      db.add_alias(&name(pair[0]), rr::RecordType::A, &name(pair[1]), AliasKind::CName);
+    }
+    db
+  }
+
+  fn add_negative(db: &mut RecordDB, owner: &str, nxdomain: bool) {
+    let server: IpAddr = "192.0.2.1".parse().unwrap();
+    db.add_negative_answer(&name(owner), rr::RecordType::A, server,
+                           NegativeAnswer { nxdomain, authority: Vec::new() });
+  }
+
+  fn messages(findings: &[Finding]) -> Vec<(Severity, String)> {
+    findings.iter().map(|f| (f.severity, f.message.clone())).collect()
+  }
+
+  #[test]
+  fn answered_chain_described() {
+    let mut db = record_db(&["www.example.com.", "web.example.net."]);
+    db.add_record(&rr::Record::from_rdata(name("web.example.net."), 300,
+      rr::RData::A("192.0.2.80".parse().unwrap())), "192.0.2.1".parse().unwrap());
+    assert_eq!(messages(&check_chains(&db)), vec![
+      (Severity::Info, "alias chain for A: www.example.com. CNAME web.example.net.".to_string()),
+    ]);
+  }
+
+  #[test]
+  fn loop_flagged() {
+    let mut db = record_db(&["a.example.com.", "b.example.com.", "a.example.com."]);
+    db.add_answer_target(&name("b.example.com."), rr::RecordType::A);
+    // Reported once, from the smallest name in the loop.
+    assert_eq!(messages(&check_chains(&db)), vec![
+      (Severity::Error,
+       "alias loop: a.example.com. CNAME b.example.com. CNAME a.example.com.".to_string()),
+    ]);
+  }
+
+  #[test]
+  fn loop_reached_from_outside_flagged() {
+    let db = record_db(&["www.example.com.", "a.example.com.", "b.example.com.", "a.example.com."]);
+    let findings = check_chains(&db);
+    assert_eq!(findings.len(), 1);
+    assert!(findings[0].message.starts_with("alias loop: www.example.com. CNAME"));
+  }
+
+  #[test]
+  fn long_chain_flagged() {
+    let names: Vec<_> = (0..=db::MAX_ALIAS_CHAIN + 1)
+      .map(|n| format!("n{}.example.com.", n))
+      .collect();
+    let names: Vec<_> = names.iter().map(|n| n.as_str()).collect();
+    let findings = check_chains(&record_db(&names));
+    assert_eq!(findings.len(), 2);
+    assert_eq!(findings[1].severity, Severity::Error);
+    assert_eq!(findings[1].message,
+               format!("alias chain is longer than {} hops", db::MAX_ALIAS_CHAIN));
+  }
+
+  #[test]
+  fn dangling_alias_flagged() {
+    let mut db = record_db(&["www.example.com.", "gone.example.net."]);
+    add_negative(&mut db, "gone.example.net.", true);
+    let findings = messages(&check_chains(&db));
+    assert_eq!(findings[1],
+               (Severity::Error, "dangling alias, gone.example.net. doesn't exist".to_string()));
+
+    let mut db = record_db(&["www.example.com.", "web.example.net."]);
+    add_negative(&mut db, "web.example.net.", false);
+    let findings = messages(&check_chains(&db));
+    assert_eq!(findings[1],
+               (Severity::Warning,
+                "alias chain ends at web.example.net., which has no A records".to_string()));
+  }
+}
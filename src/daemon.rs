@@ -0,0 +1,284 @@
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::{error, info};
+use serde::Serialize;
+use syslog::{Facility, Formatter3164, Logger, LoggerBackend};
+use trust_dns_client::rr;
+
+use crate::db::RecordDB;
+use crate::diff::{self, Change, ChangeKind};
+use crate::findings::Finding;
+use crate::snapshot;
+use crate::Checker;
+
+/// How long to wait for a webhook to take an alert.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often to check, and where alerts go besides stdout.
+#[derive(Debug, Clone)]
+pub struct Config {
+  /// Time from the start of one round of checks to the next.
+  pub interval: Duration,
+  /// Send alerts to the local syslog.
+  pub syslog: bool,
+  /// URL to POST alerts to, as JSON.
+  pub webhook: Option<String>,
+}
+
+impl Default for Config {
+  fn default() -> Config {
+    Config {
+      interval: Duration::from_secs(300),
+      syslog: false,
+      webhook: None,
+    }
+  }
+}
+
+/// The findings of a domain changed.
+#[derive(Debug, Serialize)]
+pub struct Alert {
+  pub domain: String,
+  /// When the check finished, in seconds since the epoch.
+  pub time: u64,
+  /// Every finding of the check, empty once the domain recovered.
+  pub findings: Vec<String>,
+  /// Findings added and removed, then what changed in the delegations
+  /// since the last check.
+  pub changes: Vec<Change>,
+}
+
+impl Alert {
+  /// Whether the alert is only about findings going away.
+  fn recovery(&self) -> bool {
+    !self.changes.iter().any(|change| change.kind == ChangeKind::FindingAdded)
+  }
+
+  /// One line summary.
+  fn summary(&self) -> String {
+    let state = if self.findings.is_empty() {
+      "recovered".to_string()
+    } else {
+      format!("{} findings", self.findings.len())
+    };
+    format!("{}: findings changed, {}", self.domain, state)
+  }
+
+  /// The summary, then a line per change.
+  fn lines(&self) -> Vec<String> {
+    let mut lines = vec![self.summary()];
+    for change in &self.changes {
+      lines.push(format!("{} {}", change.name, change));
+    }
+    lines
+  }
+}
+
+/// Last check of a domain.
+struct State {
+  records: RecordDB,
+  findings: Vec<Finding>,
+}
+
+/// Alert for a check, if its findings differ from the last check's. The
+/// first check of a domain alerts when there are any findings.
+fn compare(domain: &rr::Name, last: Option<&State>, current: &State) -> Option<Alert> {
+  let no_findings = Vec::new();
+  let last_findings = last.map_or(&no_findings, |last| &last.findings);
+  let mut changes = diff::diff_findings(last_findings, &current.findings);
+  if changes.is_empty() {
+    return None;
+  }
+  if let Some(last) = last {
+    changes.extend(diff::diff_delegations(&last.records, &current.records));
+  }
+
+  Some(Alert {
+    domain: snapshot::encode_name(domain),
+    time: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs()),
+    findings: current.findings.iter().map(|finding| finding.to_string()).collect(),
+    changes,
+  })
+}
+
+/// Sends alerts everywhere configured.
+struct Alerter {
+  webhook: Option<String>,
+  syslog: Option<Logger<LoggerBackend, Formatter3164>>,
+}
+
+impl Alerter {
+  fn new(config: &Config) -> Alerter {
+    let syslog = if config.syslog {
+      let formatter = Formatter3164 {
+        facility: Facility::LOG_DAEMON,
+        hostname: None,
+        process: "dns-delegation-check".to_string(),
+        pid: std::process::id(),
+      };
+      match syslog::unix(formatter) {
+        Ok(logger) => Some(logger),
+        Err(e) => {
+          error!("Can't connect to syslog, alerting without it: {}", e);
+          None
+        },
+      }
+    } else {
+      None
+    };
+    Alerter { webhook: config.webhook.clone(), syslog }
+  }
+
+  fn send(&mut self, alert: &Alert) {
+    let lines = alert.lines();
+    for line in &lines {
+      println!("{}", line);
+    }
+
+    if let Some(logger) = &mut self.syslog {
+      for line in &lines {
+        let sent = if alert.recovery() {
+          logger.notice(line.as_str())
+        } else {
+          logger.warning(line.as_str())
+        };
+        if let Err(e) = sent {
+          error!("Can't send alert to syslog: {}", e);
+          break;
+        }
+      }
+    }
+
+    if let Some(url) = &self.webhook {
+      let sent = ureq::post(url)
+        .timeout(WEBHOOK_TIMEOUT)
+        .send_json(alert);
+      if let Err(e) = sent {
+        error!("Can't send alert for {} to webhook: {}", alert.domain, e);
+      }
+    }
+  }
+}
+
+/// Check domains every interval, forever, alerting when their findings
+/// change.
+///
+/// Each domain is checked on its own, with the record types given, by a
+/// fresh `Checker` from `checker`.
+pub fn run<F: Fn() -> Checker>(domains: &[(rr::Name, Vec<rr::RecordType>)], checker: F,
+                               config: &Config) -> ! {
+  let mut alerter = Alerter::new(config);
+  let mut states: Vec<Option<State>> = domains.iter().map(|_| None).collect();
+
+  loop {
+    let started = Instant::now();
+
+    for ((domain, rtypes), last) in domains.iter().zip(states.iter_mut()) {
+      info!("Checking {}", domain);
+      let mut domain_checker = checker();
+      for rtype in rtypes {
+        domain_checker = domain_checker.target(domain, *rtype);
+      }
+      let report = match domain_checker.run() {
+        Ok(report) => report,
+        Err(e) => {
+          error!("Can't check {}: {}", domain, e);
+          continue;
+        },
+      };
+
+      let current = State { records: report.records, findings: report.findings };
+      if let Some(alert) = compare(domain, last.as_ref(), &current) {
+        alerter.send(&alert);
+      }
+      *last = Some(current);
+    }
+
+    let elapsed = started.elapsed();
+    if elapsed < config.interval {
+      thread::sleep(config.interval - elapsed);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::str::FromStr;
+
+  use super::*;
+  use crate::db::RServer;
+  use crate::findings::{Check, Severity};
+
+  fn state(latency_ms: u32) -> State {
+    let zone = rr::Name::from_str("example.com.").unwrap();
+    let server = RServer::from("192.0.2.1".parse::<std::net::IpAddr>().unwrap());
+    let findings = vec![
+      Finding::new(Severity::Error, Check::Lame, &zone, Some(&server),
+                   "server is lame, it doesn't answer for the zone".to_string()),
+      Finding::new(Severity::Warning, Check::Latency, &zone, Some(&server),
+                   format!("took {} ms to answer for example.com. SOA", latency_ms)),
+    ];
+    State { records: RecordDB::new(), findings }
+  }
+
+  #[test]
+  fn first_check_alerts() {
+    let domain = rr::Name::from_str("example.com.").unwrap();
+    let alert = compare(&domain, None, &state(1200)).unwrap();
+    assert_eq!(alert.findings.len(), 2);
+    assert!(!alert.recovery());
+  }
+
+  #[test]
+  fn unchanged_findings_dont_alert() {
+    let domain = rr::Name::from_str("example.com.").unwrap();
+    let current = state(1200);
+    assert!(compare(&domain, Some(&current), &current).is_none());
+    assert!(compare(&domain, Some(&state(1200)), &state(1800)).is_none());
+  }
+
+  #[test]
+  fn recovery_alerts() {
+    let domain = rr::Name::from_str("example.com.").unwrap();
+    let recovered = State { records: RecordDB::new(), findings: Vec::new() };
+    let alert = compare(&domain, Some(&state(1200)), &recovered).unwrap();
+    assert!(alert.findings.is_empty());
+    assert!(alert.recovery());
+  }
+
+  #[test]
+  fn webhook_gets_json() {
+    let listener = tiny_http::Server::http("127.0.0.1:0").unwrap();
+    let addr = listener.server_addr().to_ip().unwrap();
+    let received = thread::spawn(move || {
+      let mut request = listener.recv().unwrap();
+      let mut body = String::new();
+      request.as_reader().read_to_string(&mut body).unwrap();
+      let content_type = request.headers().iter()
+        .find(|header| header.field.equiv("Content-Type"))
+        .map(|header| header.value.to_string());
+      let method = request.method().clone();
+      request.respond(tiny_http::Response::empty(204)).unwrap();
+      (method, content_type, body)
+    });
+
+    let mut alerter = Alerter::new(&Config {
+      webhook: Some(format!("http://{}/alerts", addr)),
+      ..Config::default()
+    });
+    let domain = rr::Name::from_str("example.com.").unwrap();
+    alerter.send(&compare(&domain, None, &state(1200)).unwrap());
+
+    let (method, content_type, body) = received.join().unwrap();
+    assert_eq!(method, tiny_http::Method::Post);
+    assert_eq!(content_type.as_deref(), Some("application/json"));
+    let alert: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(alert["domain"], "example.com.");
+    assert_eq!(alert["findings"].as_array().unwrap().len(), 2);
+    let changes = alert["changes"].as_array().unwrap();
+    assert_eq!(changes.len(), 2);
+    assert!(changes.iter().all(|change| change["kind"] == "finding-added"));
+    assert_eq!(changes[0]["server"], "192.0.2.1");
+  }
+}
@@ -1,13 +1,23 @@
-use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::collections::{BTreeMap, BTreeSet, HashSet, VecDeque};
+use std::fmt;
 use std::hash::{Hash, Hasher};
 use std::net::{Ipv4Addr, Ipv6Addr, IpAddr};
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use log::{error, warn, info, debug, trace};
+use log::{debug, trace};
+use serde::{Deserialize, Serialize};
+use trust_dns_client::op::ResponseCode;
+use trust_dns_client::proto::rr::dnssec::rdata::DNSSECRecordType;
 use trust_dns_client::rr::{RData};
 use trust_dns_client::rr;
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+use crate::cache::ReferralCache;
+use crate::dns;
+use crate::snapshot::{self, Snapshot, SnapshotError};
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum RServer {
   V4(Ipv4Addr),
   V6(Ipv6Addr),
@@ -23,14 +33,238 @@ impl From<IpAddr> for RServer {
   }
 }
 
+impl RServer {
+  /// Address of the server, hints have none.
+  pub fn ip(&self) -> Option<IpAddr> {
+    match self {
+      RServer::V4(ip) => Some(IpAddr::V4(*ip)),
+      RServer::V6(ip) => Some(IpAddr::V6(*ip)),
+      RServer::Hint => None,
+    }
+  }
+}
+
 #[derive(Debug, Clone)]
 pub enum REntry {
   /// No Entry (NXDomain, Not Authoritative, etc).
   NoEntry,
   /// Query timeout.
   TimeOut,
-  /// Answers.
-  Entries(Vec<RData>),
+  /// Answers, with the TTL each was received with.
+  Entries(Vec<(RData, u32)>),
+}
+
+/// An authoritative NXDOMAIN or NODATA answer.
+#[derive(Debug, Clone)]
+pub struct NegativeAnswer {
+  /// Whether the name doesn't exist (NXDOMAIN), or just has no records of
+  /// the requested type (NODATA).
+  pub nxdomain: bool,
+  /// Authority section of the answer (SOA, NSEC, NSEC3 and signatures).
+  pub authority: Vec<rr::Record>,
+}
+
+/// Result of asking a server to recurse for an unrelated name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Recursion {
+  /// Recursion refused, or answered with a referral.
+  Closed,
+  /// Recursion available, and a non-authoritative answer returned.
+  Open,
+  /// No answer from the server.
+  NoAnswer,
+}
+
+/// Result of attempting a zone transfer from a server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ZoneTransfer {
+  /// The server answered with an error (usually REFUSED or NOTAUTH).
+  Refused(ResponseCode),
+  /// No usable answer, timeout or connection error.
+  Failed(String),
+  /// The transfer stopped before the closing SOA, after this many records.
+  Partial(usize, String),
+  /// The whole zone was transferred, with this many records.
+  Allowed(usize),
+}
+
+/// Identifiers a server reports for itself.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Identity {
+  /// EDNS name server identifier (RFC 5001).
+  pub nsid: Option<String>,
+  /// CHAOS TXT hostname.bind.
+  pub hostname_bind: Option<String>,
+  /// CHAOS TXT id.server.
+  pub id_server: Option<String>,
+  /// CHAOS TXT version.bind.
+  pub version_bind: Option<String>,
+}
+
+impl Identity {
+  /// Short description of the known identifiers, empty if there are none.
+  pub fn describe(&self) -> String {
+    let ids = vec![
+      ("nsid", &self.nsid),
+      ("hostname.bind", &self.hostname_bind),
+      ("id.server", &self.id_server),
+      ("version.bind", &self.version_bind),
+    ];
+
+    ids.into_iter()
+      .filter_map(|(label, id)| id.as_ref().map(|id| format!("{} \"{}\"", label, id)))
+      .collect::<Vec<_>>()
+      .join(", ")
+  }
+}
+
+/// EDNS compliance tests, as run by the ISC EDNS compliance tester.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum EdnsTest {
+  /// Plain DNS query, without EDNS.
+  Plain,
+  /// EDNS version 0 query.
+  Edns0,
+  /// Unknown EDNS version, BADVERS is expected.
+  UnknownVersion,
+  /// Unknown EDNS option, which must be ignored.
+  UnknownOption,
+  /// Unknown EDNS flag, which must be ignored and not echoed.
+  UnknownFlag,
+  /// DO bit set, which must be echoed.
+  DnssecOk,
+  /// Small UDP buffer, the answer must fit or be truncated.
+  SmallBuffer,
+}
+
+impl EdnsTest {
+  pub fn all() -> Vec<EdnsTest> {
+    vec![
+      EdnsTest::Plain,
+      EdnsTest::Edns0,
+      EdnsTest::UnknownVersion,
+      EdnsTest::UnknownOption,
+      EdnsTest::UnknownFlag,
+      EdnsTest::DnssecOk,
+      EdnsTest::SmallBuffer,
+    ]
+  }
+}
+
+impl fmt::Display for EdnsTest {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    // Names as used by the ISC EDNS compliance tester.
+    match self {
+      EdnsTest::Plain => write!(f, "dns"),
+      EdnsTest::Edns0 => write!(f, "edns"),
+      EdnsTest::UnknownVersion => write!(f, "edns1"),
+      EdnsTest::UnknownOption => write!(f, "ednsopt"),
+      EdnsTest::UnknownFlag => write!(f, "ednsflags"),
+      EdnsTest::DnssecOk => write!(f, "do"),
+      EdnsTest::SmallBuffer => write!(f, "edns@512"),
+    }
+  }
+}
+
+/// Result of an EDNS compliance test.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EdnsResult {
+  /// Behaved as RFC 6891 requires.
+  Ok,
+  /// No answer from the server.
+  TimeOut,
+  /// Answered, but not as RFC 6891 requires.
+  Deviates(String),
+}
+
+/// EDNS compliance test results for a server.
+#[derive(Debug, Clone)]
+pub struct EdnsCompliance {
+  /// Zone queried for the tests.
+  pub zone: rr::Name,
+  pub results: BTreeMap<EdnsTest, EdnsResult>,
+}
+
+/// Results of optional probes sent to a server.
+#[derive(Debug, Clone, Default)]
+pub struct ServerInfo {
+  pub edns: Option<EdnsCompliance>,
+  pub identity: Option<Identity>,
+  pub recursion: Option<Recursion>,
+  /// Zone transfer attempts, by zone.
+  pub zone_transfers: BTreeMap<rr::Name, ZoneTransfer>,
+}
+
+/// Transport a query was answered over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+  Udp,
+  /// After a truncated answer over UDP.
+  Tcp,
+}
+
+impl fmt::Display for Transport {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Transport::Udp => write!(f, "udp"),
+      Transport::Tcp => write!(f, "tcp"),
+    }
+  }
+}
+
+/// What a server answered to a query.
+#[derive(Debug, Clone)]
+pub struct QueryResponse {
+  pub rtt: Duration,
+  /// Size of the response message, in bytes.
+  pub size: usize,
+  pub rcode: ResponseCode,
+  /// Header flags, as shown by dig.
+  pub flags: String,
+}
+
+/// A query sent to a server, and how it went.
+#[derive(Debug, Clone)]
+pub struct LoggedQuery {
+  pub name: rr::Name,
+  pub rtype: rr::RecordType,
+  pub server: IpAddr,
+  pub transport: Transport,
+  /// When the query was first sent.
+  pub start: SystemTime,
+  /// Times the query was sent, counting retries after timeouts.
+  pub attempts: u32,
+  /// Response, None when every attempt timed out.
+  pub response: Option<QueryResponse>,
+}
+
+/// How a server of a zone answers for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Health {
+  /// Answers for the zone's SOA or NS.
+  Ok,
+  /// Not asked (yet).
+  Unknown,
+  /// Answers, but not with the zone's SOA or NS.
+  Lame,
+  /// Doesn't answer.
+  TimeOut,
+}
+
+/// TTL given to root hints, as in the root hints file.
+const HINT_TTL: u32 = 3_600_000;
+
+/// Longest alias chain that will be followed.
+pub const MAX_ALIAS_CHAIN: usize = 8;
+
+/// How one name aliases another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum AliasKind {
+  /// CNAME of the name itself.
+  CName,
+  /// Name synthesized from a DNAME of an ancestor.
+  DName,
 }
 
 #[derive(PartialEq, Eq, PartialOrd, Ord)]
@@ -62,10 +296,25 @@ pub struct RecordDB {
   //        Need to store NoEntry per RType.
   records: BTreeMap<rr::Name, BTreeMap<RServer, BTreeMap<rr::RecordType, REntry>>>,
   answer_targets: HashSet<(rr::Name, rr::RecordType)>,
+  address_targets: HashSet<(rr::Name, rr::RecordType)>,
   targets: HashSet<(rr::Name, rr::RecordType, rr::Name)>,
   delegations: BTreeMap<(rr::Name, rr::Name), HashSet<(rr::Name, rr::Name)>>,
+  negative_answers: BTreeMap<(rr::Name, rr::RecordType), BTreeMap<RServer, NegativeAnswer>>,
+  aliases: BTreeMap<(rr::Name, rr::RecordType), BTreeMap<rr::Name, AliasKind>>,
+  server_info: BTreeMap<RServer, ServerInfo>,
+  query_log: Vec<LoggedQuery>,
   query_queue: VecDeque<(rr::Name, rr::RecordType, IpAddr, Option<rr::Name>)>,
   change_num: u64,
+  /// Responses shared with other databases, not saved in snapshots.
+  referral_cache: Option<Arc<ReferralCache>>,
+  /// Capture and fixture of the check, not saved in snapshots.
+  hooks: dns::Hooks,
+}
+
+impl Default for RecordDB {
+  fn default() -> RecordDB {
+    RecordDB::new()
+  }
 }
 
 impl RecordDB {
@@ -74,9 +323,16 @@ impl RecordDB {
       records: BTreeMap::new(),
       targets: HashSet::new(),
       answer_targets: HashSet::new(),
+      address_targets: HashSet::new(),
       delegations: BTreeMap::new(),
+      negative_answers: BTreeMap::new(),
+      aliases: BTreeMap::new(),
+      server_info: BTreeMap::new(),
+      query_log: Vec::new(),
       query_queue: VecDeque::new(),
       change_num: 0,
+      referral_cache: None,
+      hooks: dns::Hooks::default(),
     }
   }
 
@@ -95,32 +351,32 @@ impl RecordDB {
       };
 
       self.records
-        .entry(name.clone()).or_insert_with(|| BTreeMap::new())
-        .entry(RServer::Hint).or_insert_with(|| BTreeMap::new())
+        .entry(name.clone()).or_default()
+        .entry(RServer::Hint).or_default()
         .entry(rdata.to_record_type()).and_modify(|e| {
           if let REntry::Entries(v) = e {
-            v.push(rdata.clone());
+            v.push((rdata.clone(), HINT_TTL));
             return;
           }
           // Hints shouldn't timeout or return nx, so replace
           // anything else with an entry.
-          *e = REntry::Entries(vec![rdata.clone()]);
-        }).or_insert_with(|| REntry::Entries(vec![rdata.clone()]));
+          *e = REntry::Entries(vec![(rdata.clone(), HINT_TTL)]);
+        }).or_insert_with(|| REntry::Entries(vec![(rdata.clone(), HINT_TTL)]));
 
       let rdata = RData::NS(name);
 
       self.records
-      .entry(rr::Name::from_str(".").unwrap()).or_insert_with(|| BTreeMap::new())
-      .entry(RServer::Hint).or_insert_with(|| BTreeMap::new())
+      .entry(rr::Name::from_str(".").unwrap()).or_default()
+      .entry(RServer::Hint).or_default()
       .entry(rr::RecordType::NS).and_modify(|e| {
           if let REntry::Entries(v) = e {
-            v.push(rdata.clone());
+            v.push((rdata.clone(), HINT_TTL));
             return;
           }
           // Hints shouldn't timeout or return nx, so replace
           // anything else with an entry.
-          *e = REntry::Entries(vec![rdata.clone()]);
-        }).or_insert_with(|| REntry::Entries(vec![rdata.clone()]));
+          *e = REntry::Entries(vec![(rdata.clone(), HINT_TTL)]);
+        }).or_insert_with(|| REntry::Entries(vec![(rdata.clone(), HINT_TTL)]));
     }
   }
 
@@ -129,7 +385,7 @@ impl RecordDB {
                         auth_zone: &rr::Name, auth_ns: &rr::Name) {
     self.change_num += 1;
     self.delegations
-      .entry((name.clone(), zone.clone())).or_insert_with(|| HashSet::new())
+      .entry((name.clone(), zone.clone())).or_default()
       .insert((auth_zone.clone(), auth_ns.clone()));
   }
 
@@ -140,22 +396,25 @@ impl RecordDB {
     let name = record.name();
     let rdata = record.rdata();
     let rtype = rdata.to_record_type();
+    let item = (rdata.clone(), record.ttl());
     self.records
-      .entry(name.clone()).or_insert_with(|| BTreeMap::new())
-      .entry(server_ip.into()).or_insert_with(|| BTreeMap::new())
+      .entry(name.clone()).or_default()
+      .entry(server_ip.into()).or_default()
       .entry(rtype).and_modify(|e| {
         match e {
           REntry::Entries(v) => {
-            if !v.contains(record.rdata()) {
-              v.push(record.rdata().clone())
+            // Keep the latest TTL seen for a record.
+            match v.iter_mut().find(|(r, _)| r == rdata) {
+              Some(existing) => existing.1 = record.ttl(),
+              None => v.push(item.clone()),
             }
           },
           e @ REntry::TimeOut =>
-            *e = REntry::Entries(vec![record.rdata().clone()]),
+            *e = REntry::Entries(vec![item.clone()]),
           e @ REntry::NoEntry =>
-            *e = REntry::Entries(vec![record.rdata().clone()]),
+            *e = REntry::Entries(vec![item.clone()]),
         }
-      }).or_insert_with(|| REntry::Entries(vec![record.rdata().clone()]));
+      }).or_insert_with(|| REntry::Entries(vec![item.clone()]));
   }
 
   pub fn add_rentry(&mut self, name: &rr::Name, rentry: REntry,
@@ -163,22 +422,129 @@ impl RecordDB {
     trace!("Add rentry {}, {:?}, {}", name, rentry, server_ip);
     self.change_num += 1;
     self.records
-      .entry(name.clone()).or_insert_with(|| BTreeMap::new())
-      .entry(server_ip.into()).or_insert_with(|| BTreeMap::new())
+      .entry(name.clone()).or_default()
+      .entry(server_ip.into()).or_default()
       .entry(rtype).and_modify(|e| {
         match e {
           REntry::Entries(_) => (),
           e @ REntry::TimeOut =>
             *e = rentry.clone(),
-          e @ REntry::NoEntry =>
-            match rentry {
-              REntry::TimeOut => *e = REntry::NoEntry,
-              _ => (),
-            },
+          e @ REntry::NoEntry => {
+            if let REntry::TimeOut = rentry {
+              *e = REntry::NoEntry;
+            }
+          },
         };
       }).or_insert_with(|| rentry.clone());
   }
 
+  /// Add a negative answer returned by the given server.
+  pub fn add_negative_answer(&mut self, name: &rr::Name, rtype: rr::RecordType,
+                             server_ip: IpAddr, negative: NegativeAnswer) {
+    trace!("Add negative answer {}, {}, {}", name, rtype, server_ip);
+    self.change_num += 1;
+    self.negative_answers
+      .entry((name.clone(), rtype)).or_default()
+      .insert(server_ip.into(), negative);
+  }
+
+  /// Retrieve all negative answers, by name and record type, then server.
+  pub fn get_negative_answers(&self)
+    -> &BTreeMap<(rr::Name, rr::RecordType), BTreeMap<RServer, NegativeAnswer>> {
+    &self.negative_answers
+  }
+
+  /// Retrieve every server records were received from.
+  pub fn get_servers(&self) -> BTreeSet<RServer> {
+    self.records.values()
+      .flat_map(|servers| servers.keys())
+      .filter(|server| **server != RServer::Hint)
+      .cloned()
+      .collect()
+  }
+
+  /// Retrieve probe results for all servers.
+  pub fn get_server_info(&self) -> &BTreeMap<RServer, ServerInfo> {
+    &self.server_info
+  }
+
+  /// Describe the identifiers a server gave, None if it gave none.
+  pub fn get_identity(&self, server: &RServer) -> Option<String> {
+    let identity = self.server_info.get(server)?.identity.as_ref()?.describe();
+    if identity.is_empty() {
+      None
+    } else {
+      Some(identity)
+    }
+  }
+
+  /// Retrieve probe results for a server, to add to them.
+  pub fn server_info_mut(&mut self, server: &RServer) -> &mut ServerInfo {
+    self.server_info.entry(server.clone()).or_default()
+  }
+
+  /// Share referrals and zone apex answers with other databases.
+  pub fn set_referral_cache(&mut self, cache: Arc<ReferralCache>) {
+    self.referral_cache = Some(cache);
+  }
+
+  /// Retrieve the referral cache shared with other databases, if any.
+  pub fn get_referral_cache(&self) -> Option<Arc<ReferralCache>> {
+    self.referral_cache.clone()
+  }
+
+  /// Capture queries to, and record them to or answer them from, the
+  /// check's packet capture and fixture.
+  pub fn set_hooks(&mut self, hooks: dns::Hooks) {
+    self.hooks = hooks;
+  }
+
+  /// Retrieve the capture and fixture of the check.
+  pub fn get_hooks(&self) -> dns::Hooks {
+    self.hooks.clone()
+  }
+
+  /// Add a query sent to a server to the query log.
+  pub fn add_logged_query(&mut self, query: LoggedQuery) {
+    self.query_log.push(query);
+  }
+
+  /// Retrieve every query sent, in the order they were sent.
+  pub fn get_query_log(&self) -> &[LoggedQuery] {
+    &self.query_log
+  }
+
+  /// Retrieve the last query sent to a server for a name and type.
+  pub fn get_logged_query(&self, name: &rr::Name, rtype: rr::RecordType, server: &RServer)
+    -> Option<&LoggedQuery> {
+    self.query_log.iter()
+      .rev()
+      .find(|query| &query.name == name && query.rtype == rtype
+            && server.ip() == Some(query.server))
+  }
+
+  /// Longest time a server took to answer a query.
+  pub fn get_max_rtt(&self, server: &RServer) -> Option<Duration> {
+    self.query_log.iter()
+      .filter(|query| server.ip() == Some(query.server))
+      .filter_map(|query| query.response.as_ref())
+      .map(|response| response.rtt)
+      .max()
+  }
+
+  /// When a server last answered a query.
+  pub fn get_last_answer(&self, server: &RServer) -> Option<SystemTime> {
+    self.query_log.iter()
+      .filter(|query| server.ip() == Some(query.server))
+      .filter_map(|query| query.response.as_ref().map(|response| query.start + response.rtt))
+      .max()
+  }
+
+  /// Retrieve every name records (or failures) are known for.
+  pub fn get_names(&self) -> Vec<rr::Name> {
+    self.records.keys().cloned().collect()
+  }
+
   /// For the given domain name, retrieve all records for all NS IPs under it.
   pub fn get_records(&self, name: &rr::Name)
     -> BTreeMap<RServer, BTreeMap<rr::RecordType, REntry>> {
@@ -201,9 +567,9 @@ impl RecordDB {
 
     let mut records = HashSet::new();
 
-    for (server, entries) in servers {
+    for entries in servers.values() {
       if let Some(REntry::Entries(items)) = entries.get(&rtype) {
-        for item in items {
+        for (item, _) in items {
           if item.to_record_type() == rtype {
             records.insert(RDataHash(item.clone()));
          }
@@ -214,6 +580,25 @@ impl RecordDB {
     records.into_iter().map(|RDataHash(item)| item).collect()
   }
 
+  /// For the given domain name and record type, retrieve the TTL each
+  /// server gave the record set.
+  ///
+  /// Where a server gave records of the set different TTLs, the lowest is
+  /// used, as a resolver would.
+  pub fn get_ttls(&self, name: &rr::Name, rtype: rr::RecordType) -> BTreeMap<RServer, u32> {
+    let mut ttls = BTreeMap::new();
+
+    for (server, entries) in self.get_records(name) {
+      if let Some(REntry::Entries(items)) = entries.get(&rtype) {
+        if let Some(ttl) = items.iter().map(|(_, ttl)| *ttl).min() {
+          ttls.insert(server, ttl);
+        }
+      }
+    }
+
+    ttls
+  }
+
   /// Add a domain and rtype as a final target to provide an answer for.
   pub fn add_answer_target(&mut self, name: &rr::Name, rtype: rr::RecordType) {
     if self.answer_targets.insert((name.clone(), rtype)) {
@@ -226,11 +611,178 @@ impl RecordDB {
     self.change_num += 1;
   }
 
+  /// Retrieve all answer targets, sorted by name.
+  pub fn get_answer_targets(&self) -> Vec<(rr::Name, rr::RecordType)> {
+    let mut targets: Vec<_> = self.answer_targets.iter().cloned().collect();
+    targets.sort();
+    targets
+  }
+
   /// Check if the given name and record type are in answer targets.
   pub fn is_answer_target(&self, name: &rr::Name, rtype: rr::RecordType) -> bool {
     self.answer_targets.contains(&(name.clone(), rtype))
   }
 
+  /// Add an NS name and address rtype as a target to resolve.
+  ///
+  /// Like answer targets these are resolved from the root, but they are
+  /// only used to check the NS names of answer zones.
+  pub fn add_address_target(&mut self, name: &rr::Name, rtype: rr::RecordType) {
+    if self.address_targets.insert((name.clone(), rtype)) {
+      debug!("Add address target {}, {}", name, rtype);
+      self.change_num += 1;
+    }
+    if self.targets.insert((name.clone(), rtype, rr::Name::root())) {
+      debug!("Add target {}, {}, .", name, rtype);
+    }
+  }
+
+  /// Check if the given name and record type are in address targets.
+  pub fn is_address_target(&self, name: &rr::Name, rtype: rr::RecordType) -> bool {
+    self.address_targets.contains(&(name.clone(), rtype))
+  }
+
+  /// Retrieve the zones of answer targets, including all zones referred
+  /// through from the root.
+  pub fn get_answer_zones(&self) -> BTreeSet<rr::Name> {
+    self.targets.iter()
+      .filter_map(|(name, rtype, zone)| {
+        if self.answer_targets.contains(&(name.clone(), *rtype)) {
+          Some(zone.clone())
+        } else {
+          None
+        }
+      }).collect()
+  }
+
+  /// Retrieve all delegations, by name and zone referring, to the zone
+  /// referred to and its NS names.
+  pub fn get_delegations(&self)
+    -> &BTreeMap<(rr::Name, rr::Name), HashSet<(rr::Name, rr::Name)>> {
+    &self.delegations
+  }
+
+  /// How a server of a zone answered for the zone itself.
+  pub fn get_health(&self, zone: &rr::Name, server: &RServer) -> Health {
+    let entries = match self.records.get(zone).and_then(|servers| servers.get(server)) {
+      Some(entries) => entries,
+      None => return Health::Unknown,
+    };
+    let apex: Vec<_> = [rr::RecordType::SOA, rr::RecordType::NS].iter()
+      .filter_map(|rtype| entries.get(rtype))
+      .collect();
+
+    if apex.iter().any(|entry| matches!(entry, REntry::Entries(_))) {
+      Health::Ok
+    } else if apex.iter().any(|entry| matches!(entry, REntry::NoEntry)) {
+      Health::Lame
+    } else if apex.iter().any(|entry| matches!(entry, REntry::TimeOut)) {
+      Health::TimeOut
+    } else {
+      Health::Unknown
+    }
+  }
+
+  /// NS names for a zone, as given in referrals from the parent zone.
+  pub fn get_parent_ns(&self, zone: &rr::Name) -> BTreeSet<rr::Name> {
+    self.delegations.values()
+      .flat_map(|delegations| delegations.iter())
+      .filter(|(auth_zone, _)| auth_zone == zone)
+      .map(|(_, ns)| ns.clone())
+      .collect()
+  }
+
+  /// Zone whose servers referred to the given zone.
+  pub fn get_parent_zone(&self, zone: &rr::Name) -> Option<rr::Name> {
+    self.delegations.iter()
+      .filter(|(_, delegations)| delegations.iter().any(|(auth_zone, _)| auth_zone == zone))
+      .map(|((_, parent), _)| parent)
+      .filter(|parent| *parent != zone)
+      .max_by_key(|parent| parent.num_labels())
+      .cloned()
+  }
+
+  /// Retrieve the zones a target was referred through, from the root down.
+  pub fn get_referral_chain(&self, name: &rr::Name, rtype: rr::RecordType) -> Vec<rr::Name> {
+    let mut zones: Vec<_> = self.targets.iter()
+      .filter(|(n, t, _)| n == name && *t == rtype)
+      .map(|(_, _, zone)| zone.clone())
+      .collect();
+    zones.sort_by_key(|zone| zone.num_labels());
+    zones
+  }
+
+  /// Retrieve the deepest zone an answer target was referred to.
+  pub fn get_target_zone(&self, name: &rr::Name, rtype: rr::RecordType)
+    -> Option<rr::Name> {
+    self.targets.iter()
+      .filter(|(n, t, _)| n == name && *t == rtype)
+      .map(|(_, _, zone)| zone)
+      .max_by_key(|zone| zone.num_labels())
+      .cloned()
+  }
+
+  /// Retrieve the zones holding answer targets.
+  ///
+  /// Unlike answer zones, the zones referred through aren't included.
+  pub fn get_target_zones(&self) -> BTreeSet<rr::Name> {
+    self.answer_targets.iter()
+      .filter_map(|(name, rtype)| self.get_target_zone(name, *rtype))
+      .collect()
+  }
+
+  /// Retrieve the target zones whose servers answered for an answer target,
+  /// with records or an authoritative negative answer, rather than only
+  /// referring it further down.
+  pub fn get_answered_target_zones(&self) -> BTreeSet<rr::Name> {
+    self.answer_targets.iter()
+      .filter_map(|(name, rtype)| {
+        let zone = self.get_target_zone(name, *rtype)?;
+        let servers = self.get_zone_servers(&zone);
+        let has_records = self.get_records(name).iter()
+          .any(|(server, entries)| servers.contains(server)
+               && entries.values().any(|entry| matches!(entry, REntry::Entries(_))));
+        let has_negative = self.negative_answers.get(&(name.clone(), *rtype))
+          .is_some_and(|answers| answers.keys().any(|server| servers.contains(server)));
+        if has_records || has_negative {
+          Some(zone)
+        } else {
+          None
+        }
+      })
+      .collect()
+  }
+
+  /// Retrieve the servers of a zone, any address of any NS name known for it.
+  pub fn get_zone_servers(&self, zone: &rr::Name) -> BTreeSet<RServer> {
+    let mut servers = BTreeSet::new();
+    for ns in self.get_record_set(zone, rr::RecordType::NS) {
+      let ns = ns.as_ns().unwrap();
+      for rtype in &[rr::RecordType::A, rr::RecordType::AAAA] {
+        for ip in self.get_record_set(ns, *rtype) {
+          servers.insert(RServer::from(ip.to_ip_addr().unwrap()));
+        }
+      }
+    }
+    servers
+  }
+
+  /// NS names for a zone, as answered by the zone's own servers.
+  pub fn get_child_ns(&self, zone: &rr::Name) -> BTreeSet<rr::Name> {
+    let servers = self.get_zone_servers(zone);
+
+    let mut names = BTreeSet::new();
+    for (server, entries) in self.get_records(zone) {
+      if !servers.contains(&server) {
+        continue;
+      }
+      if let Some(REntry::Entries(items)) = entries.get(&rr::RecordType::NS) {
+        names.extend(items.iter().filter_map(|(item, _)| item.as_ns().cloned()));
+      }
+    }
+    names
+  }
+
   /// Add a domain, rtype and target zone as a target.
   ///
   /// Unlike answer targets, these areused as stepping stones internally.
@@ -241,38 +793,60 @@ impl RecordDB {
     self.change_num += 1;
   }
 
-  /// Given a domain, find the longest matching domain in the database that
-  /// matches the end of the domain.
-  pub fn find_closest_domain(&self, name: &rr::Name) -> rr::Name {
-    let (mut match_name, mut match_count) = (rr::Name::from_str(".").unwrap(), 0);
+  /// Add an alias from one answer target to another.
+  pub fn add_alias(&mut self, name: &rr::Name, rtype: rr::RecordType,
+                   target: &rr::Name, kind: AliasKind) {
+    if self.aliases
+      .entry((name.clone(), rtype)).or_default()
+      .insert(target.clone(), kind).is_none() {
+      debug!("Add alias {}, {}, {:?} {}", name, rtype, kind, target);
+      self.change_num += 1;
+    }
+  }
 
-    for rname in self.records.keys() {
-      if !rname.zone_of(name) {
-        continue;
-      }
+  /// Retrieve the alias targets of a name, for the given record type.
+  pub fn get_aliases(&self, name: &rr::Name, rtype: rr::RecordType)
+    -> BTreeMap<rr::Name, AliasKind> {
+    match self.aliases.get(&(name.clone(), rtype)) {
+      Some(a) => a.clone(),
+      None => BTreeMap::new(),
+    }
+  }
 
-      // TODO: We already know that rname is a valid tail of name, it
-      //       should be safe to just count the number of labelss in rname
-      //       here.
-      let this_match_count =
-        rname.iter().rev()
-          .zip(name.iter().rev())
-          .filter(|(a, b)| a == b)
-          .count();
-
-      if this_match_count > match_count {
-        match_name = rname.clone();
-        match_count = this_match_count;
+  /// Check if the given name was reached by following an alias.
+  pub fn is_alias_target(&self, name: &rr::Name, rtype: rr::RecordType) -> bool {
+    self.aliases.iter()
+      .any(|((_, t), targets)| *t == rtype && targets.contains_key(name))
+  }
+
+  /// Number of aliases followed to reach the given name.
+  ///
+  /// Where a name is reachable in multiple ways, the longest chain is used.
+  pub fn alias_chain_length(&self, name: &rr::Name, rtype: rr::RecordType) -> usize {
+    let mut length = 0;
+    let mut current: HashSet<rr::Name> = vec![name.clone()].into_iter().collect();
+    let mut seen = current.clone();
+
+    loop {
+      let previous: HashSet<rr::Name> = self.aliases.iter()
+        .filter(|((_, t), targets)| {
+          *t == rtype && targets.keys().any(|target| current.contains(target))
+        })
+        .map(|((n, _), _)| n.clone())
+        .filter(|n| !seen.contains(n))
+        .collect();
+
+      if previous.is_empty() {
+        return length;
       }
+      length += 1;
+      seen.extend(previous.iter().cloned());
+      current = previous;
     }
-
-    match_name
   }
 
   /// Generate internal DNS queries needed to further resolve targets.
   pub fn generate_queries(&mut self) {
-    let mut missing_entries = 0;
-
     // // For each _target, find any missing records, and generate queries
     // // needed to fetch them.
     // for (name, rtype, zone) in &self.answer_targets {
@@ -334,7 +908,7 @@ impl RecordDB {
     
     // For each answer target, ensure targets contains the longest matching
     // zone.
-    for (name, rtype) in &self.answer_targets {
+    for (_name, _rtype) in &self.answer_targets {
       // Add targets for NS of zones?
     }
 
@@ -355,7 +929,7 @@ impl RecordDB {
 
           // FIXME: Do I want record set for zone instead of ns?
           // If no domain record for ns, then no a records possible?
-        let ns_ips = self.get_record_set(&ns, rr::RecordType::A);
+        let ns_ips = self.get_record_set(ns, rr::RecordType::A);
         // if ns_ips.len() == 0 {
         //   missing_entries += 1;
         // }
@@ -363,10 +937,9 @@ impl RecordDB {
         for ip in &ns_ips {
           let ip = ip.to_ip_addr().unwrap();
           if !name_records.contains_key(&ip.into()) {
-            missing_entries += 1;
             for ip in &ns_ips {
               let ip = ip.to_ip_addr().unwrap();
-              self.query_queue.push_front((name.clone(), rtype.clone(),
+              self.query_queue.push_front((name.clone(), *rtype,
                                           ip, Some(zone.clone())));
               self.change_num += 1;
             }
@@ -375,14 +948,8 @@ impl RecordDB {
       }
     }
 
-    let answer_zones: HashSet<_> = self.targets.iter()
-      .filter_map(|(name, rtype, zone)| {
-        if self.answer_targets.contains(&(name.clone(), *rtype)) {
-          Some(zone.clone())
-        } else {
-          None
-        }
-      }).collect();
+    let answer_zones = self.get_answer_zones();
+    let answered_zones = self.get_answered_target_zones();
 
     // Ensure each answer zone has an NS record from every NS server in its zone.
     for zone in &answer_zones {
@@ -397,14 +964,22 @@ impl RecordDB {
       // name from that NS server.
       for ns in zone_ns {
         let ns = ns.as_ns().unwrap();
-        let ns_ips = self.get_record_set(&ns, rr::RecordType::A);
+
+        // Resolve the NS names of the zones answering for targets, glue
+        // alone doesn't show whether a name exists or is an alias. Zones
+        // only referred through, such as the root and TLDs, are skipped.
+        if answered_zones.contains(zone) {
+          self.add_address_target(ns, rr::RecordType::A);
+          self.add_address_target(ns, rr::RecordType::AAAA);
+        }
+
+        let ns_ips = self.get_record_set(ns, rr::RecordType::A);
 
         for ip in &ns_ips {
           let ip = ip.to_ip_addr().unwrap();
 
           // Ensure the zone has an NS record for each NS server in zone..
           if !zone_records.contains_key(&ip.into()) {
-            missing_entries += 1;
             for ip in &ns_ips {
               let ip = ip.to_ip_addr().unwrap();
               self.query_queue.push_back((zone.clone(), rr::RecordType::NS, ip, None));
@@ -412,10 +987,49 @@ impl RecordDB {
             }
           }
 
+          // Ensure the zone has an SOA record from each NS server in zone.
+          let has_soa = zone_records.get(&ip.into())
+            .is_some_and(|entries| entries.contains_key(&rr::RecordType::SOA));
+          if !has_soa {
+            self.query_queue.push_back((zone.clone(), rr::RecordType::SOA, ip, None));
+            self.change_num += 1;
+          }
+
           // Ensure each NS server has an A record from each NS server in zone.
         }
       }
     }
+
+    // Ensure each zone holding an answer target has a DS answer from each
+    // server of its parent zone.
+    let ds_type = rr::RecordType::DNSSEC(DNSSECRecordType::DS);
+    for zone in self.get_target_zones() {
+      let parent = match self.get_parent_zone(&zone) {
+        Some(parent) => parent,
+        None => continue,
+      };
+      let zone_records = self.get_records(&zone);
+
+      for server in self.get_zone_servers(&parent) {
+        let ip = match server.ip() {
+          Some(ip) => ip,
+          None => continue,
+        };
+        let has_ds = zone_records.get(&server)
+          .is_some_and(|entries| entries.contains_key(&ds_type));
+        if !has_ds {
+          self.query_queue.push_back((zone.clone(), ds_type, ip, Some(parent.clone())));
+          self.change_num += 1;
+        }
+      }
+    }
+  }
+
+  /// Queue a query to send with the next queries performed.
+  pub fn queue_query(&mut self, name: &rr::Name, rtype: rr::RecordType, ip: IpAddr,
+                     zone: Option<&rr::Name>) {
+    self.query_queue.push_back((name.clone(), rtype, ip, zone.cloned()));
+    self.change_num += 1;
   }
 
   /// Perform queries from queue.
@@ -428,7 +1042,9 @@ impl RecordDB {
     }
   }
 
-  pub fn action_loop(&mut self) {
+  /// Run the action loop, calling the given function after each round of
+  /// queries.
+  pub fn action_loop_with<F: FnMut(&RecordDB)>(&mut self, mut after_round: F) {
     let mut change_num = 0;
 
     while change_num != self.change_num {
@@ -436,9 +1052,219 @@ impl RecordDB {
       self.generate_queries();
       self.perform_queries();
       debug!("Action loop, change num {}", change_num);
+      after_round(self);
     }
   }
 
+  /// Convert the database to a form that can be saved to disk.
+  pub fn to_snapshot(&self) -> Result<Snapshot, SnapshotError> {
+    let mut out = Snapshot {
+      version: snapshot::VERSION,
+      records: Vec::new(),
+      answer_targets: Vec::new(),
+      address_targets: Vec::new(),
+      targets: Vec::new(),
+      delegations: Vec::new(),
+      negative_answers: Vec::new(),
+      aliases: Vec::new(),
+      servers: Vec::new(),
+      query_queue: Vec::new(),
+      query_log: Vec::new(),
+    };
+
+    for (name, servers) in &self.records {
+      for (server, entries) in servers {
+        for (rtype, entry) in entries {
+          let entry = match entry {
+            REntry::NoEntry => snapshot::Entry::NoEntry,
+            REntry::TimeOut => snapshot::Entry::TimeOut,
+            REntry::Entries(items) => snapshot::Entry::Entries(
+              items.iter()
+                .map(|(rdata, ttl)| Ok((snapshot::encode_rdata(rdata)?, *ttl)))
+                .collect::<Result<_, SnapshotError>>()?
+            ),
+          };
+          out.records.push(snapshot::RecordSet {
+            name: snapshot::encode_name(name),
+            server: snapshot::encode_server(server),
+            rtype: snapshot::encode_type(*rtype),
+            entry,
+          });
+        }
+      }
+    }
+
+    // Sets are sorted, so saving the same database gives the same file.
+    for (name, rtype) in self.get_answer_targets() {
+      out.answer_targets.push(snapshot::Target {
+        name: snapshot::encode_name(&name),
+        rtype: snapshot::encode_type(rtype),
+      });
+    }
+    let mut address_targets: Vec<_> = self.address_targets.iter().collect();
+    address_targets.sort();
+    for (name, rtype) in address_targets {
+      out.address_targets.push(snapshot::Target {
+        name: snapshot::encode_name(name),
+        rtype: snapshot::encode_type(*rtype),
+      });
+    }
+    let mut targets: Vec<_> = self.targets.iter().collect();
+    targets.sort();
+    for (name, rtype, zone) in targets {
+      out.targets.push(snapshot::ZoneTarget {
+        name: snapshot::encode_name(name),
+        rtype: snapshot::encode_type(*rtype),
+        zone: snapshot::encode_name(zone),
+      });
+    }
+
+    for ((name, zone), delegations) in &self.delegations {
+      let mut delegations: Vec<_> = delegations.iter().collect();
+      delegations.sort();
+      for (auth_zone, ns) in delegations {
+        out.delegations.push(snapshot::Delegation {
+          name: snapshot::encode_name(name),
+          zone: snapshot::encode_name(zone),
+          auth_zone: snapshot::encode_name(auth_zone),
+          ns: snapshot::encode_name(ns),
+        });
+      }
+    }
+
+    for ((name, rtype), servers) in &self.negative_answers {
+      for (server, negative) in servers {
+        out.negative_answers.push(snapshot::Negative {
+          name: snapshot::encode_name(name),
+          rtype: snapshot::encode_type(*rtype),
+          server: snapshot::encode_server(server),
+          nxdomain: negative.nxdomain,
+          authority: negative.authority.iter()
+            .map(snapshot::encode_record)
+            .collect::<Result<_, _>>()?,
+        });
+      }
+    }
+
+    for ((name, rtype), targets) in &self.aliases {
+      for (target, kind) in targets {
+        out.aliases.push(snapshot::Alias {
+          name: snapshot::encode_name(name),
+          rtype: snapshot::encode_type(*rtype),
+          target: snapshot::encode_name(target),
+          kind: *kind,
+        });
+      }
+    }
+
+    for (server, info) in &self.server_info {
+      out.servers.push(snapshot::Server::new(server, info));
+    }
+
+    for (name, rtype, ip, zone) in &self.query_queue {
+      out.query_queue.push(snapshot::Query {
+        name: snapshot::encode_name(name),
+        rtype: snapshot::encode_type(*rtype),
+        server: *ip,
+        zone: zone.as_ref().map(snapshot::encode_name),
+      });
+    }
+
+    out.query_log = self.query_log.iter().map(snapshot::LoggedQuery::new).collect();
+
+    Ok(out)
+  }
+
+  /// Restore a database saved with to_snapshot.
+  pub fn from_snapshot(saved: &Snapshot) -> Result<RecordDB, SnapshotError> {
+    snapshot::check_version(saved.version)?;
+    let mut db = RecordDB::new();
+
+    for set in &saved.records {
+      let rtype = snapshot::decode_type(&set.rtype)?;
+      let entry = match &set.entry {
+        snapshot::Entry::NoEntry => REntry::NoEntry,
+        snapshot::Entry::TimeOut => REntry::TimeOut,
+        snapshot::Entry::Entries(items) => REntry::Entries(
+          items.iter()
+            .map(|(rdata, ttl)| Ok((snapshot::decode_rdata(rtype, rdata)?, *ttl)))
+            .collect::<Result<_, SnapshotError>>()?
+        ),
+      };
+      db.records
+        .entry(snapshot::decode_name(&set.name)?).or_default()
+        .entry(snapshot::decode_server(&set.server)?).or_default()
+        .insert(rtype, entry);
+    }
+
+    for target in &saved.answer_targets {
+      db.answer_targets.insert(
+        (snapshot::decode_name(&target.name)?, snapshot::decode_type(&target.rtype)?));
+    }
+    for target in &saved.address_targets {
+      db.address_targets.insert(
+        (snapshot::decode_name(&target.name)?, snapshot::decode_type(&target.rtype)?));
+    }
+    for target in &saved.targets {
+      db.targets.insert((
+        snapshot::decode_name(&target.name)?,
+        snapshot::decode_type(&target.rtype)?,
+        snapshot::decode_name(&target.zone)?,
+      ));
+    }
+
+    for delegation in &saved.delegations {
+      db.delegations
+        .entry((snapshot::decode_name(&delegation.name)?, snapshot::decode_name(&delegation.zone)?))
+        .or_default()
+        .insert((snapshot::decode_name(&delegation.auth_zone)?,
+                 snapshot::decode_name(&delegation.ns)?));
+    }
+
+    for negative in &saved.negative_answers {
+      let authority = negative.authority.iter()
+        .map(|record| snapshot::decode_record(record))
+        .collect::<Result<_, _>>()?;
+      db.negative_answers
+        .entry((snapshot::decode_name(&negative.name)?, snapshot::decode_type(&negative.rtype)?))
+        .or_default()
+        .insert(snapshot::decode_server(&negative.server)?,
+                NegativeAnswer { nxdomain: negative.nxdomain, authority });
+    }
+
+    for alias in &saved.aliases {
+      db.aliases
+        .entry((snapshot::decode_name(&alias.name)?, snapshot::decode_type(&alias.rtype)?))
+        .or_default()
+        .insert(snapshot::decode_name(&alias.target)?, alias.kind);
+    }
+
+    for server in &saved.servers {
+      let (server, info) = server.restore()?;
+      db.server_info.insert(server, info);
+    }
+
+    for query in &saved.query_queue {
+      let zone = match &query.zone {
+        Some(zone) => Some(snapshot::decode_name(zone)?),
+        None => None,
+      };
+      db.query_queue.push_back(
+        (snapshot::decode_name(&query.name)?, snapshot::decode_type(&query.rtype)?,
+         query.server, zone));
+    }
+
+    for query in &saved.query_log {
+      db.query_log.push(query.restore()?);
+    }
+
+    // Make sure the action loop runs at least once, to pick up where the
+    // saved run stopped.
+    db.change_num = 1;
+
+    Ok(db)
+  }
+
   /// Dump database to stdout.
   pub fn dump (&self) {
     println!("Delegations");
@@ -455,15 +1281,79 @@ impl RecordDB {
       println!("  {} {}", name, rtype);
     }
 
+    println!("Address Targets");
+
+    for (name, rtype) in &self.address_targets {
+      println!("  {} {}", name, rtype);
+    }
+
     println!("Targets");
 
     for (name, rtype, zone) in &self.targets {
       println!("  {} {} {}", name, rtype, zone);
     }
 
+    println!("Negative Answers");
+
+    for ((name, rtype), servers) in &self.negative_answers {
+      for (server, negative) in servers {
+        let kind = if negative.nxdomain { "NXDOMAIN" } else { "NODATA" };
+        println!("  {} {} {:?} {}", name, rtype, server, kind);
+        for rec in &negative.authority {
+          println!("    {} {} {:?}", rec.name(), rec.rr_type(), rec.rdata());
+        }
+      }
+    }
+
+    println!("Aliases");
+
+    for ((name, rtype), targets) in &self.aliases {
+      for (target, kind) in targets {
+        println!("  {} {} {:?} {}", name, rtype, kind, target);
+      }
+    }
+
+    println!("Server Info");
+
+    for (server, info) in &self.server_info {
+      println!("  {:?}", server);
+      if let Some(identity) = &info.identity {
+        println!("    Identity: {}", identity.describe());
+      }
+      if let Some(recursion) = &info.recursion {
+        println!("    Recursion: {:?}", recursion);
+      }
+      for (zone, transfer) in &info.zone_transfers {
+        println!("    AXFR {}: {:?}", zone, transfer);
+      }
+      if let Some(edns) = &info.edns {
+        println!("    EDNS compliance ({})", edns.zone);
+        for (test, result) in &edns.results {
+          println!("      {}: {:?}", test, result);
+        }
+      }
+    }
+
+    println!("Query Log");
+
+    for query in &self.query_log {
+      let start = query.start.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+      match &query.response {
+        Some(response) =>
+          println!("  {} {} {} {} {}: {} ms, {} bytes, {}, flags {}, {} attempts",
+                   start, query.server, query.transport, query.name, query.rtype,
+                   response.rtt.as_millis(), response.size, response.rcode,
+                   response.flags, query.attempts),
+        None =>
+          println!("  {} {} {} {} {}: timed out, {} attempts",
+                   start, query.server, query.transport, query.name, query.rtype,
+                   query.attempts),
+      }
+    }
+
     println!("Query Queue");
 
-    for (name, rtype, ip, zone) in &self.query_queue {
+    for (name, rtype, ip, _zone) in &self.query_queue {
       println!("  {} {} {}", name, rtype, ip);
     }
 
@@ -477,9 +1367,9 @@ impl RecordDB {
           println!("  Server IP: {:?}, RType {:?}", ip, rtype);
           match entry {
             REntry::Entries(v) => {
-              for rdata in v {
+              for (rdata, ttl) in v {
                 println!("    Entries");
-                println!("      {:?}", rdata);
+                println!("      {} {:?}", ttl, rdata);
               }
             },
             e => println!("    {:?}", e),
@@ -489,3 +1379,63 @@ impl RecordDB {
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn name(name: &str) -> rr::Name {
+    rr::Name::from_str(name).unwrap()
+  }
+
+  fn record(owner: &str, rdata: RData) -> rr::Record {
+    rr::Record::from_rdata(name(owner), 3600, rdata)
+  }
+
+  #[test]
+  fn referring_zones_ns_not_resolved() {
+    let mut db = RecordDB::new();
+    db.add_root_hints(vec![(name("a.root-servers.net."), "198.41.0.4".parse().unwrap())]);
+    db.add_answer_target(&name("example.com."), rr::RecordType::A);
+    db.add_target(&name("example.com."), rr::RecordType::A, &rr::Name::root());
+
+    db.generate_queries();
+    assert!(!db.is_address_target(&name("a.root-servers.net."), rr::RecordType::A));
+  }
+
+  #[test]
+  fn answering_zones_ns_resolved() {
+    let server: IpAddr = "192.0.2.1".parse().unwrap();
+    let mut db = RecordDB::new();
+    db.add_answer_target(&name("example.com."), rr::RecordType::A);
+    db.add_target(&name("example.com."), rr::RecordType::A, &name("example.com."));
+    db.add_record(&record("example.com.", RData::NS(name("ns1.example.net."))), server);
+    db.add_record(&record("ns1.example.net.", RData::A("192.0.2.1".parse().unwrap())), server);
+    db.add_record(&record("example.com.", RData::A("192.0.2.80".parse().unwrap())), server);
+
+    db.generate_queries();
+    assert!(db.is_address_target(&name("ns1.example.net."), rr::RecordType::A));
+    assert!(db.is_address_target(&name("ns1.example.net."), rr::RecordType::AAAA));
+  }
+
+  #[test]
+  fn alias_chain_lengths() {
+    let mut db = RecordDB::new();
+    db.add_alias(&name("a.example.com."), rr::RecordType::A, &name("b.example.com."),
+                 AliasKind::CName);
+    db.add_alias(&name("b.example.com."), rr::RecordType::A, &name("c.example.org."),
+                 AliasKind::DName);
+    db.add_alias(&name("x.example.com."), rr::RecordType::A, &name("c.example.org."),
+                 AliasKind::CName);
+    assert_eq!(db.alias_chain_length(&name("a.example.com."), rr::RecordType::A), 0);
+    assert_eq!(db.alias_chain_length(&name("b.example.com."), rr::RecordType::A), 1);
+    // The longest way in counts.
+    assert_eq!(db.alias_chain_length(&name("c.example.org."), rr::RecordType::A), 2);
+    assert_eq!(db.alias_chain_length(&name("c.example.org."), rr::RecordType::AAAA), 0);
+
+    // Loops end once every name was seen.
+    db.add_alias(&name("c.example.org."), rr::RecordType::A, &name("a.example.com."),
+                 AliasKind::CName);
+    assert_eq!(db.alias_chain_length(&name("a.example.com."), rr::RecordType::A), 2);
+  }
+}
@@ -0,0 +1,269 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::IpAddr;
+
+use trust_dns_client::rr;
+
+use crate::db::{Health, REntry, RecordDB, RServer};
+use crate::findings::{Check, Finding, Severity};
+
+fn join<T: ToString>(items: &BTreeSet<T>) -> String {
+  items.iter().map(|item| item.to_string()).collect::<Vec<_>>().join(", ")
+}
+
+/// Addresses of a name, as answered by the given servers.
+fn addresses(record_db: &RecordDB, name: &rr::Name, servers: &BTreeSet<RServer>)
+    -> BTreeSet<IpAddr> {
+  let mut ips = BTreeSet::new();
+  for (server, entries) in record_db.get_records(name) {
+    if !servers.contains(&server) {
+      continue;
+    }
+    for rtype in &[rr::RecordType::A, rr::RecordType::AAAA] {
+      if let Some(REntry::Entries(items)) = entries.get(rtype) {
+        ips.extend(items.iter().filter_map(|(rdata, _)| rdata.to_ip_addr()));
+      }
+    }
+  }
+  ips
+}
+
+/// Check the servers of every answer zone answer for it.
+fn check_lame(record_db: &RecordDB, zone: &rr::Name) -> Vec<Finding> {
+  let mut findings = Vec::new();
+
+  for server in record_db.get_zone_servers(zone) {
+    let finding = match record_db.get_health(zone, &server) {
+      Health::Lame => (Severity::Error, "server is lame, it doesn't answer for the zone"),
+      Health::TimeOut => (Severity::Warning, "server timed out"),
+      Health::Ok | Health::Unknown => continue,
+    };
+    findings.push(Finding::new(finding.0, Check::Lame, zone, Some(&server), finding.1.to_string()));
+  }
+
+  findings
+}
+
+/// Check the parent and the zone itself agree on its NS names.
+fn check_ns_consistency(record_db: &RecordDB, zone: &rr::Name) -> Option<Finding> {
+  let parent = record_db.get_parent_ns(zone);
+  let child = record_db.get_child_ns(zone);
+  if parent.is_empty() || child.is_empty() || parent == child {
+    return None;
+  }
+
+  let only_parent: BTreeSet<_> = parent.difference(&child).cloned().collect();
+  let only_child: BTreeSet<_> = child.difference(&parent).cloned().collect();
+  let mut problems = Vec::new();
+  if !only_parent.is_empty() {
+    problems.push(format!("only the parent has {}", join(&only_parent)));
+  }
+  if !only_child.is_empty() {
+    problems.push(format!("only the zone has {}", join(&only_child)));
+  }
+  Some(Finding::new(
+    Severity::Warning, Check::NsConsistency, zone, None,
+    format!("parent and zone NS names differ, {}", problems.join("; "))
+  ))
+}
+
+/// Check the glue given by the parent for NS names inside the zone matches
+/// the addresses the zone's servers give.
+fn check_glue(record_db: &RecordDB, zone: &rr::Name) -> Vec<Finding> {
+  let mut findings = Vec::new();
+  let parent_servers = match record_db.get_parent_zone(zone) {
+    Some(parent) => record_db.get_zone_servers(&parent),
+    None => return findings,
+  };
+  let zone_servers = record_db.get_zone_servers(zone);
+
+  for ns in record_db.get_parent_ns(zone) {
+    if !zone.zone_of(&ns) {
+      continue;
+    }
+    let glue = addresses(record_db, &ns, &parent_servers);
+    let authoritative = addresses(record_db, &ns, &zone_servers);
+
+    if glue.is_empty() {
+      findings.push(Finding::new(
+        Severity::Error, Check::Glue, zone, None,
+        format!("no glue for {}, which is inside the zone", ns)
+      ));
+    } else if !authoritative.is_empty() && glue != authoritative {
+      findings.push(Finding::new(
+        Severity::Error, Check::Glue, zone, None,
+        format!("glue for {} is {}, the zone has {}", ns, join(&glue), join(&authoritative))
+      ));
+    }
+  }
+
+  findings
+}
+
+/// Check the servers of a zone agree on its SOA serial.
+fn check_soa_serial(record_db: &RecordDB, zone: &rr::Name) -> Option<Finding> {
+  let servers = record_db.get_zone_servers(zone);
+  let mut serials: BTreeMap<u32, usize> = BTreeMap::new();
+  for (server, entries) in record_db.get_records(zone) {
+    if !servers.contains(&server) {
+      continue;
+    }
+    if let Some(REntry::Entries(items)) = entries.get(&rr::RecordType::SOA) {
+      if let Some(soa) = items.iter().filter_map(|(rdata, _)| rdata.as_soa()).next() {
+        *serials.entry(soa.serial()).or_insert(0) += 1;
+      }
+    }
+  }
+
+  if serials.len() > 1 {
+    let serials: Vec<_> = serials.iter()
+      .map(|(serial, count)| format!("{} ({} servers)", serial, count))
+      .collect();
+    Some(Finding::new(
+      Severity::Warning, Check::SoaSerial, zone, None,
+      format!("servers disagree on the SOA serial: {}", serials.join(", "))
+    ))
+  } else {
+    None
+  }
+}
+
+/// Check the delegation of every answer zone.
+///
+/// Servers must answer for the zone they're delegated, the parent and the
+/// zone must agree on NS names and on the addresses of NS names inside the
+/// zone, and the zone's servers must agree on the SOA serial.
+pub fn check_delegations(record_db: &RecordDB) -> Vec<Finding> {
+  let mut findings = Vec::new();
+
+  for zone in record_db.get_answer_zones() {
+    findings.extend(check_lame(record_db, &zone));
+    findings.extend(check_ns_consistency(record_db, &zone));
+    findings.extend(check_glue(record_db, &zone));
+    findings.extend(check_soa_serial(record_db, &zone));
+  }
+
+  findings
+}
+
+#[cfg(test)]
+mod tests {
+  use std::str::FromStr;
+
+  use trust_dns_client::rr::RData;
+
+  use super::*;
+
+  fn name(name: &str) -> rr::Name {
+    rr::Name::from_str(name).unwrap()
+  }
+
+  fn ip(ip: &str) -> IpAddr {
+    ip.parse().unwrap()
+  }
+
+  fn add(db: &mut RecordDB, owner: &str, rdata: RData, server: &str) {
+    db.add_record(&rr::Record::from_rdata(name(owner), 3600, rdata), ip(server));
+  }
+
+  fn soa(serial: u32) -> RData {
+    RData::SOA(rr::rdata::SOA::new(
+      name("ns1.example.com."), name("hostmaster.example.com."), serial, 3600, 600, 86400, 300))
+  }
+
+  /// Database with example.com delegated from com, served at 198.51.100.1,
+  /// to ns1 and ns2.example.com, at 192.0.2.1 and 192.0.2.2.
+  ///
+  /// Both servers answer for the zone, agreeing with the parent. Only
+  /// ns1 was asked for the SOA.
+  fn record_db() -> RecordDB {
+    let mut db = RecordDB::new();
+    add(&mut db, "com.", RData::NS(name("ns.com-servers.net.")), "198.51.100.1");
+    add(&mut db, "ns.com-servers.net.", RData::A("198.51.100.1".parse().unwrap()), "198.51.100.1");
+
+    for (ns, address) in &[("ns1.example.com.", "192.0.2.1"), ("ns2.example.com.", "192.0.2.2")] {
+      db.add_delegation(&name("www.example.com."), &name("com."), &name("example.com."), &name(ns));
+      add(&mut db, ns, RData::A(address.parse().unwrap()), "198.51.100.1");
+      for server in &["192.0.2.1", "192.0.2.2"] {
+        add(&mut db, "example.com.", RData::NS(name(ns)), server);
+        add(&mut db, ns, RData::A(address.parse().unwrap()), server);
+      }
+    }
+    add(&mut db, "example.com.", soa(1), "192.0.2.1");
+    db
+  }
+
+  fn messages(findings: Vec<Finding>) -> Vec<String> {
+    findings.into_iter().map(|finding| finding.message).collect()
+  }
+
+  #[test]
+  fn healthy_delegation_passes() {
+    let mut db = record_db();
+    add(&mut db, "example.com.", soa(1), "192.0.2.2");
+    let zone = name("example.com.");
+    assert!(check_lame(&db, &zone).is_empty());
+    assert!(check_ns_consistency(&db, &zone).is_none());
+    assert!(check_glue(&db, &zone).is_empty());
+    assert!(check_soa_serial(&db, &zone).is_none());
+  }
+
+  #[test]
+  fn lame_servers_flagged() {
+    let mut db = RecordDB::new();
+    let zone = name("example.com.");
+    add(&mut db, "example.com.", RData::NS(name("ns1.example.com.")), "192.0.2.1");
+    add(&mut db, "example.com.", RData::NS(name("ns2.example.com.")), "192.0.2.1");
+    add(&mut db, "ns1.example.com.", RData::A("192.0.2.1".parse().unwrap()), "192.0.2.1");
+    add(&mut db, "ns2.example.com.", RData::A("192.0.2.2".parse().unwrap()), "192.0.2.1");
+    add(&mut db, "ns2.example.com.", RData::A("192.0.2.3".parse().unwrap()), "192.0.2.1");
+    db.add_rentry(&zone, REntry::NoEntry, rr::RecordType::SOA, ip("192.0.2.2"));
+    db.add_rentry(&zone, REntry::TimeOut, rr::RecordType::SOA, ip("192.0.2.3"));
+
+    let findings = check_lame(&db, &zone);
+    assert_eq!(findings.len(), 2);
+    assert_eq!(findings[0].severity, Severity::Error);
+    assert_eq!(findings[0].server, Some(RServer::from(ip("192.0.2.2"))));
+    assert_eq!(findings[1].severity, Severity::Warning);
+    assert_eq!(findings[1].server, Some(RServer::from(ip("192.0.2.3"))));
+  }
+
+  #[test]
+  fn ns_differences_flagged() {
+    let mut db = record_db();
+    add(&mut db, "example.com.", RData::NS(name("ns.example.net.")), "192.0.2.1");
+    db.add_delegation(&name("www.example.com."), &name("com."), &name("example.com."),
+                      &name("ns.example.org."));
+    let finding = check_ns_consistency(&db, &name("example.com.")).unwrap();
+    assert_eq!(finding.message, "parent and zone NS names differ, \
+                                 only the parent has ns.example.org.; \
+                                 only the zone has ns.example.net.");
+  }
+
+  #[test]
+  fn glue_flagged() {
+    let mut db = record_db();
+    // ns2 moved, the parent wasn't told.
+    add(&mut db, "ns2.example.com.", RData::A("192.0.2.20".parse().unwrap()), "192.0.2.1");
+    // ns3 delegated to without glue.
+    db.add_delegation(&name("www.example.com."), &name("com."), &name("example.com."),
+                      &name("ns3.example.com."));
+    // Glue is only needed inside the zone.
+    db.add_delegation(&name("www.example.com."), &name("com."), &name("example.com."),
+                      &name("ns.example.net."));
+
+    assert_eq!(messages(check_glue(&db, &name("example.com."))), vec![
+      "glue for ns2.example.com. is 192.0.2.2, the zone has 192.0.2.2, 192.0.2.20".to_string(),
+      "no glue for ns3.example.com., which is inside the zone".to_string(),
+    ]);
+  }
+
+  #[test]
+  fn soa_serials_flagged() {
+    let mut db = record_db();
+    add(&mut db, "example.com.", soa(2), "192.0.2.2");
+    // Outsiders' answers don't count.
+    add(&mut db, "example.com.", soa(3), "198.51.100.1");
+    let finding = check_soa_serial(&db, &name("example.com.")).unwrap();
+    assert_eq!(finding.message, "servers disagree on the SOA serial: 1 (1 servers), 2 (1 servers)");
+  }
+}
@@ -0,0 +1,438 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use serde::Serialize;
+use trust_dns_client::rr::{self, RData};
+
+use crate::db::{REntry, RecordDB, RServer};
+use crate::findings::{self, Check, CheckConfig, Finding};
+use crate::snapshot;
+
+/// What changed between two runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChangeKind {
+  /// NS names given for a zone in referrals from its parent.
+  ParentNs,
+  /// NS records a server answered for a zone.
+  Ns,
+  /// Addresses of a nameserver, as a whole or from one server (glue from
+  /// parent servers).
+  Addresses,
+  /// SOA serial a server answered for a zone.
+  SoaSerial,
+  /// A server stopped answering.
+  NewTimeout,
+  /// A server that timed out answers again.
+  TimeoutResolved,
+  FindingAdded,
+  FindingRemoved,
+}
+
+impl fmt::Display for ChangeKind {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      ChangeKind::ParentNs => write!(f, "parent NS"),
+      ChangeKind::Ns => write!(f, "NS"),
+      ChangeKind::Addresses => write!(f, "addresses"),
+      ChangeKind::SoaSerial => write!(f, "SOA serial"),
+      ChangeKind::NewTimeout => write!(f, "new timeout"),
+      ChangeKind::TimeoutResolved => write!(f, "timeout resolved"),
+      ChangeKind::FindingAdded => write!(f, "new finding"),
+      ChangeKind::FindingRemoved => write!(f, "finding gone"),
+    }
+  }
+}
+
+/// A single change, as values removed and added.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct Change {
+  /// Name (or zone) the change is about.
+  pub name: String,
+  /// Server the change is about, if it's specific to one server.
+  pub server: Option<String>,
+  pub kind: ChangeKind,
+  pub removed: Vec<String>,
+  pub added: Vec<String>,
+}
+
+impl Change {
+  fn new(kind: ChangeKind, name: &rr::Name, server: Option<&RServer>,
+         removed: Vec<String>, added: Vec<String>) -> Change {
+    Change {
+      name: snapshot::encode_name(name),
+      server: server.map(snapshot::encode_server),
+      kind,
+      removed,
+      added,
+    }
+  }
+}
+
+impl fmt::Display for Change {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match &self.server {
+      Some(server) => write!(f, "@{} {}", server, self.kind)?,
+      None => write!(f, "{}", self.kind)?,
+    }
+
+    match self.kind {
+      ChangeKind::SoaSerial => {
+        write!(f, ": {} -> {}", self.removed.join(", "), self.added.join(", "))?;
+        let serials = (self.removed.first().and_then(|s| s.parse().ok()),
+                       self.added.first().and_then(|s| s.parse().ok()));
+        if let (Some(old), Some(new)) = serials {
+          if serial_decreased(old, new) {
+            write!(f, " (went backwards)")?;
+          }
+        }
+        Ok(())
+      },
+      _ => {
+        for value in &self.removed {
+          write!(f, " -{}", value)?;
+        }
+        for value in &self.added {
+          write!(f, " +{}", value)?;
+        }
+        Ok(())
+      },
+    }
+  }
+}
+
+/// Record data in a form that's stable across runs.
+fn rdata_text(rdata: &RData) -> String {
+  match rdata {
+    RData::A(ip) => ip.to_string(),
+    RData::AAAA(ip) => ip.to_string(),
+    RData::NS(name) => name.to_string(),
+    RData::SOA(soa) => soa.serial().to_string(),
+    _ => format!("{:?}", rdata),
+  }
+}
+
+/// Records of a type a server answered for a name, None unless it answered.
+fn answered(servers: &BTreeMap<RServer, BTreeMap<rr::RecordType, REntry>>,
+            server: &RServer, rtype: rr::RecordType) -> Option<BTreeSet<String>> {
+  match servers.get(server).and_then(|entries| entries.get(&rtype)) {
+    Some(REntry::Entries(items)) => Some(items.iter().map(|(rdata, _)| rdata_text(rdata)).collect()),
+    _ => None,
+  }
+}
+
+/// Values only in the first set, then only in the second.
+fn set_diff(old: &BTreeSet<String>, new: &BTreeSet<String>) -> (Vec<String>, Vec<String>) {
+  (old.difference(new).cloned().collect(), new.difference(old).cloned().collect())
+}
+
+/// Compare what each server answered for a name, for servers that answered
+/// in both runs.
+fn diff_answers(old: &RecordDB, new: &RecordDB, kind: ChangeKind, name: &rr::Name,
+                rtype: rr::RecordType, changes: &mut Vec<Change>) {
+  let old_servers = old.get_records(name);
+  let new_servers = new.get_records(name);
+
+  for server in old_servers.keys().filter(|server| **server != RServer::Hint) {
+    let (old_set, new_set) = match (answered(&old_servers, server, rtype),
+                                    answered(&new_servers, server, rtype)) {
+      (Some(old_set), Some(new_set)) => (old_set, new_set),
+      _ => continue,
+    };
+    let (removed, added) = set_diff(&old_set, &new_set);
+    if !removed.is_empty() || !added.is_empty() {
+      changes.push(Change::new(kind, name, Some(server), removed, added));
+    }
+  }
+}
+
+/// Whether an SOA serial went backwards (RFC 1982 serial arithmetic).
+fn serial_decreased(old: u32, new: u32) -> bool {
+  old != new && new.wrapping_sub(old) > 1 << 31
+}
+
+/// Compare the delegations of zones between two runs.
+fn diff_zone(old: &RecordDB, new: &RecordDB, zone: &rr::Name, changes: &mut Vec<Change>) {
+  let to_text = |names: BTreeSet<rr::Name>| -> BTreeSet<String> {
+    names.iter().map(|name| name.to_string()).collect()
+  };
+
+  let (removed, added) = set_diff(&to_text(old.get_parent_ns(zone)), &to_text(new.get_parent_ns(zone)));
+  if !removed.is_empty() || !added.is_empty() {
+    changes.push(Change::new(ChangeKind::ParentNs, zone, None, removed, added));
+  }
+
+  diff_answers(old, new, ChangeKind::Ns, zone, rr::RecordType::NS, changes);
+
+  // Every NS name either run knew, from either side of the zone cut.
+  let mut ns_names = BTreeSet::new();
+  for db in &[old, new] {
+    ns_names.extend(db.get_parent_ns(zone));
+    ns_names.extend(db.get_child_ns(zone));
+  }
+
+  for ns in &ns_names {
+    for rtype in &[rr::RecordType::A, rr::RecordType::AAAA] {
+      let all = |db: &RecordDB| -> BTreeSet<String> {
+        db.get_record_set(ns, *rtype).iter().map(rdata_text).collect()
+      };
+      let (removed, added) = set_diff(&all(old), &all(new));
+      if !removed.is_empty() || !added.is_empty() {
+        changes.push(Change::new(ChangeKind::Addresses, ns, None, removed, added));
+      }
+
+      diff_answers(old, new, ChangeKind::Addresses, ns, *rtype, changes);
+    }
+  }
+
+  let serials = |db: &RecordDB| -> BTreeMap<RServer, u32> {
+    db.get_records(zone).iter()
+      .filter_map(|(server, entries)| match entries.get(&rr::RecordType::SOA) {
+        Some(REntry::Entries(items)) => items.iter()
+          .filter_map(|(rdata, _)| rdata.as_soa())
+          .map(|soa| (server.clone(), soa.serial()))
+          .next(),
+        _ => None,
+      })
+      .collect()
+  };
+  let new_serials = serials(new);
+  for (server, old_serial) in serials(old) {
+    if let Some(new_serial) = new_serials.get(&server) {
+      if old_serial != *new_serial {
+        changes.push(Change::new(ChangeKind::SoaSerial, zone, Some(&server),
+                                 vec![old_serial.to_string()], vec![new_serial.to_string()]));
+      }
+    }
+  }
+}
+
+/// Record types a server timed out for, by name and server.
+fn timeouts(db: &RecordDB) -> BTreeSet<(rr::Name, RServer, String)> {
+  let mut out = BTreeSet::new();
+  for name in db.get_names() {
+    for (server, entries) in db.get_records(&name) {
+      for (rtype, entry) in entries {
+        if let REntry::TimeOut = entry {
+          out.insert((name.clone(), server.clone(), rtype.to_string()));
+        }
+      }
+    }
+  }
+  out
+}
+
+/// Compare two runs.
+///
+/// Delegations are compared for every answer zone of either run, and
+/// findings are checked on both with the same settings.
+pub fn diff(old: &RecordDB, new: &RecordDB, config: &CheckConfig) -> Vec<Change> {
+  let mut changes = diff_delegations(old, new);
+  changes.extend(diff_findings(&findings::check_all(old, config),
+                               &findings::check_all(new, config)));
+
+  changes.sort();
+  changes.dedup();
+  changes
+}
+
+/// Compare the delegations of every answer zone of either run, and the
+/// servers timing out.
+pub fn diff_delegations(old: &RecordDB, new: &RecordDB) -> Vec<Change> {
+  let mut changes = Vec::new();
+
+  let zones: BTreeSet<_> = old.get_answer_zones().into_iter()
+    .chain(new.get_answer_zones())
+    .collect();
+  for zone in &zones {
+    diff_zone(old, new, zone, &mut changes);
+  }
+
+  let old_timeouts = timeouts(old);
+  let new_timeouts = timeouts(new);
+  for (name, server, rtype) in new_timeouts.difference(&old_timeouts) {
+    changes.push(Change::new(ChangeKind::NewTimeout, name, Some(server),
+                             Vec::new(), vec![rtype.clone()]));
+  }
+  for (name, server, rtype) in old_timeouts.difference(&new_timeouts) {
+    changes.push(Change::new(ChangeKind::TimeoutResolved, name, Some(server),
+                             vec![rtype.clone()], Vec::new()));
+  }
+
+  changes.sort();
+  changes.dedup();
+  changes
+}
+
+/// Compare the findings of two runs.
+///
+/// Findings only differing in a measured value, such as how long a server
+/// took to answer, aren't changes.
+pub fn diff_findings(old: &[Finding], new: &[Finding]) -> Vec<Change> {
+  let mut changes = Vec::new();
+
+  let key = |finding: &Finding| {
+    let message = match finding.check {
+      Check::Latency => None,
+      _ => Some(finding.message.clone()),
+    };
+    (finding.check, finding.severity, finding.name.clone(), finding.server.clone(), message)
+  };
+  let old_keys: BTreeSet<_> = old.iter().map(key).collect();
+  let new_keys: BTreeSet<_> = new.iter().map(key).collect();
+  let describe = |finding: &Finding| {
+    format!("{} [{}] {}", finding.severity, finding.check, finding.message)
+  };
+  for finding in new.iter().filter(|finding| !old_keys.contains(&key(finding))) {
+    changes.push(Change::new(ChangeKind::FindingAdded, &finding.name, finding.server.as_ref(),
+                             Vec::new(), vec![describe(finding)]));
+  }
+  for finding in old.iter().filter(|finding| !new_keys.contains(&key(finding))) {
+    changes.push(Change::new(ChangeKind::FindingRemoved, &finding.name, finding.server.as_ref(),
+                             vec![describe(finding)], Vec::new()));
+  }
+
+  changes.sort();
+  changes.dedup();
+  changes
+}
+
+/// Dump changes to stdout, grouped by name.
+pub fn dump(changes: &[Change]) {
+  println!("Changes");
+
+  let mut last_name = None;
+  for change in changes {
+    if last_name != Some(&change.name) {
+      println!("  {}", change.name);
+      last_name = Some(&change.name);
+    }
+    println!("    {}", change);
+  }
+}
+
+/// Dump changes to stdout as a JSON list.
+pub fn dump_json(changes: &[Change]) {
+  println!("{}", serde_json::to_string_pretty(changes).unwrap());
+}
+
+#[cfg(test)]
+mod tests {
+  use std::net::IpAddr;
+  use std::str::FromStr;
+
+  use trust_dns_client::rr::rdata::SOA;
+
+  use super::*;
+
+  const PARENT: &str = "192.0.2.100";
+  const NS1: &str = "192.0.2.1";
+
+  fn name(name: &str) -> rr::Name {
+    rr::Name::from_str(name).unwrap()
+  }
+
+  fn server(ip: &str) -> IpAddr {
+    ip.parse().unwrap()
+  }
+
+  /// example.com delegated to the given NS names, with NS1 answering the
+  /// given SOA serial.
+  fn run(ns_names: &[&str], serial: u32) -> RecordDB {
+    let zone = name("example.com.");
+    let mut db = RecordDB::new();
+    db.add_answer_target(&zone, rr::RecordType::SOA);
+    db.add_target(&zone, rr::RecordType::SOA, &zone);
+    for ns in ns_names {
+      db.add_delegation(&zone, &name("com."), &zone, &name(ns));
+      db.add_record(&rr::Record::from_rdata(zone.clone(), 3600, RData::NS(name(ns))),
+                    server(PARENT));
+    }
+    db.add_record(&rr::Record::from_rdata(name("ns1.example.com."), 3600,
+                                          RData::A(NS1.parse().unwrap())), server(PARENT));
+    let soa = SOA::new(name("ns1.example.com."), name("hostmaster.example.com."),
+                       serial, 7200, 3600, 1209600, 3600);
+    db.add_record(&rr::Record::from_rdata(zone, 3600, RData::SOA(soa)), server(NS1));
+    db
+  }
+
+  #[test]
+  fn same_run_has_no_changes() {
+    let old = run(&["ns1.example.com.", "ns2.example.com."], 10);
+    let new = run(&["ns1.example.com.", "ns2.example.com."], 10);
+    assert!(diff(&old, &new, &CheckConfig::default()).is_empty());
+  }
+
+  #[test]
+  fn delegation_changes() {
+    let old = run(&["ns1.example.com.", "ns2.example.com."], 10);
+    let new = run(&["ns1.example.com.", "ns3.example.com."], 11);
+    let changes = diff_delegations(&old, &new);
+
+    let parent_ns = changes.iter().find(|change| change.kind == ChangeKind::ParentNs).unwrap();
+    assert_eq!(parent_ns.removed, vec!["ns2.example.com."]);
+    assert_eq!(parent_ns.added, vec!["ns3.example.com."]);
+
+    let serial = changes.iter().find(|change| change.kind == ChangeKind::SoaSerial).unwrap();
+    assert_eq!(serial.server.as_deref(), Some(NS1));
+    assert_eq!(serial.to_string(), format!("@{} SOA serial: 10 -> 11", NS1));
+  }
+
+  #[test]
+  fn serial_going_backwards() {
+    assert!(serial_decreased(10, 9));
+    assert!(!serial_decreased(10, 11));
+    // Serials wrap around.
+    assert!(!serial_decreased(u32::MAX, 1));
+
+    let changes = diff_delegations(&run(&["ns1.example.com."], 10),
+                                   &run(&["ns1.example.com."], 9));
+    assert!(changes[0].to_string().ends_with("10 -> 9 (went backwards)"));
+  }
+
+  #[test]
+  fn new_and_resolved_timeouts() {
+    let old = run(&["ns1.example.com."], 10);
+    let mut new = run(&["ns1.example.com."], 10);
+    new.add_rentry(&name("example.com."), REntry::TimeOut, rr::RecordType::NS, server(NS1));
+
+    let changes = diff_delegations(&old, &new);
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].kind, ChangeKind::NewTimeout);
+    assert_eq!(changes[0].added, vec!["NS"]);
+
+    let changes = diff_delegations(&new, &old);
+    assert_eq!(changes[0].kind, ChangeKind::TimeoutResolved);
+  }
+
+  #[test]
+  fn findings_sharing_a_key() {
+    let zone = name("example.com.");
+    let ns_target = |message: &str| {
+      Finding::new(findings::Severity::Error, Check::NsTarget, &zone, None, message.to_string())
+    };
+    let old = vec![ns_target("ns1.example.com. is an alias (CNAME)")];
+    let new = vec![ns_target("ns1.example.com. is an alias (CNAME)"),
+                   ns_target("ns2.example.com. doesn't exist (NXDOMAIN)")];
+
+    let changes = diff_findings(&old, &new);
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].kind, ChangeKind::FindingAdded);
+    assert!(changes[0].added[0].ends_with("ns2.example.com. doesn't exist (NXDOMAIN)"));
+
+    let changes = diff_findings(&new, &old);
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].kind, ChangeKind::FindingRemoved);
+  }
+
+  #[test]
+  fn measured_values_arent_changes() {
+    let zone = name("example.com.");
+    let ns1 = RServer::from(server(NS1));
+    let latency = |ms: u32| {
+      Finding::new(findings::Severity::Warning, Check::Latency, &zone, Some(&ns1),
+                   format!("took {} ms to answer for example.com. SOA", ms))
+    };
+    assert!(diff_findings(&[latency(1200)], &[latency(1800)]).is_empty());
+  }
+}
@@ -1,29 +1,536 @@
 use crate::db;
+use crate::fixture;
+use crate::pcap;
 
-use std::net::{IpAddr};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, UdpSocket};
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
-use log::{error, warn, info, debug, trace};
-use trust_dns_client::client::{Client, ClientHandle, SyncClient};
-use trust_dns_client::error::{ClientErrorKind, ClientResult};
-use trust_dns_client::op::DnsResponse;
+use log::{warn, debug, trace};
+use trust_dns_client::proto::error::ProtoError;
+use trust_dns_client::proto::serialize::binary::{BinDecodable, BinDecoder};
+use trust_dns_client::op::{Edns, Header, Message, MessageType, OpCode, Query, ResponseCode};
 use trust_dns_client::rr;
-use trust_dns_client::udp::UdpClientConnection;
+use trust_dns_client::rr::rdata::opt::EdnsOption;
+
+/// How long to wait for a server to answer a query.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Times a query is sent to a server before giving up on it.
+const QUERY_ATTEMPTS: u32 = 2;
+
+/// DNAME record type, unsupported by trust-dns.
+const DNAME_TYPE: u16 = 39;
+
+/// UDP payload size advertised with EDNS.
+const EDNS_PAYLOAD: u16 = 1232;
+
+/// Error returned when a DNS query fails.
+#[derive(Debug)]
+pub enum QueryError {
+  /// No answer from the server before the timeout.
+  Timeout,
+  /// Socket level error.
+  Io(io::Error),
+  /// The query couldn't be encoded, or the response couldn't be decoded.
+  Proto(ProtoError),
+}
+
+impl From<io::Error> for QueryError {
+  fn from(e: io::Error) -> QueryError {
+    match e.kind() {
+      io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => QueryError::Timeout,
+      _ => QueryError::Io(e),
+    }
+  }
+}
+
+impl From<ProtoError> for QueryError {
+  fn from(e: ProtoError) -> QueryError {
+    QueryError::Proto(e)
+  }
+}
+
+impl fmt::Display for QueryError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      QueryError::Timeout => write!(f, "query timed out"),
+      QueryError::Io(e) => write!(f, "io error: {}", e),
+      QueryError::Proto(e) => write!(f, "protocol error: {}", e),
+    }
+  }
+}
+
+/// Per query knobs.
+#[derive(Debug, Clone)]
+pub struct QueryOptions {
+  /// Class to query, IN unless asking for server identity.
+  pub class: rr::DNSClass,
+  /// Set the RD bit, asking the server to recurse.
+  pub recursion_desired: bool,
+  /// Include an EDNS OPT record, the remaining options need it.
+  pub edns: bool,
+  /// EDNS version.
+  pub edns_version: u8,
+  /// UDP payload size advertised with EDNS.
+  pub max_payload: u16,
+  /// Set the EDNS DO bit, so DNSSEC records are returned.
+  pub dnssec_ok: bool,
+  /// Extra EDNS flag bits to set, other than DO.
+  pub edns_flags: u16,
+  /// EDNS options to include, such as NSID (RFC 5001).
+  pub edns_options: Vec<EdnsOption>,
+}
+
+impl Default for QueryOptions {
+  fn default() -> QueryOptions {
+    QueryOptions {
+      class: rr::DNSClass::IN,
+      recursion_desired: false,
+      edns: true,
+      edns_version: 0,
+      max_payload: EDNS_PAYLOAD,
+      dnssec_ok: false,
+      edns_flags: 0,
+      edns_options: Vec::new(),
+    }
+  }
+}
+
+/// Where the queries of a check are captured to, and recorded to or
+/// answered from, besides the network.
+#[derive(Debug, Clone, Default)]
+pub struct Hooks {
+  pub capture: Option<Arc<pcap::Capture>>,
+  pub fixture: Option<Arc<fixture::Session>>,
+}
+
+impl Hooks {
+  fn replaying(&self) -> bool {
+    self.fixture.as_ref().is_some_and(|fixture| fixture.replaying())
+  }
+
+  fn capture_udp(&self, src: SocketAddr, dst: SocketAddr, message: &[u8]) {
+    if let Some(capture) = &self.capture {
+      capture.udp(src, dst, message);
+    }
+  }
+
+  fn capture_tcp(&self, src: SocketAddr, dst: SocketAddr, message: &[u8]) {
+    if let Some(capture) = &self.capture {
+      capture.tcp(src, dst, message);
+    }
+  }
+
+  fn record(&self, server: IpAddr, query: &[u8], transport: db::Transport,
+            result: &Result<Vec<u8>, QueryError>) {
+    if let Some(fixture) = &self.fixture {
+      fixture.add(server, query, transport, result);
+    }
+  }
+
+  fn answer(&self, server: IpAddr, query: &[u8], transport: db::Transport)
+      -> Result<Vec<u8>, QueryError> {
+    match &self.fixture {
+      Some(fixture) => fixture.answer(server, query, transport),
+      None => Err(QueryError::Timeout),
+    }
+  }
+}
+
+/// Build the query message for a name and record type.
+fn build_query(name: &rr::Name, record_type: rr::RecordType, options: &QueryOptions)
+    -> Message {
+  let mut query = Query::query(name.clone(), record_type);
+  query.set_query_class(options.class);
+
+  let mut message = Message::new();
+  message
+    .set_id(rand::random())
+    .set_message_type(MessageType::Query)
+    .set_op_code(OpCode::Query)
+    .set_recursion_desired(options.recursion_desired)
+    .add_query(query);
+
+  if options.edns {
+    let mut edns = Edns::new();
+    edns.set_version(options.edns_version);
+    edns.set_max_payload(options.max_payload);
+    edns.set_dnssec_ok(options.dnssec_ok);
+    for option in &options.edns_options {
+      edns.set_option(option.clone());
+    }
+    message.set_edns(edns);
+  }
+
+  message
+}
+
+/// Encode a query, setting any extra EDNS flags.
+///
+/// trust-dns only knows about the DO flag, so other flags are set in the
+/// OPT record TTL after encoding. The OPT record directly follows the
+/// question, as it's the only other record.
+fn encode_query(query: &Message, options: &QueryOptions) -> Result<Vec<u8>, QueryError> {
+  let mut bytes = query.to_vec()?;
+
+  if options.edns && options.edns_flags != 0 {
+    let mut decoder = BinDecoder::new(&bytes);
+    Header::read(&mut decoder)?;
+    Query::read(&mut decoder)?;
+    // Skip the root name, type and class to get to the flags in the TTL.
+    let flags = decoder.index() + 1 + 2 + 2 + 2;
+    let value = u16::from_be_bytes([bytes[flags], bytes[flags + 1]]) | options.edns_flags;
+    bytes[flags..flags + 2].copy_from_slice(&value.to_be_bytes());
+  }
+
+  Ok(bytes)
+}
+
+/// OPT record of a response, as sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawOpt {
+  /// Extended rcode, version and flags.
+  pub ttl: u32,
+  /// Codes of the options included.
+  pub options: Vec<u16>,
+}
+
+/// Get the OPT record of a response as sent, as trust-dns drops flags it
+/// doesn't know and options without a value.
+pub fn raw_opt(response: &[u8]) -> Result<Option<RawOpt>, QueryError> {
+  let mut decoder = BinDecoder::new(response);
+  let header = Header::read(&mut decoder)?;
+  for _ in 0..header.query_count() {
+    Query::read(&mut decoder)?;
+  }
+
+  let count = header.answer_count() as usize + header.name_server_count() as usize
+    + header.additional_count() as usize;
+  for _ in 0..count {
+    rr::Name::read(&mut decoder)?;
+    let rtype = rr::RecordType::from(decoder.read_u16()?.unverified());
+    decoder.read_u16()?;
+    let ttl = decoder.read_u32()?.unverified();
+    let length = decoder.read_u16()?.unverified() as usize;
+    let rdata = decoder.read_slice(length)?.unverified();
+    if rtype != rr::RecordType::OPT {
+      continue;
+    }
+
+    let mut options = Vec::new();
+    let mut rdata = BinDecoder::new(rdata);
+    while !rdata.is_empty() {
+      options.push(rdata.read_u16()?.unverified());
+      let length = rdata.read_u16()?.unverified() as usize;
+      rdata.read_slice(length)?;
+    }
+    return Ok(Some(RawOpt { ttl, options }));
+  }
+
+  Ok(None)
+}
+
+/// Send a query over UDP and wait for the matching response.
+fn round_trip_udp(server: SocketAddr, query: &[u8], id: u16, hooks: &Hooks)
+    -> Result<Vec<u8>, QueryError> {
+  let local: SocketAddr = match server {
+    SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+    SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+  };
+  let socket = UdpSocket::bind(local)?;
+  socket.set_read_timeout(Some(QUERY_TIMEOUT))?;
+  // Connect, so the local address used is known for packet captures.
+  socket.connect(server)?;
+  let local = socket.local_addr()?;
+  socket.send(query)?;
+  hooks.capture_udp(local, server, query);
+
+  let mut buf = [0u8; 65535];
+  loop {
+    let (len, from) = socket.recv_from(&mut buf)?;
+    // Ignore anything that isn't a response to our query.
+    if from != server || len < 2 || u16::from_be_bytes([buf[0], buf[1]]) != id {
+      trace!("Ignoring stray packet from {}", from);
+      continue;
+    }
+    hooks.capture_udp(server, local, &buf[..len]);
+    return Ok(buf[..len].to_vec());
+  }
+}
+
+/// Connect to a server over TCP, and send a length prefixed query.
+fn connect_tcp(server: SocketAddr, query: &[u8], hooks: &Hooks) -> Result<TcpStream, QueryError> {
+  let mut stream = TcpStream::connect_timeout(&server, QUERY_TIMEOUT)?;
+  stream.set_read_timeout(Some(QUERY_TIMEOUT))?;
+  stream.set_write_timeout(Some(QUERY_TIMEOUT))?;
+
+  let mut framed = (query.len() as u16).to_be_bytes().to_vec();
+  framed.extend_from_slice(query);
+  stream.write_all(&framed)?;
+  hooks.capture_tcp(stream.local_addr()?, server, query);
+  Ok(stream)
+}
+
+/// Read a single length prefixed message from a TCP stream.
+fn read_message(stream: &mut TcpStream, hooks: &Hooks) -> Result<Vec<u8>, QueryError> {
+  let mut len = [0u8; 2];
+  stream.read_exact(&mut len)?;
+  let mut buf = vec![0u8; u16::from_be_bytes(len) as usize];
+  stream.read_exact(&mut buf)?;
+  hooks.capture_tcp(stream.peer_addr()?, stream.local_addr()?, &buf);
+  Ok(buf)
+}
+
+/// Send a query over UDP and wait for the matching response, or answer it
+/// from the fixture being replayed.
+fn exchange_udp(server: SocketAddr, query: &[u8], id: u16, hooks: &Hooks)
+    -> Result<Vec<u8>, QueryError> {
+  if hooks.replaying() {
+    return hooks.answer(server.ip(), query, db::Transport::Udp);
+  }
+  let result = round_trip_udp(server, query, id, hooks);
+  hooks.record(server.ip(), query, db::Transport::Udp, &result);
+  result
+}
+
+/// A TCP connection a query was sent over, without a stream when
+/// replaying a fixture.
+struct TcpConnection {
+  server: SocketAddr,
+  query: Vec<u8>,
+  stream: Option<TcpStream>,
+}
+
+/// Connect to a server over TCP, and send a length prefixed query.
+fn send_tcp(server: SocketAddr, query: &[u8], hooks: &Hooks)
+    -> Result<TcpConnection, QueryError> {
+  let mut connection = TcpConnection { server, query: query.to_vec(), stream: None };
+  if hooks.replaying() {
+    return Ok(connection);
+  }
+
+  match connect_tcp(server, query, hooks) {
+    Ok(stream) => {
+      connection.stream = Some(stream);
+      Ok(connection)
+    },
+    Err(e) => {
+      // Record the failure as the response, replays fail when reading.
+      let result = Err(e);
+      hooks.record(server.ip(), query, db::Transport::Tcp, &result);
+      result.map(|_| connection)
+    },
+  }
+}
+
+/// Read a single response from a TCP connection.
+fn read_tcp(connection: &mut TcpConnection, hooks: &Hooks) -> Result<Vec<u8>, QueryError> {
+  let server = connection.server.ip();
+  match &mut connection.stream {
+    Some(stream) => {
+      let result = read_message(stream, hooks);
+      hooks.record(server, &connection.query, db::Transport::Tcp, &result);
+      result
+    },
+    None => hooks.answer(server, &connection.query, db::Transport::Tcp),
+  }
+}
+
+/// Send a query over TCP and read a single response.
+fn exchange_tcp(server: SocketAddr, query: &[u8], hooks: &Hooks) -> Result<Vec<u8>, QueryError> {
+  let mut connection = send_tcp(server, query, hooks)?;
+  read_tcp(&mut connection, hooks)
+}
+
+/// Perform a DNS query over UDP only.
+///
+/// The response is returned along with its raw bytes, truncated responses
+/// are returned as is.
+pub fn do_udp_query(server_ip: IpAddr, name: &rr::Name, record_type: rr::RecordType,
+                    options: &QueryOptions, hooks: &Hooks)
+    -> Result<(Message, Vec<u8>), QueryError> {
+  let server = SocketAddr::new(server_ip, 53);
+  let query = build_query(name, record_type, options);
+  let bytes = encode_query(&query, options)?;
+
+  trace!("Dns query: dig '{}' '{}' '@{}'", name, record_type, server_ip);
+
+  let response = exchange_udp(server, &bytes, query.id(), hooks)?;
+  Ok((Message::from_vec(&response)?, response))
+}
+
+/// A response, with how it was received.
+pub struct Exchange {
+  pub message: Message,
+  pub transport: db::Transport,
+  /// Size of the response message, in bytes.
+  pub size: usize,
+}
+
+/// Header flags of a message, as shown by dig.
+pub fn header_flags(message: &Message) -> String {
+  let flags = [
+    (message.message_type() == MessageType::Response, "qr"),
+    (message.authoritative(), "aa"),
+    (message.truncated(), "tc"),
+    (message.recursion_desired(), "rd"),
+    (message.recursion_available(), "ra"),
+    (message.authentic_data(), "ad"),
+    (message.checking_disabled(), "cd"),
+  ];
+  flags.iter()
+    .filter(|(set, _)| *set)
+    .map(|(_, flag)| *flag)
+    .collect::<Vec<_>>()
+    .join(" ")
+}
+
+/// Perform a DNS query, returning how the response was received along with
+/// it.
+///
+/// Queries are sent over UDP, and retried over TCP if the answer was
+/// truncated.
+pub fn do_dns_exchange(server_ip: IpAddr, name: &rr::Name, record_type: rr::RecordType,
+                       options: &QueryOptions, hooks: &Hooks) -> Result<Exchange, QueryError> {
+  let server = SocketAddr::new(server_ip, 53);
+  let query = build_query(name, record_type, options);
+  let bytes = encode_query(&query, options)?;
+
+  trace!("Dns query: dig '{}' '{}' '@{}'", name, record_type, server_ip);
+
+  let response = exchange_udp(server, &bytes, query.id(), hooks)?;
+  let message = Message::from_vec(&response)?;
+  if !message.truncated() {
+    return Ok(Exchange { message, transport: db::Transport::Udp, size: response.len() });
+  }
+
+  debug!("Truncated answer from {}, retrying over tcp", server_ip);
+  let response = exchange_tcp(server, &bytes, hooks)?;
+  let message = Message::from_vec(&response)?;
+  Ok(Exchange { message, transport: db::Transport::Tcp, size: response.len() })
+}
 
 /// Perform a DNS query.
 ///
 /// A basic wrapper to perform a DNS query and wait for result.
-pub fn do_dns_query(server_ip: IpAddr, name: &rr::Name, record_type: rr::RecordType)
-     -> ClientResult<DnsResponse> {
-  let client = SyncClient::new(
-    UdpClientConnection::new(
-      (server_ip, 53).into()
-    ).unwrap()
-  );
+pub fn do_dns_query(server_ip: IpAddr, name: &rr::Name, record_type: rr::RecordType,
+                    options: &QueryOptions, hooks: &Hooks) -> Result<Message, QueryError> {
+  do_dns_exchange(server_ip, name, record_type, options, hooks).map(|exchange| exchange.message)
+}
 
-  trace!("Dns query: dig '{}' '{}' '@{}'", name, record_type, server_ip);
+/// Messages of a zone transfer, as far as it got.
+#[derive(Debug)]
+pub struct Transfer {
+  pub messages: Vec<Message>,
+  /// Whether the closing SOA record was received.
+  pub complete: bool,
+  /// Why reading stopped midway, if it did.
+  pub error: Option<QueryError>,
+}
 
-  client.query(name, rr::DNSClass::IN, record_type)
+/// Attempt a zone transfer (AXFR) over TCP.
+///
+/// Messages are read until the closing SOA record, or until a message
+/// with an error response code, which is returned as the last message.
+/// An error before the first message fails the transfer, later errors
+/// are returned with the messages read so far.
+pub fn do_zone_transfer(server_ip: IpAddr, zone: &rr::Name, hooks: &Hooks)
+    -> Result<Transfer, QueryError> {
+  zone_transfer(SocketAddr::new(server_ip, 53), zone, hooks)
+}
+
+/// Zone transfer from any port, see do_zone_transfer.
+pub(crate) fn zone_transfer(server: SocketAddr, zone: &rr::Name, hooks: &Hooks)
+    -> Result<Transfer, QueryError> {
+  let query = build_query(zone, rr::RecordType::AXFR, &QueryOptions::default());
+
+  trace!("Dns query: dig '{}' AXFR '@{}'", zone, server.ip());
+
+  let mut connection = send_tcp(server, &query.to_vec()?, hooks)?;
+  let mut transfer = Transfer { messages: Vec::new(), complete: false, error: None };
+  let mut soa_count = 0;
+
+  while soa_count < 2 {
+    let msg = match read_tcp(&mut connection, hooks)
+        .and_then(|response| Ok(Message::from_vec(&response)?)) {
+      Ok(msg) => msg,
+      Err(e) if transfer.messages.is_empty() => return Err(e),
+      Err(e) => {
+        transfer.error = Some(e);
+        return Ok(transfer);
+      },
+    };
+    let done = msg.response_code() != ResponseCode::NoError || msg.answers().is_empty();
+    soa_count += msg.answers().iter()
+      .filter(|rec| rec.rr_type() == rr::RecordType::SOA)
+      .count();
+    transfer.messages.push(msg);
+
+    if done {
+      break;
+    }
+  }
+
+  transfer.complete = soa_count >= 2;
+  Ok(transfer)
+}
+
+/// Send a query, retrying on timeouts, and log it however it went.
+///
+/// Returns None when there was no answer, after noting that in the
+/// database.
+fn send_query(record_db: &mut db::RecordDB, server_ip: IpAddr, name: &rr::Name,
+              record_type: rr::RecordType, options: &QueryOptions) -> Option<Message> {
+  let hooks = record_db.get_hooks();
+  let start = SystemTime::now();
+  let mut attempts = 0;
+  let result = loop {
+    attempts += 1;
+    let sent = Instant::now();
+    match do_dns_exchange(server_ip, name, record_type, options, &hooks) {
+      Err(QueryError::Timeout) if attempts < QUERY_ATTEMPTS =>
+        debug!("Timeout from {}, retrying", server_ip),
+      result => break result.map(|exchange| (exchange, sent.elapsed())),
+    }
+  };
+
+  let mut logged = db::LoggedQuery {
+    name: name.clone(),
+    rtype: record_type,
+    server: server_ip,
+    transport: db::Transport::Udp,
+    start,
+    attempts,
+    response: None,
+  };
+  if let Ok((exchange, rtt)) = &result {
+    logged.transport = exchange.transport;
+    logged.response = Some(db::QueryResponse {
+      rtt: *rtt,
+      size: exchange.size,
+      rcode: exchange.message.response_code(),
+      flags: header_flags(&exchange.message),
+    });
+  }
+  record_db.add_logged_query(logged);
+
+  match result {
+    Ok((exchange, _)) => Some(exchange.message),
+    Err(e) => {
+      // Unreachable servers and refused or broken connections are no
+      // answer, just like timeouts.
+      match e {
+        QueryError::Timeout => debug!("No answer from {}", server_ip),
+        e => warn!("Query for {} {} to {} failed: {}", name, record_type, server_ip, e),
+      }
+      record_db.add_rentry(name, db::REntry::TimeOut, record_type, server_ip);
+      None
+    },
+  }
 }
 
 /// Query a givern record and add it to database.
@@ -32,57 +539,151 @@ pub fn query_record(record_db: &mut db::RecordDB, server_ip: IpAddr,
                     zone: Option<rr::Name>) {
   debug!("Query record {}, {}, {}", name, record_type, server_ip);
 
-  let result = match do_dns_query(server_ip, &name, record_type) {
-    Ok(r) => r,
-    Err(e) => {
-      match e.kind() {
-        // TODO: Add retries on timeout.
-        ClientErrorKind::Timeout =>
-          record_db.add_rentry(&name, db::REntry::TimeOut, record_type, server_ip),
-        // FIXME: More appropriate error?
-        _ => unimplemented!("We don't handle this  error yet: {}", e),
+  // Ask for DNSSEC records for answer targets, so denial of existence can
+  // be checked.
+  let options = QueryOptions {
+    dnssec_ok: record_db.is_answer_target(&name, record_type),
+    ..QueryOptions::default()
+  };
+
+  // Referrals and apex answers of zones above the ones checked might have
+  // been seen by another check already.
+  let cache = record_db.get_referral_cache();
+  let cached = cache.as_ref().and_then(|cache| {
+    cache.get(server_ip, &name, record_type, zone.as_ref(), &record_db.get_target_zones())
+  });
+  let msg = match cached {
+    Some(msg) => {
+      debug!("Cached answer for {}, {} from {}", name, record_type, server_ip);
+      msg
+    },
+    None => {
+      let msg = match send_query(record_db, server_ip, &name, record_type, &options) {
+        Some(msg) => msg,
+        None => return,
       };
-      return;
+      if let Some(cache) = &cache {
+        cache.add(server_ip, &name, record_type, zone.as_ref(), &msg);
+      }
+      msg
     },
   };
 
-  trace!("Got answer: {:?}", result);
+  trace!("Got answer: {:?}", msg);
 
   let mut has_answer = false;
 
-  for msg in result.messages() {
-    // Add query answers into database.
-    for rec in msg.answers() {
-      record_db.add_record(rec, server_ip);
+  // Add query answers into database.
+  for rec in msg.answers() {
+    record_db.add_record(rec, server_ip);
+    // Aliases (and signatures) aren't an answer for the requested type.
+    if rec.name() == &name
+        && (rec.rr_type() == record_type || record_type == rr::RecordType::ANY) {
       has_answer = true;
     }
+  }
+
+  // Follow aliases of answer targets, the alias target becomes an answer
+  // target of its own and is resolved from the root.
+  if !has_answer && record_db.is_answer_target(&name, record_type) {
+    if let Some((target, kind)) = find_alias(&name, record_type, msg.answers()) {
+      record_db.add_alias(&name, record_type, &target, kind);
 
-    // Add additional answers (glue records) into database.
-    for rec in msg.additionals() {
-      record_db.add_record(rec, server_ip);
+      if record_db.alias_chain_length(&target, record_type) <= db::MAX_ALIAS_CHAIN {
+        record_db.add_answer_target(&target, record_type);
+      } else {
+        warn!("Alias chain too long at {}, {}, not following", target, record_type);
+      }
     }
+  }
 
-    // Add any auth answers into the database.
-    for rec in msg.name_servers() {
-      // Add record.
-      record_db.add_record(rec, server_ip);
+  // Add additional answers (glue records) into database.
+  for rec in msg.additionals() {
+    record_db.add_record(rec, server_ip);
+  }
 
-      if let Some(ns) = rec.rdata().as_ns() {
-        if let Some(zone) = &zone {
-          record_db.add_delegation(&name, &zone, rec.name(), ns);
-        }
-      }
+  // Add any auth answers into the database.
+  for rec in msg.name_servers() {
+    // Add record.
+    record_db.add_record(rec, server_ip);
 
-      // If this is an answer target, add new target for given new authoritative zone.
-      if record_db.is_answer_target(&name, record_type) {
-        record_db.add_target(&name, record_type, rec.name());
+    if let Some(ns) = rec.rdata().as_ns() {
+      if let Some(zone) = &zone {
+        record_db.add_delegation(&name, zone, rec.name(), ns);
       }
     }
+
+    // If this is an answer target, add new target for given new authoritative zone.
+    // Only NS and SOA owners are zones, NSEC and signature owners aren't.
+    let zone_owner = matches!(rec.rr_type(), rr::RecordType::NS | rr::RecordType::SOA);
+    if zone_owner && (record_db.is_answer_target(&name, record_type)
+                      || record_db.is_address_target(&name, record_type)) {
+      record_db.add_target(&name, record_type, rec.name());
+    }
   }
 
   if !has_answer {
     record_db.add_rentry(&name, db::REntry::NoEntry, record_type, server_ip);
+
+    // An authoritative answer without records is NXDOMAIN or NODATA, keep
+    // the authority section around to check denial of existence.
+    let nxdomain = msg.response_code() == ResponseCode::NXDomain;
+    if msg.authoritative() && msg.answers().is_empty()
+        && (nxdomain || msg.response_code() == ResponseCode::NoError) {
+      let negative = db::NegativeAnswer {
+        nxdomain,
+        authority: msg.name_servers().to_vec(),
+      };
+      record_db.add_negative_answer(&name, record_type, server_ip, negative);
+    }
+  }
+}
+
+/// Find the alias to follow for a name, from the answer section.
+///
+/// A CNAME of the name itself wins, otherwise a DNAME of one of its
+/// ancestors is used to synthesize the new name.
+fn find_alias(name: &rr::Name, record_type: rr::RecordType, answers: &[rr::Record])
+    -> Option<(rr::Name, db::AliasKind)> {
+  if record_type == rr::RecordType::CNAME {
+    return None;
+  }
+
+  for rec in answers {
+    if rec.name() == name {
+      if let Some(target) = rec.rdata().as_cname() {
+        return Some((target.clone(), db::AliasKind::CName));
+      }
+    }
+  }
+
+  for rec in answers {
+    if rec.rr_type() != rr::RecordType::Unknown(DNAME_TYPE)
+        || rec.name() == name || !rec.name().zone_of(name) {
+      continue;
+    }
+
+    let target = match rec.rdata() {
+      rr::RData::Unknown { rdata, .. } => match rdata.anything()
+        .and_then(|bytes| rr::Name::read(&mut BinDecoder::new(bytes)).ok()) {
+        Some(target) => target,
+        None => {
+          warn!("Invalid DNAME for {}", rec.name());
+          continue;
+        },
+      },
+      _ => continue,
+    };
+
+    // Replace the DNAME owner suffix of the name with the DNAME target.
+    let prefix = (name.num_labels() - rec.name().num_labels()) as usize;
+    match rr::Name::from_labels(name.iter().take(prefix).chain(target.iter())) {
+      Ok(synthesized) => return Some((synthesized, db::AliasKind::DName)),
+      Err(e) => warn!("Can't synthesize DNAME target for {}: {}", name, e),
+    }
   }
+
+  None
 }
 
 pub fn root_hints() -> Vec<(rr::Name, IpAddr)> {
@@ -193,3 +794,65 @@ pub fn root_hints() -> Vec<(rr::Name, IpAddr)> {
     ),
   ]
 }
+
+#[cfg(test)]
+mod tests {
+  use trust_dns_client::proto::serialize::binary::BinEncodable;
+  use trust_dns_client::rr::rdata::NULL;
+
+  use super::*;
+
+  fn name(name: &str) -> rr::Name {
+    rr::Name::from_str(name).unwrap()
+  }
+
+  fn cname(owner: &str, target: &str) -> rr::Record {
+    rr::Record::from_rdata(name(owner), 300, rr::RData::CNAME(name(target)))
+  }
+
+  fn dname(owner: &str, target: &str) -> rr::Record {
+    let rdata = NULL::with(name(target).to_bytes().unwrap());
+    rr::Record::from_rdata(name(owner), 300, rr::RData::Unknown { code: DNAME_TYPE, rdata })
+  }
+
+  #[test]
+  fn cname_followed() {
+    let answers = vec![cname("www.example.com.", "web.example.net.")];
+    assert_eq!(find_alias(&name("www.example.com."), rr::RecordType::A, &answers),
+               Some((name("web.example.net."), db::AliasKind::CName)));
+    // Asking for the CNAME itself, it's the answer.
+    assert_eq!(find_alias(&name("www.example.com."), rr::RecordType::CNAME, &answers), None);
+    // Other names' CNAMEs don't matter.
+    assert_eq!(find_alias(&name("ftp.example.com."), rr::RecordType::A, &answers), None);
+  }
+
+  #[test]
+  fn dname_synthesized() {
+    let answers = vec![dname("example.com.", "example.net.")];
+    assert_eq!(find_alias(&name("www.sub.example.com."), rr::RecordType::A, &answers),
+               Some((name("www.sub.example.net."), db::AliasKind::DName)));
+    // A DNAME redirects names below its owner, not the owner itself.
+    assert_eq!(find_alias(&name("example.com."), rr::RecordType::A, &answers), None);
+    assert_eq!(find_alias(&name("www.example.org."), rr::RecordType::A, &answers), None);
+  }
+
+  #[test]
+  fn cname_wins_over_dname() {
+    let answers = vec![
+      dname("example.com.", "example.net."),
+      cname("www.example.com.", "www.example.net."),
+    ];
+    assert_eq!(find_alias(&name("www.example.com."), rr::RecordType::A, &answers),
+               Some((name("www.example.net."), db::AliasKind::CName)));
+  }
+
+  #[test]
+  fn invalid_dname_skipped() {
+    let rdata = NULL::with(vec![0xff]);
+    let answers = vec![
+      rr::Record::from_rdata(name("example.com."), 300,
+                             rr::RData::Unknown { code: DNAME_TYPE, rdata }),
+    ];
+    assert_eq!(find_alias(&name("www.example.com."), rr::RecordType::A, &answers), None);
+  }
+}
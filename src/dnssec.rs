@@ -0,0 +1,496 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use data_encoding::BASE32HEX_NOPAD;
+use trust_dns_client::proto::rr::dnssec::Nsec3HashAlgorithm;
+use trust_dns_client::proto::rr::dnssec::rdata::{DNSSECRData, DNSSECRecordType, NSEC, NSEC3};
+use trust_dns_client::rr::{self, RData};
+
+use crate::db::{NegativeAnswer, RecordDB, RServer};
+use crate::findings::{Check, Finding, Severity};
+
+/// NSEC3 hashing parameters, as used by a zone.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Nsec3Params {
+  algorithm: u8,
+  iterations: u16,
+  salt: Vec<u8>,
+  opt_out: bool,
+}
+
+impl Nsec3Params {
+  fn new(nsec3: &NSEC3) -> Nsec3Params {
+    Nsec3Params {
+      algorithm: u8::from(nsec3.hash_algorithm()),
+      iterations: nsec3.iterations(),
+      salt: nsec3.salt().to_vec(),
+      opt_out: nsec3.opt_out(),
+    }
+  }
+
+  fn describe(&self) -> String {
+    let salt = if self.salt.is_empty() {
+      "-".to_string()
+    } else {
+      self.salt.iter().map(|b| format!("{:02X}", b)).collect()
+    };
+    format!("algorithm {}, iterations {}, salt {}, opt-out {}",
+            self.algorithm, self.iterations, salt, self.opt_out)
+  }
+}
+
+/// NSEC and NSEC3 records, and the record types signed, from a negative answer.
+struct Denial<'a> {
+  nsecs: Vec<(&'a rr::Name, &'a NSEC)>,
+  nsec3s: Vec<(&'a rr::Name, &'a NSEC3)>,
+  signed: BTreeSet<rr::RecordType>,
+}
+
+impl<'a> Denial<'a> {
+  fn new(negative: &'a NegativeAnswer) -> Denial<'a> {
+    let mut denial = Denial {
+      nsecs: Vec::new(),
+      nsec3s: Vec::new(),
+      signed: BTreeSet::new(),
+    };
+
+    for rec in &negative.authority {
+      match rec.rdata() {
+        RData::DNSSEC(DNSSECRData::NSEC(nsec)) => denial.nsecs.push((rec.name(), nsec)),
+        RData::DNSSEC(DNSSECRData::NSEC3(nsec3)) => denial.nsec3s.push((rec.name(), nsec3)),
+        RData::DNSSEC(DNSSECRData::SIG(sig)) => { denial.signed.insert(sig.type_covered()); },
+        _ => (),
+      }
+    }
+
+    denial
+  }
+
+  fn is_empty(&self) -> bool {
+    self.nsecs.is_empty() && self.nsec3s.is_empty() && self.signed.is_empty()
+  }
+}
+
+/// Check denial of existence proofs in negative answers.
+///
+/// For every NXDOMAIN or NODATA answer from a signed zone, each server must
+/// include NSEC or NSEC3 records proving the name (or type) doesn't exist.
+/// Only coverage of the name is checked, signatures are required to be
+/// present but aren't validated. NSEC3 parameters are reported per server,
+/// and servers of the same zone using different parameters are flagged.
+pub fn check_denials(record_db: &RecordDB) -> Vec<Finding> {
+  let mut findings = Vec::new();
+  let mut zone_params: BTreeMap<rr::Name, BTreeMap<RServer, Nsec3Params>> = BTreeMap::new();
+
+  for ((name, rtype), servers) in record_db.get_negative_answers() {
+    // The zone is signed if any server returned DNSSEC records for it.
+    let signed = servers.values().any(|negative| !Denial::new(negative).is_empty());
+    if !signed {
+      continue;
+    }
+
+    for (server, negative) in servers {
+      let denial = Denial::new(negative);
+
+      if let Some((owner, nsec3)) = denial.nsec3s.first() {
+        zone_params
+          .entry(owner.base_name()).or_default()
+          .insert(server.clone(), Nsec3Params::new(nsec3));
+      }
+
+      if let Err(e) = check_denial(name, *rtype, negative.nxdomain, &denial) {
+        let kind = if negative.nxdomain { "NXDOMAIN" } else { "NODATA" };
+        findings.push(Finding::new(
+          Severity::Error, Check::Denial, name, Some(server),
+          format!("{} for {} has no valid denial of existence: {}", kind, rtype, e)
+        ));
+      }
+    }
+  }
+
+  for (zone, servers) in &zone_params {
+    for (server, params) in servers {
+      findings.push(Finding::new(
+        Severity::Info, Check::Denial, zone, Some(server),
+        format!("NSEC3 parameters: {}", params.describe())
+      ));
+    }
+
+    let distinct: BTreeSet<_> = servers.values().collect();
+    if distinct.len() > 1 {
+      findings.push(Finding::new(
+        Severity::Warning, Check::Denial, zone, None,
+        format!("servers use {} different sets of NSEC3 parameters", distinct.len())
+      ));
+    }
+  }
+
+  findings
+}
+
+/// Check a single negative answer proves what it claims.
+fn check_denial(name: &rr::Name, rtype: rr::RecordType, nxdomain: bool, denial: &Denial)
+    -> Result<(), String> {
+  if !denial.nsecs.is_empty() {
+    if !denial.signed.contains(&rr::RecordType::DNSSEC(DNSSECRecordType::NSEC)) {
+      return Err("NSEC records are not signed".to_string());
+    }
+    return check_nsec(name, rtype, nxdomain, &denial.nsecs);
+  }
+
+  if !denial.nsec3s.is_empty() {
+    if !denial.signed.contains(&rr::RecordType::DNSSEC(DNSSECRecordType::NSEC3)) {
+      return Err("NSEC3 records are not signed".to_string());
+    }
+    return check_nsec3(name, rtype, nxdomain, &denial.nsec3s);
+  }
+
+  Err("no NSEC or NSEC3 records".to_string())
+}
+
+/// Whether the NSEC (or NSEC3) span from owner to next covers the given name.
+///
+/// The last span in a zone wraps around to the start.
+fn covers<T: Ord>(owner: &T, next: &T, name: &T) -> bool {
+  if owner < next {
+    owner < name && name < next
+  } else {
+    owner < name || name < next
+  }
+}
+
+/// Longest common ancestor of two names.
+fn common_ancestor(a: &rr::Name, b: &rr::Name) -> rr::Name {
+  let (a_lower, b_lower) = (a.to_lowercase(), b.to_lowercase());
+  let count = a_lower.iter().rev()
+    .zip(b_lower.iter().rev())
+    .take_while(|(a, b)| a == b)
+    .count();
+  a.trim_to(count)
+}
+
+/// Wildcard name directly under the given name.
+fn wildcard_of(name: &rr::Name) -> Result<rr::Name, String> {
+  let wildcard: &[u8] = b"*";
+  rr::Name::from_labels(std::iter::once(wildcard).chain(name.iter()))
+    .map_err(|e| format!("can't build wildcard of {}: {}", name, e))
+}
+
+/// Whether a type bitmap shows the name has records of the given type.
+fn has_type(types: &[rr::RecordType], rtype: rr::RecordType) -> bool {
+  types.contains(&rtype) || types.contains(&rr::RecordType::CNAME)
+}
+
+/// Check an NSEC based proof (RFC 4035, section 5.4).
+fn check_nsec(name: &rr::Name, rtype: rr::RecordType, nxdomain: bool,
+              nsecs: &[(&rr::Name, &NSEC)]) -> Result<(), String> {
+  let covering = nsecs.iter()
+    .find(|(owner, nsec)| covers(*owner, nsec.next_domain_name(), name));
+
+  if !nxdomain {
+    if let Some((_, nsec)) = nsecs.iter().find(|(owner, _)| *owner == name) {
+      if has_type(nsec.type_bit_maps(), rtype) {
+        return Err(format!("NSEC for {} lists type {}", name, rtype));
+      }
+      return Ok(());
+    }
+
+    // An empty non-terminal has no NSEC of its own, the covering NSEC's
+    // next name is below it instead.
+    if let Some((_, nsec)) = covering {
+      if name.zone_of(nsec.next_domain_name()) {
+        return Ok(());
+      }
+    }
+
+    return Err(format!("no NSEC matches {}", name));
+  }
+
+  let (owner, nsec) = covering.ok_or_else(|| format!("no NSEC covers {}", name))?;
+
+  let closest_encloser = {
+    let a = common_ancestor(name, owner);
+    let b = common_ancestor(name, nsec.next_domain_name());
+    if a.num_labels() > b.num_labels() { a } else { b }
+  };
+  let wildcard = wildcard_of(&closest_encloser)?;
+
+  if !nsecs.iter().any(|(owner, nsec)| covers(*owner, nsec.next_domain_name(), &wildcard)) {
+    return Err(format!("no NSEC proves {} doesn't exist", wildcard));
+  }
+
+  Ok(())
+}
+
+/// Check an NSEC3 based proof (RFC 5155, section 8).
+fn check_nsec3(name: &rr::Name, rtype: rr::RecordType, nxdomain: bool,
+               nsec3s: &[(&rr::Name, &NSEC3)]) -> Result<(), String> {
+  // Decode the hashes from the owner names, and hash with the same parameters.
+  let params = Nsec3Params::new(nsec3s[0].1);
+  let algorithm = Nsec3HashAlgorithm::from_u8(params.algorithm)
+    .map_err(|e| format!("unsupported NSEC3 hash algorithm: {}", e))?;
+
+  let mut spans = Vec::new();
+  for (owner, nsec3) in nsec3s {
+    let label = owner.iter().next().unwrap_or_default().to_ascii_uppercase();
+    let hash = BASE32HEX_NOPAD.decode(&label)
+      .map_err(|e| format!("NSEC3 owner {} is not a hash: {}", owner, e))?;
+    spans.push((hash, nsec3.next_hashed_owner_name().to_vec(), *nsec3));
+  }
+
+  let hash = |name: &rr::Name| -> Result<Vec<u8>, String> {
+    algorithm.hash(&params.salt, name, params.iterations)
+      .map(|digest| digest.as_ref().to_vec())
+      .map_err(|e| format!("can't hash {}: {}", name, e))
+  };
+  let matching = |h: &[u8]| spans.iter().find(|(owner, _, _)| owner.as_slice() == h);
+  let covering = |h: &[u8]| spans.iter().find(|(owner, next, _)| covers(&owner.as_slice(), &next.as_slice(), &h));
+
+  if !nxdomain {
+    if let Some((_, _, nsec3)) = matching(&hash(name)?) {
+      if has_type(nsec3.type_bit_maps(), rtype) {
+        return Err(format!("NSEC3 for {} lists type {}", name, rtype));
+      }
+      return Ok(());
+    }
+
+    // Without a matching NSEC3, only an opt-out span covering a DS query is
+    // acceptable.
+    if rtype != rr::RecordType::DNSSEC(DNSSECRecordType::DS) {
+      return Err(format!("no NSEC3 matches {}", name));
+    }
+  }
+
+  // Closest encloser proof, find the closest ancestor with an NSEC3.
+  let zone = nsec3s[0].0.base_name();
+  let mut closest_encloser = None;
+  let mut ancestor = name.base_name();
+  while zone.zone_of(&ancestor) {
+    if matching(&hash(&ancestor)?).is_some() {
+      closest_encloser = Some(ancestor);
+      break;
+    }
+    if ancestor.is_root() {
+      break;
+    }
+    ancestor = ancestor.base_name();
+  }
+  let closest_encloser = closest_encloser
+    .ok_or_else(|| format!("no NSEC3 matches a closest encloser of {}", name))?;
+
+  let next_closer = name.trim_to(closest_encloser.num_labels() as usize + 1);
+  let (_, _, next_closer_nsec3) = covering(&hash(&next_closer)?)
+    .ok_or_else(|| format!("no NSEC3 covers next closer name {}", next_closer))?;
+
+  if !nxdomain {
+    if !next_closer_nsec3.opt_out() {
+      return Err(format!("no NSEC3 matches {}, and next closer name {} isn't opt-out",
+                         name, next_closer));
+    }
+    return Ok(());
+  }
+
+  let wildcard = wildcard_of(&closest_encloser)?;
+  if covering(&hash(&wildcard)?).is_none() {
+    return Err(format!("no NSEC3 proves {} doesn't exist", wildcard));
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use std::net::IpAddr;
+  use std::str::FromStr;
+
+  use trust_dns_client::proto::rr::dnssec::Algorithm;
+  use trust_dns_client::proto::rr::dnssec::rdata::SIG;
+
+  use super::*;
+
+  fn name(name: &str) -> rr::Name {
+    rr::Name::from_str(name).unwrap()
+  }
+
+  fn nsec_type() -> rr::RecordType {
+    rr::RecordType::DNSSEC(DNSSECRecordType::NSEC)
+  }
+
+  fn ds_type() -> rr::RecordType {
+    rr::RecordType::DNSSEC(DNSSECRecordType::DS)
+  }
+
+  /// NSEC chain of example.com: the apex, a, c and x.y, where y is an
+  /// empty non-terminal.
+  fn nsec_chain() -> Vec<(rr::Name, NSEC)> {
+    let owners = ["example.com.", "a.example.com.", "c.example.com.", "x.y.example.com."];
+    owners.iter().enumerate()
+      .map(|(i, owner)| {
+        let next = owners[(i + 1) % owners.len()];
+        (name(owner), NSEC::new(name(next), vec![rr::RecordType::A, nsec_type()]))
+      })
+      .collect()
+  }
+
+  fn nsec(qname: &str, rtype: rr::RecordType, nxdomain: bool, chain: &[(rr::Name, NSEC)])
+      -> Result<(), String> {
+    let nsecs: Vec<_> = chain.iter().map(|(owner, nsec)| (owner, nsec)).collect();
+    check_nsec(&name(qname), rtype, nxdomain, &nsecs)
+  }
+
+  fn hash(qname: &rr::Name) -> Vec<u8> {
+    Nsec3HashAlgorithm::SHA1.hash(&[], qname, 0).unwrap().as_ref().to_vec()
+  }
+
+  /// NSEC3 chain of example.com (no salt, no extra iterations) with the
+  /// given names in it.
+  fn nsec3_chain(names: &[&str], opt_out: bool) -> Vec<(rr::Name, NSEC3)> {
+    let mut hashes: Vec<_> = names.iter().map(|n| hash(&name(n))).collect();
+    hashes.sort();
+    hashes.iter().enumerate()
+      .map(|(i, h)| {
+        let owner = format!("{}.example.com.", BASE32HEX_NOPAD.encode(h).to_lowercase());
+        let next = hashes[(i + 1) % hashes.len()].clone();
+        (name(&owner), NSEC3::new(Nsec3HashAlgorithm::SHA1, opt_out, 0, Vec::new(), next,
+                                  vec![rr::RecordType::A]))
+      })
+      .collect()
+  }
+
+  fn nsec3(qname: &str, rtype: rr::RecordType, nxdomain: bool, chain: &[(rr::Name, NSEC3)])
+      -> Result<(), String> {
+    let nsec3s: Vec<_> = chain.iter().map(|(owner, nsec3)| (owner, nsec3)).collect();
+    check_nsec3(&name(qname), rtype, nxdomain, &nsec3s)
+  }
+
+  #[test]
+  fn spans() {
+    assert!(covers(&1, &5, &3));
+    assert!(!covers(&1, &5, &1));
+    assert!(!covers(&1, &5, &5));
+    assert!(!covers(&1, &5, &7));
+    // The last span wraps around to the start of the zone.
+    assert!(covers(&5, &1, &7));
+    assert!(covers(&5, &1, &0));
+    assert!(!covers(&5, &1, &3));
+    // Names sort in canonical order.
+    assert!(covers(&name("example.com."), &name("a.example.com."), &name("*.example.com.")));
+    assert!(covers(&name("a.example.com."), &name("c.example.com."), &name("x.b.example.com.")));
+    assert!(!covers(&name("a.example.com."), &name("c.example.com."), &name("d.example.com.")));
+  }
+
+  #[test]
+  fn ancestors() {
+    assert_eq!(common_ancestor(&name("a.b.example.com."), &name("c.b.example.com.")),
+               name("b.example.com."));
+    assert_eq!(common_ancestor(&name("A.Example.com."), &name("b.example.COM.")),
+               name("example.com."));
+    assert_eq!(common_ancestor(&name("example.com."), &name("example.net.")), rr::Name::root());
+  }
+
+  #[test]
+  fn nsec_nxdomain() {
+    let chain = nsec_chain();
+    // a -> c covers the name, the apex -> a covers the wildcard.
+    assert_eq!(nsec("b.example.com.", rr::RecordType::A, true, &chain), Ok(()));
+
+    let no_wildcard: Vec<_> = chain[1..].to_vec();
+    assert_eq!(nsec("b.example.com.", rr::RecordType::A, true, &no_wildcard),
+               Err("no NSEC proves *.example.com. doesn't exist".to_string()));
+
+    let no_cover: Vec<_> = chain.iter().filter(|(owner, _)| *owner != name("a.example.com."))
+      .cloned().collect();
+    assert_eq!(nsec("b.example.com.", rr::RecordType::A, true, &no_cover),
+               Err("no NSEC covers b.example.com.".to_string()));
+  }
+
+  #[test]
+  fn nsec_nodata() {
+    let chain = nsec_chain();
+    assert_eq!(nsec("a.example.com.", rr::RecordType::AAAA, false, &chain), Ok(()));
+    assert_eq!(nsec("a.example.com.", rr::RecordType::A, false, &chain),
+               Err("NSEC for a.example.com. lists type A".to_string()));
+    // c -> x.y covers y, with the next name below it.
+    assert_eq!(nsec("y.example.com.", rr::RecordType::A, false, &chain), Ok(()));
+    assert_eq!(nsec("b.example.com.", rr::RecordType::A, false, &chain),
+               Err("no NSEC matches b.example.com.".to_string()));
+  }
+
+  #[test]
+  fn nsec3_nxdomain() {
+    // In hash order: c, e, a, the apex. c -> e covers b, the next closer
+    // name, and e -> a covers the wildcard.
+    let chain = nsec3_chain(&["example.com.", "a.example.com.", "c.example.com.",
+                              "e.example.com."], false);
+    assert_eq!(nsec3("b.example.com.", rr::RecordType::A, true, &chain), Ok(()));
+
+    let without = |owner: &str| -> Vec<(rr::Name, NSEC3)> {
+      let label = BASE32HEX_NOPAD.encode(&hash(&name(owner))).to_lowercase();
+      let owner = name(&format!("{}.example.com.", label));
+      chain.iter().filter(|(o, _)| *o != owner).cloned().collect()
+    };
+    assert_eq!(nsec3("b.example.com.", rr::RecordType::A, true, &without("c.example.com.")),
+               Err("no NSEC3 covers next closer name b.example.com.".to_string()));
+    assert_eq!(nsec3("b.example.com.", rr::RecordType::A, true, &without("e.example.com.")),
+               Err("no NSEC3 proves *.example.com. doesn't exist".to_string()));
+    assert_eq!(nsec3("b.example.com.", rr::RecordType::A, true, &without("example.com.")),
+               Err("no NSEC3 matches a closest encloser of b.example.com.".to_string()));
+  }
+
+  #[test]
+  fn nsec3_nodata() {
+    let chain = nsec3_chain(&["example.com.", "a.example.com."], false);
+    assert_eq!(nsec3("a.example.com.", rr::RecordType::AAAA, false, &chain), Ok(()));
+    assert_eq!(nsec3("a.example.com.", rr::RecordType::A, false, &chain),
+               Err("NSEC3 for a.example.com. lists type A".to_string()));
+    assert_eq!(nsec3("b.example.com.", rr::RecordType::A, false, &chain),
+               Err("no NSEC3 matches b.example.com.".to_string()));
+  }
+
+  #[test]
+  fn nsec3_opt_out() {
+    // insecure.example.com is an unsigned delegation, left out of the chain.
+    let chain = nsec3_chain(&["example.com.", "a.example.com."], true);
+    assert_eq!(nsec3("insecure.example.com.", ds_type(), false, &chain), Ok(()));
+
+    let chain = nsec3_chain(&["example.com.", "a.example.com."], false);
+    assert_eq!(nsec3("insecure.example.com.", ds_type(), false, &chain),
+               Err("no NSEC3 matches insecure.example.com., and next closer name \
+                    insecure.example.com. isn't opt-out".to_string()));
+  }
+
+  #[test]
+  fn unsigned_proof_flagged() {
+    let signed: IpAddr = "192.0.2.1".parse().unwrap();
+    let unsigned: IpAddr = "192.0.2.2".parse().unwrap();
+    let records = |sign: bool| {
+      let mut records: Vec<_> = nsec_chain().into_iter()
+        .map(|(owner, nsec)| {
+          rr::Record::from_rdata(owner, 300, RData::DNSSEC(DNSSECRData::NSEC(nsec)))
+        })
+        .collect();
+      if sign {
+        let sig = SIG::new(nsec_type(), Algorithm::ECDSAP256SHA256, 2, 300, 0, 0, 12345,
+                           name("example.com."), vec![0; 64]);
+        records.push(rr::Record::from_rdata(name("a.example.com."), 300,
+                                            RData::DNSSEC(DNSSECRData::SIG(sig))));
+      }
+      records
+    };
+
+    let mut db = RecordDB::new();
+    let qname = name("b.example.com.");
+    for (server, sign) in &[(signed, true), (unsigned, false)] {
+      db.add_negative_answer(&qname, rr::RecordType::A, *server, NegativeAnswer {
+        nxdomain: true,
+        authority: records(*sign),
+      });
+    }
+
+    let findings = check_denials(&db);
+    assert_eq!(findings.len(), 1);
+    assert_eq!(findings[0].severity, Severity::Error);
+    assert_eq!(findings[0].server, Some(RServer::from(unsigned)));
+    assert_eq!(findings[0].message,
+               "NXDOMAIN for A has no valid denial of existence: NSEC records are not signed");
+  }
+}
@@ -0,0 +1,132 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use trust_dns_client::rr;
+
+use crate::db::{Health, RecordDB, RServer};
+
+/// Quote a string for use as a DOT identifier or label.
+fn quote(text: &str) -> String {
+  format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn zone_node(zone: &rr::Name) -> String {
+  quote(&format!("zone {}", zone))
+}
+
+fn ns_node(ns: &rr::Name) -> String {
+  quote(&format!("ns {}", ns))
+}
+
+fn server_node(server: &RServer) -> String {
+  quote(&format!("server {:?}", server))
+}
+
+fn color(health: Health) -> &'static str {
+  match health {
+    Health::Ok => "darkgreen",
+    Health::Unknown => "gray",
+    Health::Lame => "orange",
+    Health::TimeOut => "red",
+  }
+}
+
+/// Dump the delegation graph to stdout, in Graphviz DOT format.
+///
+/// Zones, NS names and server addresses are nodes. Referrals point from
+/// the zone referring to the zone referred to, then on to its NS names
+/// (dashed where only the zone itself lists the name), and NS names point
+/// to their addresses. Addresses are colored by the worst health of the
+/// server for any zone it serves: green answers, orange is lame, red
+/// timed out, and gray wasn't asked. Addresses show the server's slowest
+/// answer.
+pub fn dump(record_db: &RecordDB) {
+  let mut zones = BTreeSet::new();
+  let mut referrals = BTreeSet::new();
+  let mut zone_ns: BTreeMap<rr::Name, BTreeSet<(rr::Name, bool)>> = BTreeMap::new();
+
+  for ((_, zone), delegations) in record_db.get_delegations() {
+    for (auth_zone, _) in delegations {
+      if zone != auth_zone {
+        referrals.insert((zone.clone(), auth_zone.clone()));
+      }
+      zones.insert(zone.clone());
+      zones.insert(auth_zone.clone());
+    }
+  }
+  zones.extend(record_db.get_answer_zones());
+
+  for zone in &zones {
+    // The root has no parent, its NS names come from the hints.
+    let parent_ns = if zone.is_root() {
+      record_db.get_record_set(zone, rr::RecordType::NS).iter()
+        .filter_map(|rdata| rdata.as_ns().cloned())
+        .collect()
+    } else {
+      record_db.get_parent_ns(zone)
+    };
+    let child_ns = record_db.get_child_ns(zone);
+    let entry = zone_ns.entry(zone.clone()).or_default();
+    for ns in &parent_ns {
+      entry.insert((ns.clone(), true));
+    }
+    for ns in child_ns.difference(&parent_ns) {
+      entry.insert((ns.clone(), false));
+    }
+  }
+
+  // Addresses of each NS name, and the worst health of each server.
+  let mut addresses: BTreeMap<rr::Name, BTreeSet<RServer>> = BTreeMap::new();
+  let mut health: BTreeMap<RServer, Health> = BTreeMap::new();
+  for (zone, names) in &zone_ns {
+    for (ns, _) in names {
+      let servers = addresses.entry(ns.clone()).or_default();
+      for rtype in &[rr::RecordType::A, rr::RecordType::AAAA] {
+        for rdata in record_db.get_record_set(ns, *rtype) {
+          if let Some(ip) = rdata.to_ip_addr() {
+            let server = RServer::from(ip);
+            let server_health = record_db.get_health(zone, &server);
+            let worst = health.entry(server.clone()).or_insert(server_health);
+            *worst = (*worst).max(server_health);
+            servers.insert(server);
+          }
+        }
+      }
+    }
+  }
+
+  println!("digraph delegations {{");
+  println!("  rankdir=LR;");
+
+  for zone in &zones {
+    println!("  {} [label={}, shape=box];", zone_node(zone), quote(&zone.to_string()));
+  }
+  for ns in addresses.keys() {
+    println!("  {} [label={}, shape=ellipse];", ns_node(ns), quote(&ns.to_string()));
+  }
+  for (server, server_health) in &health {
+    let mut label = server.ip().map(|ip| ip.to_string()).unwrap_or_default();
+    if let Some(rtt) = record_db.get_max_rtt(server) {
+      label.push_str(&format!(" ({} ms)", rtt.as_millis()));
+    }
+    println!("  {} [label={}, shape=note, color={}];",
+             server_node(server), quote(&label), color(*server_health));
+  }
+
+  for (zone, auth_zone) in &referrals {
+    println!("  {} -> {} [label=\"referral\"];", zone_node(zone), zone_node(auth_zone));
+  }
+  for (zone, names) in &zone_ns {
+    for (ns, from_parent) in names {
+      let style = if *from_parent { "solid" } else { "dashed" };
+      println!("  {} -> {} [style={}];", zone_node(zone), ns_node(ns), style);
+    }
+  }
+  for (ns, servers) in &addresses {
+    for server in servers {
+      let server_health = health.get(server).cloned().unwrap_or(Health::Unknown);
+      println!("  {} -> {} [color={}];", ns_node(ns), server_node(server), color(server_health));
+    }
+  }
+
+  println!("}}");
+}
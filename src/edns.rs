@@ -0,0 +1,424 @@
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+
+use log::debug;
+use trust_dns_client::op::{Message, ResponseCode};
+use trust_dns_client::proto::rr::dnssec::rdata::DNSSECRecordType;
+use trust_dns_client::rr;
+use trust_dns_client::rr::rdata::opt::EdnsOption;
+
+use crate::db::{EdnsCompliance, EdnsResult, EdnsTest, RecordDB};
+use crate::dns::{self, Hooks, QueryError, QueryOptions};
+use crate::findings::{Check, Finding, Severity};
+
+/// Option code used for the unknown option test, as used by ISC.
+const UNKNOWN_OPTION: u16 = 100;
+
+/// Flag used for the unknown flag test, as used by ISC.
+const UNKNOWN_FLAG: u16 = 0x0080;
+
+/// The DO flag, the only EDNS flag defined.
+const DO_FLAG: u16 = 0x8000;
+
+/// Largest response allowed for the small buffer test.
+const SMALL_BUFFER: u16 = 512;
+
+/// Run a single EDNS compliance test against a server.
+fn run_test(ip: IpAddr, zone: &rr::Name, test: EdnsTest, hooks: &Hooks) -> EdnsResult {
+  let mut options = QueryOptions::default();
+  let mut rtype = rr::RecordType::SOA;
+
+  match test {
+    EdnsTest::Plain => options.edns = false,
+    EdnsTest::Edns0 => (),
+    EdnsTest::UnknownVersion => options.edns_version = 1,
+    EdnsTest::UnknownOption =>
+      options.edns_options = vec![EdnsOption::Unknown(UNKNOWN_OPTION, Vec::new())],
+    EdnsTest::UnknownFlag => options.edns_flags = UNKNOWN_FLAG,
+    EdnsTest::DnssecOk => options.dnssec_ok = true,
+    EdnsTest::SmallBuffer => {
+      options.max_payload = SMALL_BUFFER;
+      options.dnssec_ok = true;
+      rtype = rr::RecordType::DNSSEC(DNSSECRecordType::DNSKEY);
+    },
+  }
+
+  let (msg, bytes) = match dns::do_udp_query(ip, zone, rtype, &options, hooks) {
+    Ok(r) => r,
+    // Only a response that can't be parsed is the server's fault, network
+    // failures are no answer.
+    Err(QueryError::Proto(e)) => return EdnsResult::Deviates(e.to_string()),
+    Err(e) => {
+      debug!("EDNS test {:?} of {} failed: {}", test, ip, e);
+      return EdnsResult::TimeOut;
+    },
+  };
+  check_response(test, &msg, &bytes)
+}
+
+/// Check a server's response to an EDNS compliance test.
+fn check_response(test: EdnsTest, msg: &Message, bytes: &[u8]) -> EdnsResult {
+  let opt = match dns::raw_opt(bytes) {
+    Ok(opt) => opt,
+    Err(e) => return EdnsResult::Deviates(e.to_string()),
+  };
+
+  let mut problems = Vec::new();
+
+  let expected_rcode = match test {
+    EdnsTest::UnknownVersion => ResponseCode::BADVERS,
+    _ => ResponseCode::NoError,
+  };
+  // Compared by value, as trust-dns decodes 16 as BADSIG rather than BADVERS.
+  if u16::from(msg.response_code()) != u16::from(expected_rcode) {
+    problems.push(format!("expected {}, got {}", expected_rcode, msg.response_code()));
+  }
+
+  match (test, &opt) {
+    (EdnsTest::Plain, Some(_)) =>
+      problems.push("OPT record in answer to a query without EDNS".to_string()),
+    (EdnsTest::Plain, None) => (),
+    (_, None) => problems.push("no OPT record".to_string()),
+    (_, Some(opt)) => {
+      let version = (opt.ttl >> 16) & 0xff;
+      let flags = (opt.ttl & 0xffff) as u16;
+      if version != 0 {
+        problems.push(format!("answered with EDNS version {}", version));
+      }
+      if flags & !DO_FLAG != 0 {
+        problems.push(format!("unknown EDNS flags {:#06x} in answer", flags & !DO_FLAG));
+      }
+    },
+  }
+
+  match test {
+    EdnsTest::Plain => {
+      if !msg.authoritative() {
+        problems.push("answer not authoritative".to_string());
+      }
+    },
+    EdnsTest::UnknownVersion => {
+      if !msg.answers().is_empty() {
+        problems.push("answered a query with an unknown EDNS version".to_string());
+      }
+    },
+    EdnsTest::UnknownOption => {
+      if opt.is_some_and(|opt| opt.options.contains(&UNKNOWN_OPTION)) {
+        problems.push("unknown option echoed".to_string());
+      }
+    },
+    EdnsTest::DnssecOk => {
+      if !msg.edns().is_some_and(|edns| edns.dnssec_ok()) {
+        problems.push("DO flag not echoed".to_string());
+      }
+    },
+    EdnsTest::SmallBuffer => {
+      if !msg.truncated() && bytes.len() > SMALL_BUFFER as usize {
+        problems.push(format!("{} byte answer without TC", bytes.len()));
+      }
+    },
+    EdnsTest::Edns0 | EdnsTest::UnknownFlag => (),
+  }
+
+  if problems.is_empty() {
+    EdnsResult::Ok
+  } else {
+    EdnsResult::Deviates(problems.join(", "))
+  }
+}
+
+/// Run the EDNS compliance tests against every server of every zone holding
+/// an answer target.
+///
+/// Root and TLD servers referred through aren't tested. Each server is
+/// tested once, with the first zone found for it.
+pub fn probe_edns(record_db: &mut RecordDB) {
+  let hooks = record_db.get_hooks();
+  for zone in record_db.get_target_zones() {
+    for server in record_db.get_zone_servers(&zone) {
+      let ip = match server.ip() {
+        Some(ip) => ip,
+        None => continue,
+      };
+      if record_db.server_info_mut(&server).edns.is_some() {
+        continue;
+      }
+      debug!("Probe EDNS compliance {}, {}", zone, ip);
+
+      let results = EdnsTest::all().into_iter()
+        .map(|test| (test, run_test(ip, &zone, test, &hooks)))
+        .collect();
+
+      record_db.server_info_mut(&server).edns = Some(EdnsCompliance {
+        zone: zone.clone(),
+        results,
+      });
+    }
+  }
+}
+
+/// Report EDNS compliance deviations per server, with a summary per zone.
+pub fn check_edns(record_db: &RecordDB) -> Vec<Finding> {
+  let mut findings = Vec::new();
+
+  for (server, info) in record_db.get_server_info() {
+    let edns = match &info.edns {
+      Some(edns) => edns,
+      None => continue,
+    };
+
+    // A server that doesn't answer at all isn't an EDNS problem.
+    if edns.results.values().all(|result| *result == EdnsResult::TimeOut) {
+      continue;
+    }
+
+    for (test, result) in &edns.results {
+      let problem = match result {
+        EdnsResult::Ok => continue,
+        EdnsResult::TimeOut => "no answer".to_string(),
+        EdnsResult::Deviates(problem) => problem.clone(),
+      };
+      findings.push(Finding::new(
+        Severity::Warning, Check::Edns, &edns.zone, Some(server),
+        format!("EDNS test {} failed: {}", test, problem)
+      ));
+    }
+  }
+
+  for zone in record_db.get_target_zones() {
+    let mut tested = 0;
+    let mut failures: BTreeMap<EdnsTest, usize> = BTreeMap::new();
+
+    for server in record_db.get_zone_servers(&zone) {
+      let edns = match record_db.get_server_info().get(&server).and_then(|i| i.edns.as_ref()) {
+        Some(edns) => edns,
+        None => continue,
+      };
+      tested += 1;
+      for (test, result) in &edns.results {
+        if *result != EdnsResult::Ok {
+          *failures.entry(*test).or_insert(0) += 1;
+        }
+      }
+    }
+
+    if tested == 0 {
+      continue;
+    }
+
+    let summary = if failures.is_empty() {
+      "all passed".to_string()
+    } else {
+      failures.iter()
+        .map(|(test, count)| format!("{} failed on {}", test, count))
+        .collect::<Vec<_>>()
+        .join(", ")
+    };
+    findings.push(Finding::new(
+      Severity::Info, Check::Edns, &zone, None,
+      format!("EDNS compliance of {} servers: {}", tested, summary)
+    ));
+  }
+
+  findings
+}
+
+#[cfg(test)]
+mod tests {
+  use std::env;
+  use std::fs;
+  use std::str::FromStr;
+  use std::sync::Arc;
+
+  use data_encoding::HEXLOWER;
+  use trust_dns_client::op::{Edns, MessageType};
+
+  use super::*;
+  use crate::db::{RServer, Transport};
+  use crate::fixture::{self, Exchange, Fixture, Session};
+  use crate::snapshot;
+
+  fn name(name: &str) -> rr::Name {
+    rr::Name::from_str(name).unwrap()
+  }
+
+  /// Authoritative SOA answer, with an OPT record of the given version and
+  /// DO flag when given.
+  fn response(opt: Option<(u8, bool)>) -> Message {
+    let mut msg = Message::new();
+    msg.set_message_type(MessageType::Response);
+    msg.set_authoritative(true);
+    msg.add_answer(rr::Record::from_rdata(name("example.com."), 3600,
+      rr::RData::SOA(rr::rdata::SOA::new(name("ns1.example.com."),
+        name("hostmaster.example.com."), 1, 3600, 600, 86400, 300))));
+    if let Some((version, dnssec_ok)) = opt {
+      let mut edns = Edns::new();
+      edns.set_version(version);
+      edns.set_dnssec_ok(dnssec_ok);
+      msg.set_edns(edns);
+    }
+    msg
+  }
+
+  /// Check a response as received, the extended rcode only comes
+  /// together once decoded.
+  fn check(test: EdnsTest, msg: &Message) -> EdnsResult {
+    let bytes = msg.to_vec().unwrap();
+    check_response(test, &Message::from_vec(&bytes).unwrap(), &bytes)
+  }
+
+  fn deviates(problem: &str) -> EdnsResult {
+    EdnsResult::Deviates(problem.to_string())
+  }
+
+  #[test]
+  fn compliant_answers_pass() {
+    assert_eq!(check(EdnsTest::Plain, &response(None)), EdnsResult::Ok);
+    assert_eq!(check(EdnsTest::Edns0, &response(Some((0, false)))), EdnsResult::Ok);
+    assert_eq!(check(EdnsTest::DnssecOk, &response(Some((0, true)))), EdnsResult::Ok);
+
+    let mut badvers = Message::new();
+    badvers.set_message_type(MessageType::Response);
+    badvers.set_response_code(ResponseCode::BADVERS);
+    let mut edns = Edns::new();
+    edns.set_rcode_high(ResponseCode::BADVERS.high() as u8);
+    badvers.set_edns(edns);
+    assert_eq!(check(EdnsTest::UnknownVersion, &badvers), EdnsResult::Ok);
+  }
+
+  #[test]
+  fn opt_records_checked() {
+    assert_eq!(check(EdnsTest::Plain, &response(Some((0, false)))),
+               deviates("OPT record in answer to a query without EDNS"));
+    assert_eq!(check(EdnsTest::Edns0, &response(None)), deviates("no OPT record"));
+    assert_eq!(check(EdnsTest::Edns0, &response(Some((1, false)))),
+               deviates("answered with EDNS version 1"));
+    assert_eq!(check(EdnsTest::DnssecOk, &response(Some((0, false)))),
+               deviates("DO flag not echoed"));
+  }
+
+  #[test]
+  fn unknown_version_answered() {
+    assert_eq!(check(EdnsTest::UnknownVersion, &response(Some((0, false)))),
+               deviates("expected Bad option verions, got No Error, \
+                         answered a query with an unknown EDNS version"));
+  }
+
+  #[test]
+  fn unknown_option_echoed() {
+    let mut msg = response(Some((0, false)));
+    msg.edns_mut().set_option(EdnsOption::Unknown(UNKNOWN_OPTION, Vec::new()));
+    assert_eq!(check(EdnsTest::UnknownOption, &msg), deviates("unknown option echoed"));
+  }
+
+  #[test]
+  fn unknown_flag_echoed() {
+    let msg = response(Some((0, false)));
+    let mut bytes = msg.to_vec().unwrap();
+    // The OPT record, without options, ends with the flags and RDLENGTH.
+    let flags = bytes.len() - 4;
+    bytes[flags..flags + 2].copy_from_slice(&UNKNOWN_FLAG.to_be_bytes());
+    assert_eq!(check_response(EdnsTest::UnknownFlag, &msg, &bytes),
+               deviates("unknown EDNS flags 0x0080 in answer"));
+  }
+
+  #[test]
+  fn large_answers_truncated() {
+    let mut msg = response(Some((0, true)));
+    for n in 0..40 {
+      msg.add_answer(rr::Record::from_rdata(name("example.com."), 3600,
+        rr::RData::A(format!("192.0.2.{}", n).parse().unwrap())));
+    }
+    let size = msg.to_vec().unwrap().len();
+    assert!(size > SMALL_BUFFER as usize);
+    assert_eq!(check(EdnsTest::SmallBuffer, &msg),
+               deviates(&format!("{} byte answer without TC", size)));
+
+    // trust-dns sets TC itself when encoding.
+    let mut bytes = msg.to_vec().unwrap();
+    bytes[2] |= 0x02;
+    assert_eq!(check_response(EdnsTest::SmallBuffer, &Message::from_vec(&bytes).unwrap(), &bytes),
+               EdnsResult::Ok);
+  }
+
+  /// Hooks answering queries from the given exchanges, anything else times
+  /// out.
+  fn replay(exchanges: Vec<Exchange>) -> Hooks {
+    let fixture = Fixture { version: fixture::VERSION, exchanges };
+    let path = env::temp_dir()
+      .join(format!("dns-delegation-check-edns-{}.json", std::process::id()));
+    fs::write(&path, serde_json::to_string(&fixture).unwrap()).unwrap();
+    let session = Session::replay(&path);
+    fs::remove_file(&path).unwrap();
+    Hooks { fixture: Some(Arc::new(session.unwrap())), ..Hooks::default() }
+  }
+
+  #[test]
+  fn timeouts_and_garbage() {
+    let ip: IpAddr = "192.0.2.1".parse().unwrap();
+    let zone = name("example.com.");
+    let hooks = replay(vec![Exchange {
+      server: ip,
+      name: snapshot::encode_name(&zone),
+      rtype: snapshot::encode_type(rr::RecordType::DNSSEC(DNSSECRecordType::DNSKEY)),
+      transport: Transport::Udp,
+      responses: vec![fixture::Response::Message(HEXLOWER.encode(&[0, 1, 2]))],
+    }]);
+
+    assert_eq!(run_test(ip, &zone, EdnsTest::Edns0, &hooks), EdnsResult::TimeOut);
+    assert!(matches!(run_test(ip, &zone, EdnsTest::SmallBuffer, &hooks), EdnsResult::Deviates(_)));
+  }
+
+  /// Database with www.example.com referred from the root to example.com,
+  /// each with a server.
+  fn record_db() -> RecordDB {
+    let mut db = RecordDB::new();
+    let www = name("www.example.com.");
+    db.add_answer_target(&www, rr::RecordType::A);
+    db.add_target(&www, rr::RecordType::A, &name("example.com."));
+    for (zone, ns, ip) in &[(".", "a.root-servers.net.", "198.41.0.4"),
+                            ("example.com.", "ns1.example.com.", "192.0.2.1")] {
+      let ip: IpAddr = ip.parse().unwrap();
+      db.add_record(&rr::Record::from_rdata(name(zone), 3600, rr::RData::NS(name(ns))), ip);
+      db.add_record(&rr::Record::from_rdata(name(ns), 3600, rr::RData::A(
+        match ip { IpAddr::V4(ip) => ip, IpAddr::V6(_) => unreachable!() })), ip);
+    }
+    db
+  }
+
+  #[test]
+  fn only_target_zone_servers_probed() {
+    let mut db = record_db();
+    db.set_hooks(replay(Vec::new()));
+    probe_edns(&mut db);
+
+    let root = RServer::from("198.41.0.4".parse::<IpAddr>().unwrap());
+    let server = RServer::from("192.0.2.1".parse::<IpAddr>().unwrap());
+    assert!(!db.get_server_info().contains_key(&root));
+    let edns = db.get_server_info()[&server].edns.as_ref().unwrap();
+    assert_eq!(edns.zone, name("example.com."));
+    assert!(edns.results.values().all(|result| *result == EdnsResult::TimeOut));
+  }
+
+  #[test]
+  fn failures_reported() {
+    let mut db = record_db();
+    let server = RServer::from("192.0.2.1".parse::<IpAddr>().unwrap());
+    let mut results: BTreeMap<_, _> = EdnsTest::all().into_iter()
+      .map(|test| (test, EdnsResult::Ok))
+      .collect();
+    results.insert(EdnsTest::UnknownFlag, EdnsResult::TimeOut);
+    results.insert(EdnsTest::DnssecOk, deviates("DO flag not echoed"));
+    db.server_info_mut(&server).edns = Some(EdnsCompliance { zone: name("example.com."), results });
+
+    let findings: Vec<_> = check_edns(&db).into_iter()
+      .map(|finding| (finding.severity, finding.message))
+      .collect();
+    assert_eq!(findings, vec![
+      (Severity::Warning, "EDNS test ednsflags failed: no answer".to_string()),
+      (Severity::Warning, "EDNS test do failed: DO flag not echoed".to_string()),
+      (Severity::Info,
+       "EDNS compliance of 1 servers: ednsflags failed on 1, do failed on 1".to_string()),
+    ]);
+  }
+}
@@ -0,0 +1,362 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use trust_dns_client::rr;
+
+use crate::chain;
+use crate::delegation;
+use crate::db::{LoggedQuery, RecordDB, Recursion, RServer, ZoneTransfer};
+use crate::dnssec;
+use crate::edns;
+use crate::nameserver;
+use crate::negative;
+use crate::policy::{self, Policy};
+use crate::ttl;
+use crate::zonefile::{self, ZoneFile};
+
+/// How serious a finding is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+  /// Informational, nothing is wrong.
+  Info,
+  /// Misconfiguration that doesn't (yet) break resolution.
+  Warning,
+  /// Resolution is broken, or will break for some resolvers.
+  Error,
+}
+
+impl fmt::Display for Severity {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Severity::Info => write!(f, "INFO"),
+      Severity::Warning => write!(f, "WARNING"),
+      Severity::Error => write!(f, "ERROR"),
+    }
+  }
+}
+
+/// The check that raised a finding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Check {
+  /// Servers that don't answer for the zone delegated to them.
+  Lame,
+  /// NS names at the parent compared to the zone's own.
+  NsConsistency,
+  /// Glue addresses compared to the zone's own.
+  Glue,
+  /// SOA serials of the zone's servers.
+  SoaSerial,
+  /// Servers slow to answer.
+  Latency,
+  /// Authenticated denial of existence (NSEC/NSEC3).
+  Denial,
+  /// CNAME and DNAME chains.
+  Alias,
+  /// NS names that can't be used to reach a server.
+  NsTarget,
+  /// Authoritative servers that recurse for anyone.
+  Recursion,
+  /// Zone transfers allowed to anyone.
+  ZoneTransfer,
+  /// EDNS compliance (RFC 6891).
+  Edns,
+  /// TTL consistency.
+  Ttl,
+  /// SOA values used for negative caching.
+  NegativeCaching,
+  /// Expected state from a policy file.
+  Policy,
+  /// RRsets served compared to a local zone file.
+  ZoneFile,
+}
+
+impl fmt::Display for Check {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Check::Lame => write!(f, "lame"),
+      Check::NsConsistency => write!(f, "ns-consistency"),
+      Check::Glue => write!(f, "glue"),
+      Check::SoaSerial => write!(f, "soa-serial"),
+      Check::Latency => write!(f, "latency"),
+      Check::Denial => write!(f, "denial"),
+      Check::Alias => write!(f, "alias"),
+      Check::NsTarget => write!(f, "ns-target"),
+      Check::Recursion => write!(f, "recursion"),
+      Check::ZoneTransfer => write!(f, "zone-transfer"),
+      Check::Edns => write!(f, "edns"),
+      Check::Ttl => write!(f, "ttl"),
+      Check::NegativeCaching => write!(f, "negative-caching"),
+      Check::Policy => write!(f, "policy"),
+      Check::ZoneFile => write!(f, "zone-file"),
+    }
+  }
+}
+
+impl Check {
+  pub fn all() -> Vec<Check> {
+    vec![
+      Check::Lame,
+      Check::NsConsistency,
+      Check::Glue,
+      Check::SoaSerial,
+      Check::Latency,
+      Check::Denial,
+      Check::Alias,
+      Check::NsTarget,
+      Check::Recursion,
+      Check::ZoneTransfer,
+      Check::Edns,
+      Check::Ttl,
+      Check::NegativeCaching,
+      Check::Policy,
+      Check::ZoneFile,
+    ]
+  }
+
+  /// What the check looks at, and why it matters.
+  pub fn explanation(&self) -> &'static str {
+    match self {
+      Check::Lame =>
+        "Every server a zone is delegated to must answer for it, or resolvers \
+         waste time on it and fail when it's the only one left.",
+      Check::NsConsistency =>
+        "The NS names in the parent zone's referral and in the zone itself \
+         should be the same (RFC 1034, section 4.2.2).",
+      Check::Glue =>
+        "NS names inside the zone can only be reached through glue from the \
+         parent, which must match the addresses in the zone.",
+      Check::SoaSerial =>
+        "Servers giving different SOA serials serve different versions of the \
+         zone, usually because zone transfers are failing.",
+      Check::Latency =>
+        "Slow servers, or servers that only answer when asked again, delay \
+         resolution and make resolvers prefer other servers.",
+      Check::Denial =>
+        "Signed zones must prove names and types don't exist with signed NSEC or \
+         NSEC3 records, or validating resolvers will fail to resolve them.",
+      Check::Alias =>
+        "CNAME and DNAME chains must end at a name with records of the type \
+         asked for; loops and long chains make resolvers give up.",
+      Check::NsTarget =>
+        "NS names must have addresses, and must not be aliases, for resolvers \
+         to reach the zone's servers.",
+      Check::Recursion =>
+        "Authoritative servers that recurse for anyone can be used for cache \
+         poisoning and amplification attacks.",
+      Check::ZoneTransfer =>
+        "Allowing anyone to transfer a zone discloses every name in it.",
+      Check::Edns =>
+        "Servers must handle EDNS (RFC 6891) correctly, or resolvers fall back \
+         to slower and less capable queries, or fail.",
+      Check::Ttl =>
+        "Servers and both sides of a delegation should agree on TTLs, so \
+         resolvers cache records consistently.",
+      Check::NegativeCaching =>
+        "The SOA in negative answers sets how long resolvers remember that a \
+         name doesn't exist (RFC 2308).",
+      Check::Policy =>
+        "The delegation doesn't match the expected state written down for it.",
+      Check::ZoneFile =>
+        "What the servers serve doesn't match the local zone file.",
+    }
+  }
+}
+
+/// Something noteworthy found when checking the record database.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Finding {
+  pub severity: Severity,
+  pub check: Check,
+  /// Name (or zone) the finding is about.
+  pub name: rr::Name,
+  /// Server the finding is about, if it's specific to one server.
+  pub server: Option<RServer>,
+  pub message: String,
+}
+
+impl Finding {
+  pub fn new(severity: Severity, check: Check, name: &rr::Name,
+             server: Option<&RServer>, message: String) -> Finding {
+    Finding {
+      severity,
+      check,
+      name: name.clone(),
+      server: server.cloned(),
+      message,
+    }
+  }
+}
+
+impl fmt::Display for Finding {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{} [{}] {}", self.severity, self.check, self.name)?;
+    if let Some(server) = &self.server {
+      write!(f, " @{:?}", server)?;
+    }
+    write!(f, ": {}", self.message)
+  }
+}
+
+/// Settings for checks.
+#[derive(Debug, Clone)]
+pub struct CheckConfig {
+  /// Allowed range of SOA minimum (negative caching TTL) values.
+  pub soa_minimum: (u32, u32),
+  /// Allowed range of SOA record TTLs.
+  pub soa_ttl: (u32, u32),
+  /// Expected state of delegations to assert.
+  pub policy: Option<Policy>,
+  /// Local zone files to compare servers against.
+  pub zone_files: Vec<ZoneFile>,
+  /// Answers at least this slow are flagged.
+  pub slow_query: Duration,
+}
+
+impl Default for CheckConfig {
+  fn default() -> CheckConfig {
+    CheckConfig {
+      soa_minimum: (300, 86400),
+      soa_ttl: (300, 86400),
+      policy: None,
+      zone_files: Vec::new(),
+      slow_query: Duration::from_millis(1000),
+    }
+  }
+}
+
+/// Run all checks against the record database.
+pub fn check_all(record_db: &RecordDB, config: &CheckConfig) -> Vec<Finding> {
+  let mut findings = Vec::new();
+
+  findings.extend(delegation::check_delegations(record_db));
+  findings.extend(dnssec::check_denials(record_db));
+  findings.extend(chain::check_chains(record_db));
+  findings.extend(nameserver::check_ns_targets(record_db));
+  findings.extend(check_latency(record_db, config));
+  findings.extend(check_recursion(record_db));
+  findings.extend(check_zone_transfers(record_db));
+  findings.extend(edns::check_edns(record_db));
+  findings.extend(ttl::check_ttls(record_db));
+  findings.extend(negative::check_negative_caching(record_db, config));
+  if let Some(policy) = &config.policy {
+    findings.extend(policy::check_policy(record_db, policy));
+  }
+  for zone_file in &config.zone_files {
+    findings.extend(zonefile::check_zone_file(record_db, zone_file));
+  }
+
+  findings.sort();
+  findings.dedup();
+  findings
+}
+
+/// Flag servers slow to answer, from the query log.
+fn check_latency(record_db: &RecordDB, config: &CheckConfig) -> Vec<Finding> {
+  let mut slowest: BTreeMap<IpAddr, &LoggedQuery> = BTreeMap::new();
+  let mut retried: BTreeMap<IpAddr, &LoggedQuery> = BTreeMap::new();
+  for query in record_db.get_query_log() {
+    let rtt = match &query.response {
+      Some(response) => response.rtt,
+      None => continue,
+    };
+    let entry = slowest.entry(query.server).or_insert(query);
+    if entry.response.as_ref().is_none_or(|response| response.rtt < rtt) {
+      *entry = query;
+    }
+    if query.attempts > 1 {
+      retried.entry(query.server).or_insert(query);
+    }
+  }
+
+  let mut findings = Vec::new();
+  for (ip, query) in slowest {
+    let rtt = query.response.as_ref().unwrap().rtt;
+    if rtt >= config.slow_query {
+      findings.push(Finding::new(
+        Severity::Warning, Check::Latency, &query.name, Some(&RServer::from(ip)),
+        format!("took {} ms to answer for {} {}", rtt.as_millis(), query.name, query.rtype)
+      ));
+    }
+  }
+  for (ip, query) in retried {
+    findings.push(Finding::new(
+      Severity::Info, Check::Latency, &query.name, Some(&RServer::from(ip)),
+      format!("only answered for {} {} after {} attempts", query.name, query.rtype, query.attempts)
+    ));
+  }
+  findings
+}
+
+/// Flag servers found to be open resolvers.
+fn check_recursion(record_db: &RecordDB) -> Vec<Finding> {
+  record_db.get_server_info().iter()
+    .filter(|(_, info)| info.recursion == Some(Recursion::Open))
+    .map(|(server, _)| Finding::new(
+      Severity::Error, Check::Recursion, &rr::Name::root(), Some(server),
+      "authoritative server recurses for anyone".to_string()
+    ))
+    .collect()
+}
+
+/// Flag servers allowing anyone to transfer a zone.
+fn check_zone_transfers(record_db: &RecordDB) -> Vec<Finding> {
+  let mut findings = Vec::new();
+
+  for (server, info) in record_db.get_server_info() {
+    for (zone, transfer) in &info.zone_transfers {
+      let message = match transfer {
+        ZoneTransfer::Allowed(records) =>
+          format!("zone transfer allowed to anyone ({} records)", records),
+        ZoneTransfer::Partial(records, e) =>
+          format!("zone transfer allowed to anyone, stopped after {} records: {}", records, e),
+        _ => continue,
+      };
+      findings.push(Finding::new(
+        Severity::Warning, Check::ZoneTransfer, zone, Some(server), message
+      ));
+    }
+  }
+
+  findings
+}
+
+/// Dump findings to stdout.
+///
+/// Identifiers of the server a finding is about are shown with it, when
+/// they are known.
+pub fn dump(record_db: &RecordDB, findings: &[Finding]) {
+  println!("Findings");
+
+  for finding in findings {
+    println!("  {}", finding);
+
+    let identity = finding.server.as_ref().and_then(|server| record_db.get_identity(server));
+    if let Some(identity) = identity {
+      println!("    Server identity: {}", identity);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::net::IpAddr;
+
+  use super::*;
+
+  #[test]
+  fn open_resolvers_flagged() {
+    let open = RServer::from("192.0.2.1".parse::<IpAddr>().unwrap());
+    let closed = RServer::from("192.0.2.2".parse::<IpAddr>().unwrap());
+    let mut db = RecordDB::new();
+    db.server_info_mut(&open).recursion = Some(Recursion::Open);
+    db.server_info_mut(&closed).recursion = Some(Recursion::Closed);
+
+    let findings = check_recursion(&db);
+    assert_eq!(findings.len(), 1);
+    assert_eq!(findings[0].check, Check::Recursion);
+    assert_eq!(findings[0].severity, Severity::Error);
+    assert_eq!(findings[0].server, Some(open));
+  }
+}
@@ -0,0 +1,279 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use data_encoding::HEXLOWER;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use trust_dns_client::op::{Header, Query};
+use trust_dns_client::proto::serialize::binary::{BinDecodable, BinDecoder};
+
+use crate::db::Transport;
+use crate::dns::QueryError;
+use crate::snapshot;
+
+/// Version of the fixture format.
+pub const VERSION: u32 = 1;
+
+/// Error loading or saving a fixture.
+#[derive(Debug)]
+pub enum FixtureError {
+  Io(io::Error),
+  Json(serde_json::Error),
+  /// Fixture written by an incompatible version.
+  Version(u32),
+}
+
+impl From<io::Error> for FixtureError {
+  fn from(e: io::Error) -> FixtureError {
+    FixtureError::Io(e)
+  }
+}
+
+impl From<serde_json::Error> for FixtureError {
+  fn from(e: serde_json::Error) -> FixtureError {
+    FixtureError::Json(e)
+  }
+}
+
+impl fmt::Display for FixtureError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      FixtureError::Io(e) => write!(f, "io error: {}", e),
+      FixtureError::Json(e) => write!(f, "json error: {}", e),
+      FixtureError::Version(v) =>
+        write!(f, "unsupported fixture version {}, expected {}", v, VERSION),
+    }
+  }
+}
+
+/// What a server answered to a query, as recorded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Response {
+  /// Hex encoded response message.
+  Message(String),
+  Timeout,
+  /// Any other failure, such as a refused connection.
+  Error(String),
+}
+
+/// Every response to the same query, in the order they were received.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Exchange {
+  pub server: IpAddr,
+  pub name: String,
+  pub rtype: String,
+  pub transport: Transport,
+  pub responses: Vec<Response>,
+}
+
+/// Responses of a whole run, as written.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Fixture {
+  pub version: u32,
+  pub exchanges: Vec<Exchange>,
+}
+
+/// Server, name, type and transport of a query.
+type Key = (IpAddr, String, String, Transport);
+
+#[derive(Debug)]
+enum Mode {
+  Record(PathBuf, BTreeMap<Key, Vec<Response>>),
+  Replay(BTreeMap<Key, VecDeque<Response>>),
+}
+
+/// Responses of a check being recorded to a fixture, or answered from one.
+#[derive(Debug)]
+pub struct Session {
+  mode: Mutex<Mode>,
+}
+
+impl Session {
+  /// Record every response, to save to a fixture with `save`.
+  pub fn record(path: &Path) -> Session {
+    Session { mode: Mutex::new(Mode::Record(path.to_path_buf(), BTreeMap::new())) }
+  }
+
+  /// Answer every query from a fixture, instead of sending it.
+  pub fn replay(path: &Path) -> Result<Session, FixtureError> {
+    let fixture: Fixture = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+    if fixture.version != VERSION {
+      return Err(FixtureError::Version(fixture.version));
+    }
+
+    let exchanges = fixture.exchanges.into_iter()
+      .map(|e| ((e.server, e.name, e.rtype, e.transport), e.responses.into_iter().collect()))
+      .collect();
+    Ok(Session { mode: Mutex::new(Mode::Replay(exchanges)) })
+  }
+
+  /// Whether queries are answered from a fixture.
+  pub fn replaying(&self) -> bool {
+    matches!(*self.mode.lock().unwrap(), Mode::Replay(_))
+  }
+
+  /// Note the outcome of a query sent, when recording.
+  pub fn add(&self, server: IpAddr, query: &[u8], transport: Transport,
+             result: &Result<Vec<u8>, QueryError>) {
+    let mut mode = self.mode.lock().unwrap();
+    let responses = match &mut *mode {
+      Mode::Record(_, responses) => responses,
+      Mode::Replay(_) => return,
+    };
+    let key = match key(server, query, transport) {
+      Some(key) => key,
+      None => return,
+    };
+
+    let response = match result {
+      Ok(message) => Response::Message(HEXLOWER.encode(message)),
+      Err(QueryError::Timeout) => Response::Timeout,
+      Err(e) => Response::Error(e.to_string()),
+    };
+    responses.entry(key).or_insert_with(Vec::new).push(response);
+  }
+
+  /// Answer a query from the fixture, with the query's message ID.
+  ///
+  /// Responses to the same query are given in the order recorded, the last
+  /// one again once they run out. Queries missing from the fixture time out.
+  pub fn answer(&self, server: IpAddr, query: &[u8], transport: Transport)
+      -> Result<Vec<u8>, QueryError> {
+    let mut mode = self.mode.lock().unwrap();
+    let exchanges = match &mut *mode {
+      Mode::Replay(exchanges) => exchanges,
+      Mode::Record(..) => return Err(QueryError::Timeout),
+    };
+    let key = key(server, query, transport).ok_or(QueryError::Timeout)?;
+
+    let responses = match exchanges.get_mut(&key) {
+      Some(responses) if !responses.is_empty() => responses,
+      _ => {
+        warn!("No recorded response for {} {} {} over {}", key.1, key.2, key.0, key.3);
+        return Err(QueryError::Timeout);
+      },
+    };
+    let response = if responses.len() > 1 {
+      responses.pop_front().unwrap()
+    } else {
+      responses[0].clone()
+    };
+
+    match response {
+      Response::Message(hex) => {
+        let mut message = HEXLOWER.decode(hex.as_bytes())
+          .map_err(|e| QueryError::Io(io::Error::new(io::ErrorKind::InvalidData, e.to_string())))?;
+        if message.len() >= 2 && query.len() >= 2 {
+          message[..2].copy_from_slice(&query[..2]);
+        }
+        Ok(message)
+      },
+      Response::Timeout => Err(QueryError::Timeout),
+      Response::Error(e) => Err(QueryError::Io(io::Error::other(e))),
+    }
+  }
+
+  /// Save the responses recorded so far, when recording.
+  pub fn save(&self) -> Result<(), FixtureError> {
+    let mode = self.mode.lock().unwrap();
+    let (path, responses) = match &*mode {
+      Mode::Record(path, responses) => (path, responses),
+      Mode::Replay(_) => return Ok(()),
+    };
+
+    let fixture = Fixture {
+      version: VERSION,
+      exchanges: responses.iter()
+        .map(|((server, name, rtype, transport), responses)| Exchange {
+          server: *server,
+          name: name.clone(),
+          rtype: rtype.clone(),
+          transport: *transport,
+          responses: responses.clone(),
+        })
+        .collect(),
+    };
+
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let mut writer = BufWriter::new(File::create(&tmp)?);
+    serde_json::to_writer_pretty(&mut writer, &fixture)?;
+    writer.flush()?;
+    drop(writer);
+    fs::rename(&tmp, path)?;
+    Ok(())
+  }
+}
+
+/// Key of a query message, None if it can't be parsed.
+fn key(server: IpAddr, query: &[u8], transport: Transport) -> Option<Key> {
+  let mut decoder = BinDecoder::new(query);
+  Header::read(&mut decoder).ok()?;
+  let query = Query::read(&mut decoder).ok()?;
+  Some((server, snapshot::encode_name(query.name()),
+        snapshot::encode_type(query.query_type()), transport))
+}
+
+#[cfg(test)]
+mod tests {
+  use std::env;
+  use std::str::FromStr;
+
+  use trust_dns_client::op::Message;
+  use trust_dns_client::rr;
+
+  use super::*;
+
+  const SERVER: &str = "192.0.2.1";
+
+  fn query(id: u16) -> Vec<u8> {
+    let mut message = Message::new();
+    message.set_id(id);
+    message.add_query(Query::query(rr::Name::from_str("example.com.").unwrap(),
+                                   rr::RecordType::SOA));
+    message.to_vec().unwrap()
+  }
+
+  fn temp_path(test: &str) -> PathBuf {
+    env::temp_dir().join(format!("dns-delegation-check-{}-{}.json", test, std::process::id()))
+  }
+
+  #[test]
+  fn record_and_replay() {
+    let path = temp_path("record-and-replay");
+    let server = SERVER.parse().unwrap();
+
+    let recording = Session::record(&path);
+    assert!(!recording.replaying());
+    recording.add(server, &query(1), Transport::Udp, &Err(QueryError::Timeout));
+    recording.add(server, &query(2), Transport::Udp, &Ok(vec![0, 2, 0x84, 0]));
+    recording.save().unwrap();
+
+    let replay = Session::replay(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert!(replay.replaying());
+
+    // Responses come back in order, with the ID of the query answered.
+    assert!(matches!(replay.answer(server, &query(7), Transport::Udp), Err(QueryError::Timeout)));
+    assert_eq!(replay.answer(server, &query(8), Transport::Udp).unwrap(), vec![0, 8, 0x84, 0]);
+    assert_eq!(replay.answer(server, &query(9), Transport::Udp).unwrap(), vec![0, 9, 0x84, 0]);
+
+    // Anything not recorded times out.
+    assert!(matches!(replay.answer(server, &query(7), Transport::Tcp), Err(QueryError::Timeout)));
+  }
+
+  #[test]
+  fn replay_unsupported_version() {
+    let path = temp_path("unsupported-version");
+    fs::write(&path, r#"{"version": 99, "exchanges": []}"#).unwrap();
+    let result = Session::replay(&path);
+    fs::remove_file(&path).unwrap();
+    assert!(matches!(result, Err(FixtureError::Version(99))));
+  }
+}
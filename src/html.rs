@@ -0,0 +1,235 @@
+use std::collections::BTreeSet;
+use std::io::{self, Write};
+
+use trust_dns_client::rr;
+
+use crate::db::{REntry, RecordDB, RServer};
+use crate::findings::{Finding, Severity};
+use crate::trace;
+
+const STYLE: &str = "
+body { font-family: sans-serif; margin: 2em; color: #222; }
+h2 { border-bottom: 1px solid #ccc; padding-bottom: 0.2em; }
+table { border-collapse: collapse; margin: 0.5em 0 1.5em; }
+th, td { border: 1px solid #ccc; padding: 0.3em 0.6em; text-align: left; vertical-align: top; }
+th { background: #f3f3f3; }
+code { font-size: 0.9em; }
+.badge { display: inline-block; padding: 0.1em 0.6em; border-radius: 0.8em; color: #fff; font-size: 0.8em; }
+.ok { background: #2e7d32; }
+.info { background: #1565c0; }
+.warning { background: #ef6c00; }
+.error { background: #c62828; }
+td.timeout { background: #fdecea; }
+td.none { color: #888; }
+";
+
+/// Escape text for HTML.
+fn escape(text: &str) -> String {
+  text.replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+    .replace('"', "&quot;")
+}
+
+fn severity_class(severity: Option<Severity>) -> &'static str {
+  match severity {
+    None => "ok",
+    Some(Severity::Info) => "info",
+    Some(Severity::Warning) => "warning",
+    Some(Severity::Error) => "error",
+  }
+}
+
+fn badge(severity: Option<Severity>) -> String {
+  let label = severity.map(|s| s.to_string()).unwrap_or_else(|| "OK".to_string());
+  format!("<span class=\"badge {}\">{}</span>", severity_class(severity), label)
+}
+
+/// Table cell for what a server answered, for one name and type.
+fn cell(record_db: &RecordDB, name: &rr::Name, server: &RServer,
+        rtype: rr::RecordType) -> String {
+  let entries = record_db.get_records(name).remove(server).unwrap_or_default();
+  match entries.get(&rtype) {
+    Some(REntry::Entries(items)) => {
+      let items: Vec<_> = items.iter()
+        .map(|(rdata, ttl)| format!("<code>{}</code> ({})", escape(&trace::rdata_text(rdata)), ttl))
+        .collect();
+      format!("<td>{}</td>", items.join("<br>"))
+    },
+    Some(REntry::NoEntry) => "<td class=\"none\">no data</td>".to_string(),
+    Some(REntry::TimeOut) => "<td class=\"timeout\">timed out</td>".to_string(),
+    None => "<td class=\"none\">-</td>".to_string(),
+  }
+}
+
+fn findings_table(out: &mut impl Write, record_db: &RecordDB, findings: &[&Finding])
+    -> io::Result<()> {
+  if findings.is_empty() {
+    writeln!(out, "<p>No findings.</p>")?;
+    return Ok(());
+  }
+
+  writeln!(out, "<table><tr><th>Severity</th><th>Check</th><th>Name</th><th>Server</th><th>Finding</th></tr>")?;
+  for finding in findings {
+    let mut server = finding.server.as_ref()
+      .and_then(|server| server.ip())
+      .map(|ip| ip.to_string())
+      .unwrap_or_default();
+    let identity = finding.server.as_ref().and_then(|server| record_db.get_identity(server));
+    if let Some(identity) = identity {
+      server.push_str(&format!("<br><small>{}</small>", escape(&identity)));
+    }
+    writeln!(out, "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}<br><small>{}</small></td></tr>",
+             badge(Some(finding.severity)), finding.check, escape(&finding.name.to_string()),
+             server, escape(&finding.message), escape(finding.check.explanation()))?;
+  }
+  writeln!(out, "</table>")
+}
+
+fn query_log(out: &mut impl Write, record_db: &RecordDB) -> io::Result<()> {
+  let queries = record_db.get_query_log();
+  if queries.is_empty() {
+    return Ok(());
+  }
+
+  writeln!(out, "<h2>Query log</h2><details><summary>{} queries</summary>", queries.len())?;
+  writeln!(out, "<table><tr><th>Server</th><th>Query</th><th>Transport</th><th>RTT</th><th>Size</th>\
+            <th>Response</th><th>Flags</th><th>Attempts</th></tr>")?;
+  for query in queries {
+    write!(out, "<tr><td>{}</td><td>{} {}</td><td>{}</td>",
+           query.server, escape(&query.name.to_string()), query.rtype, query.transport)?;
+    match &query.response {
+      Some(response) => write!(out, "<td>{} ms</td><td>{}</td><td>{}</td><td>{}</td>",
+                               response.rtt.as_millis(), response.size, response.rcode, response.flags)?,
+      None => write!(out, "<td class=\"timeout\" colspan=\"4\">timed out</td>")?,
+    }
+    writeln!(out, "<td>{}</td></tr>", query.attempts)?;
+  }
+  writeln!(out, "</table></details>")
+}
+
+/// Write a self-contained HTML report.
+///
+/// Each answer target gets a badge for its worst finding, the zones it was
+/// referred through with their NS names, a matrix of what each server of
+/// the last zone answered, and findings about the target or its zones.
+/// Every query sent is listed at the end.
+pub fn dump(out: &mut impl Write, record_db: &RecordDB, findings: &[Finding]) -> io::Result<()> {
+  writeln!(out, "<!DOCTYPE html>")?;
+  writeln!(out, "<html><head><meta charset=\"utf-8\"><title>DNS delegation report</title>")?;
+  writeln!(out, "<style>{}</style></head><body>", STYLE)?;
+  writeln!(out, "<h1>DNS delegation report</h1>")?;
+
+  let mut shown = BTreeSet::new();
+
+  for (name, rtype) in record_db.get_answer_targets() {
+    let chain = record_db.get_referral_chain(&name, rtype);
+    let relevant: Vec<_> = findings.iter()
+      .filter(|finding| finding.name == name || chain.contains(&finding.name))
+      .collect();
+    shown.extend(relevant.iter().map(|finding| (*finding).clone()));
+    let worst = relevant.iter()
+      .map(|finding| finding.severity)
+      .filter(|severity| *severity != Severity::Info)
+      .max();
+
+    writeln!(out, "<h2>{} {} {}</h2>", escape(&name.to_string()), rtype, badge(worst))?;
+
+    writeln!(out, "<h3>Delegation chain</h3><ol>")?;
+    for zone in &chain {
+      let ns: Vec<_> = record_db.get_record_set(zone, rr::RecordType::NS).iter()
+        .filter_map(|rdata| rdata.as_ns())
+        .map(|ns| escape(&ns.to_string()))
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect();
+      writeln!(out, "<li><b>{}</b> NS {}</li>", escape(&zone.to_string()), ns.join(", "))?;
+    }
+    writeln!(out, "</ol>")?;
+
+    if let Some(zone) = chain.last() {
+      // Columns are the zone's apex records, then every type seen for the
+      // target name.
+      let mut columns = vec![(zone.clone(), rr::RecordType::SOA), (zone.clone(), rr::RecordType::NS)];
+      let mut types = BTreeSet::new();
+      types.insert(rtype);
+      for entries in record_db.get_records(&name).values() {
+        types.extend(entries.keys().cloned());
+      }
+      for column_type in types {
+        if !columns.contains(&(name.clone(), column_type)) {
+          columns.push((name.clone(), column_type));
+        }
+      }
+
+      writeln!(out, "<h3>Servers of {}</h3>", escape(&zone.to_string()))?;
+      write!(out, "<table><tr><th>Server</th><th>Query</th>")?;
+      for (owner, column_type) in &columns {
+        write!(out, "<th>{} {}</th>", escape(&owner.to_string()), column_type)?;
+      }
+      writeln!(out, "</tr>")?;
+
+      let names = trace::server_names(record_db, zone);
+      for server in record_db.get_zone_servers(zone) {
+        let ip = server.ip().map(|ip| ip.to_string()).unwrap_or_default();
+        let ns: Vec<_> = names.get(&server).into_iter()
+          .flat_map(|names| names.iter())
+          .map(|ns| escape(&ns.to_string()))
+          .collect();
+        write!(out, "<tr><th>{}<br><small>{}</small></th>", ip, ns.join(", "))?;
+        let query = trace::describe_query(record_db, &name, rtype, &server).unwrap_or_default();
+        write!(out, "<td>{}</td>", escape(&query))?;
+        for (owner, column_type) in &columns {
+          write!(out, "{}", cell(record_db, owner, &server, *column_type))?;
+        }
+        writeln!(out, "</tr>")?;
+      }
+      writeln!(out, "</table>")?;
+    }
+
+    writeln!(out, "<h3>Findings</h3>")?;
+    findings_table(out, record_db, &relevant)?;
+  }
+
+  let others: Vec<_> = findings.iter().filter(|finding| !shown.contains(*finding)).collect();
+  if !others.is_empty() {
+    writeln!(out, "<h2>Other findings</h2>")?;
+    findings_table(out, record_db, &others)?;
+  }
+
+  query_log(out, record_db)?;
+
+  writeln!(out, "</body></html>")
+}
+
+#[cfg(test)]
+mod tests {
+  use std::str::FromStr;
+
+  use super::*;
+  use crate::findings::Check;
+
+  #[test]
+  fn findings_escaped_and_grouped() {
+    let www = rr::Name::from_str("www.example.com.").unwrap();
+    let mut db = RecordDB::new();
+    db.add_answer_target(&www, rr::RecordType::A);
+    let findings = vec![
+      Finding::new(Severity::Error, Check::Alias, &www, None,
+                   "alias loop: <a> & <b>".to_string()),
+      Finding::new(Severity::Warning, Check::Alias, &rr::Name::from_str("example.org.").unwrap(),
+                   None, "elsewhere".to_string()),
+    ];
+
+    let mut out = Vec::new();
+    dump(&mut out, &db, &findings).unwrap();
+    let html = String::from_utf8(out).unwrap();
+
+    assert!(html.starts_with("<!DOCTYPE html>\n"));
+    assert!(html.ends_with("</body></html>\n"));
+    assert!(html.contains("<h2>www.example.com. A <span class=\"badge error\">ERROR</span></h2>"));
+    assert!(html.contains("alias loop: &lt;a&gt; &amp; &lt;b&gt;"));
+    let others = html.find("<h2>Other findings</h2>").unwrap();
+    assert!(html[others..].contains("elsewhere"));
+  }
+}
@@ -0,0 +1,126 @@
+use std::collections::BTreeSet;
+
+use crate::db::RecordDB;
+use crate::findings::{Check, Finding, Severity};
+
+/// Escape text for XML.
+fn escape(text: &str) -> String {
+  text.replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+    .replace('"', "&quot;")
+}
+
+/// A finding as a line of a failure or system-out.
+fn describe(record_db: &RecordDB, finding: &Finding) -> String {
+  let mut server = finding.server.as_ref()
+    .and_then(|server| server.ip())
+    .map(|ip| format!(" @{}", ip))
+    .unwrap_or_default();
+  let identity = finding.server.as_ref().and_then(|server| record_db.get_identity(server));
+  if let Some(identity) = identity {
+    server.push_str(&format!(" ({})", identity));
+  }
+  format!("{} {}{}: {}", finding.severity, finding.name, server, finding.message)
+}
+
+/// Test suite with a test case per check, failing where the check has
+/// warnings or errors.
+fn suite(record_db: &RecordDB, name: &str, findings: &[&Finding]) -> (usize, String) {
+  let mut failures = 0;
+  let mut cases = String::new();
+
+  for check in Check::all() {
+    let found: Vec<_> = findings.iter().filter(|finding| finding.check == check).collect();
+    let failed: Vec<_> = found.iter().filter(|finding| finding.severity != Severity::Info).collect();
+    let info: Vec<_> = found.iter().filter(|finding| finding.severity == Severity::Info).collect();
+
+    let mut body = String::new();
+    if let Some(worst) = failed.iter().map(|finding| finding.severity).max() {
+      failures += 1;
+      let lines: Vec<_> = failed.iter().map(|finding| escape(&describe(record_db, finding))).collect();
+      body.push_str(&format!(
+        "      <failure type=\"{}\" message=\"{}\">{}\n{}</failure>\n",
+        worst, escape(&failed[0].message), escape(check.explanation()), lines.join("\n")
+      ));
+    }
+    if !info.is_empty() {
+      let lines: Vec<_> = info.iter().map(|finding| escape(&describe(record_db, finding))).collect();
+      body.push_str(&format!("      <system-out>{}</system-out>\n", lines.join("\n")));
+    }
+
+    let case = format!("    <testcase classname=\"{}\" name=\"{}\"", escape(name), check);
+    if body.is_empty() {
+      cases.push_str(&format!("{}/>\n", case));
+    } else {
+      cases.push_str(&format!("{}>\n{}    </testcase>\n", case, body));
+    }
+  }
+
+  let xml = format!("  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"0\">\n{}  </testsuite>\n",
+                    escape(name), Check::all().len(), failures, cases);
+  (failures, xml)
+}
+
+/// Dump findings as a JUnit XML report to stdout, for CI systems.
+///
+/// Each answer target is a test suite with a test case per check. Findings
+/// about the target or the zones it was referred through are failures of
+/// their check, with the server and message. Findings about anything else
+/// go in a last "other" suite.
+pub fn dump(record_db: &RecordDB, findings: &[Finding]) {
+  let mut suites = Vec::new();
+  let mut shown = BTreeSet::new();
+
+  for (name, rtype) in record_db.get_answer_targets() {
+    let chain = record_db.get_referral_chain(&name, rtype);
+    let relevant: Vec<_> = findings.iter()
+      .filter(|finding| finding.name == name || chain.contains(&finding.name))
+      .collect();
+    shown.extend(relevant.iter().map(|finding| (*finding).clone()));
+    suites.push(suite(record_db, &format!("{} {}", name, rtype), &relevant));
+  }
+
+  let others: Vec<_> = findings.iter().filter(|finding| !shown.contains(*finding)).collect();
+  if !others.is_empty() {
+    suites.push(suite(record_db, "other", &others));
+  }
+
+  let tests = suites.len() * Check::all().len();
+  let failures: usize = suites.iter().map(|(failures, _)| failures).sum();
+  println!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>");
+  println!("<testsuites name=\"dns-delegation-check\" tests=\"{}\" failures=\"{}\" errors=\"0\">",
+           tests, failures);
+  for (_, xml) in &suites {
+    print!("{}", xml);
+  }
+  println!("</testsuites>");
+}
+
+#[cfg(test)]
+mod tests {
+  use std::net::IpAddr;
+  use std::str::FromStr;
+
+  use trust_dns_client::rr;
+
+  use super::*;
+  use crate::db::{Identity, RServer};
+
+  #[test]
+  fn identity_next_to_server() {
+    let server = RServer::from("192.0.2.1".parse::<IpAddr>().unwrap());
+    let mut db = RecordDB::new();
+    db.server_info_mut(&server).identity = Some(Identity {
+      nsid: Some("ns1-ams".to_string()),
+      ..Identity::default()
+    });
+    let finding = Finding::new(Severity::Error, Check::Lame,
+                               &rr::Name::from_str("example.com.").unwrap(), Some(&server),
+                               "server is lame".to_string());
+    assert_eq!(describe(&db, &finding),
+               "ERROR example.com. @192.0.2.1 (nsid \"ns1-ams\"): server is lame");
+    assert_eq!(describe(&RecordDB::new(), &finding),
+               "ERROR example.com. @192.0.2.1: server is lame");
+  }
+}
@@ -0,0 +1,366 @@
+//! Check DNS delegations by resolving names from the root, asking every
+//! server of every zone on the way.
+//!
+//! ```no_run
+//! use std::str::FromStr;
+//!
+//! use dns_delegation_check::Checker;
+//! use trust_dns_client::rr;
+//!
+//! let report = Checker::new()
+//!   .target(&rr::Name::from_str("example.com.").unwrap(), rr::RecordType::A)
+//!   .check_edns()
+//!   .run()
+//!   .unwrap();
+//! for finding in &report.findings {
+//!   println!("{}", finding);
+//! }
+//! ```
+
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use log::error;
+use trust_dns_client::rr;
+
+pub mod cache;
+pub mod chain;
+pub mod daemon;
+pub mod db;
+pub mod delegation;
+pub mod diff;
+pub mod dot;
+pub mod dns;
+pub mod dnssec;
+pub mod edns;
+pub mod findings;
+pub mod fixture;
+pub mod html;
+pub mod junit;
+pub mod nagios;
+pub mod nameserver;
+pub mod negative;
+pub mod pcap;
+pub mod policy;
+pub mod probe;
+pub mod prometheus;
+pub mod server;
+pub mod snapshot;
+pub mod trace;
+pub mod ttl;
+pub mod zonefile;
+
+pub use cache::ReferralCache;
+pub use db::{REntry, RServer, RecordDB};
+pub use findings::{Check, CheckConfig, Finding, Severity};
+
+/// Where answers to queries come from.
+#[derive(Debug, Clone)]
+pub enum QueryTransport {
+  /// Send queries to the servers.
+  Network,
+  /// Send queries to the servers, recording every response to a fixture
+  /// file.
+  Record(PathBuf),
+  /// Answer queries from a recorded fixture file.
+  Replay(PathBuf),
+}
+
+/// Error setting up a check.
+#[derive(Debug)]
+pub enum CheckError {
+  /// The packet capture couldn't be written.
+  Pcap(io::Error),
+  /// The fixture to replay couldn't be loaded.
+  Fixture(fixture::FixtureError),
+}
+
+impl fmt::Display for CheckError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      CheckError::Pcap(e) => write!(f, "can't write packet capture: {}", e),
+      CheckError::Fixture(e) => write!(f, "can't load fixture: {}", e),
+    }
+  }
+}
+
+/// What a check found: everything gathered, and the findings about it.
+#[derive(Debug)]
+pub struct Report {
+  pub records: RecordDB,
+  pub findings: Vec<Finding>,
+}
+
+impl Report {
+  /// Most serious finding, None if there are none.
+  pub fn worst(&self) -> Option<Severity> {
+    self.findings.iter().map(|finding| finding.severity).max()
+  }
+}
+
+/// Builder for a check of some names.
+#[derive(Debug)]
+pub struct Checker {
+  root_hints: Vec<(rr::Name, IpAddr)>,
+  records: Option<RecordDB>,
+  targets: Vec<(rr::Name, rr::RecordType)>,
+  transport: QueryTransport,
+  pcap: Option<PathBuf>,
+  referral_cache: Option<Arc<ReferralCache>>,
+  send_queries: bool,
+  identify: bool,
+  check_axfr: bool,
+  check_edns: bool,
+  recursion_probe: Option<rr::Name>,
+  checks: CheckConfig,
+}
+
+impl Checker {
+  /// Check starting from the usual root hints, with no probes.
+  pub fn new() -> Checker {
+    Checker {
+      root_hints: dns::root_hints(),
+      records: None,
+      targets: Vec::new(),
+      transport: QueryTransport::Network,
+      pcap: None,
+      referral_cache: None,
+      send_queries: true,
+      identify: false,
+      check_axfr: false,
+      check_edns: false,
+      recursion_probe: None,
+      checks: CheckConfig::default(),
+    }
+  }
+
+  /// Start from other root servers.
+  pub fn root_hints(mut self, hints: Vec<(rr::Name, IpAddr)>) -> Checker {
+    self.root_hints = hints;
+    self
+  }
+
+  /// Start from a saved record database instead of root hints, resuming
+  /// its queries.
+  pub fn records(mut self, records: RecordDB) -> Checker {
+    self.records = Some(records);
+    self
+  }
+
+  /// Resolve a name and record type.
+  pub fn target(mut self, name: &rr::Name, rtype: rr::RecordType) -> Checker {
+    self.targets.push((name.clone(), rtype));
+    self
+  }
+
+  /// Where answers to queries come from.
+  pub fn transport(mut self, transport: QueryTransport) -> Checker {
+    self.transport = transport;
+    self
+  }
+
+  /// Write every query and response to a pcap file.
+  pub fn pcap(mut self, path: &Path) -> Checker {
+    self.pcap = Some(path.to_path_buf());
+    self
+  }
+
+  /// Share referrals and zone apex answers with other checks using the
+  /// same cache.
+  pub fn referral_cache(mut self, cache: Arc<ReferralCache>) -> Checker {
+    self.referral_cache = Some(cache);
+    self
+  }
+
+  /// Only analyse the starting record database, sending no queries.
+  pub fn no_queries(mut self) -> Checker {
+    self.send_queries = false;
+    self
+  }
+
+  /// Ask servers for their NSID and CHAOS identifiers.
+  pub fn identify(mut self) -> Checker {
+    self.identify = true;
+    self
+  }
+
+  /// Check whether answer zones can be transferred.
+  pub fn check_axfr(mut self) -> Checker {
+    self.check_axfr = true;
+    self
+  }
+
+  /// Run EDNS compliance tests against servers.
+  pub fn check_edns(mut self) -> Checker {
+    self.check_edns = true;
+    self
+  }
+
+  /// Check whether authoritative servers recurse, asking for an unrelated
+  /// name.
+  pub fn check_recursion(mut self, probe: &rr::Name) -> Checker {
+    self.recursion_probe = Some(probe.clone());
+    self
+  }
+
+  /// Settings for checks, including any policy and zone files.
+  pub fn checks(mut self, checks: CheckConfig) -> Checker {
+    self.checks = checks;
+    self
+  }
+
+  /// Run the check.
+  pub fn run(self) -> Result<Report, CheckError> {
+    self.run_with(|_| ())
+  }
+
+  /// Run the check, calling back after every round of queries, such as to
+  /// save the record database.
+  pub fn run_with<F: FnMut(&RecordDB)>(self, after_round: F) -> Result<Report, CheckError> {
+    let mut records = match self.records {
+      Some(records) => records,
+      None => {
+        let mut records = RecordDB::new();
+        records.add_root_hints(self.root_hints);
+        records
+      },
+    };
+
+    for (name, rtype) in &self.targets {
+      records.add_answer_target(name, *rtype);
+    }
+    if let Some(cache) = self.referral_cache {
+      records.set_referral_cache(cache);
+    }
+
+    if self.send_queries {
+      // Resolve zones of the policy and zone files, so there's something
+      // to check them against.
+      if let Some(policy) = &self.checks.policy {
+        for zone in policy.zones.keys() {
+          records.add_answer_target(zone, rr::RecordType::SOA);
+        }
+      }
+      for zone_file in &self.checks.zone_files {
+        records.add_answer_target(&zone_file.zone, rr::RecordType::SOA);
+      }
+
+      let mut hooks = dns::Hooks::default();
+      if let Some(path) = &self.pcap {
+        hooks.capture = Some(Arc::new(pcap::Capture::create(path).map_err(CheckError::Pcap)?));
+      }
+      hooks.fixture = match &self.transport {
+        QueryTransport::Network => None,
+        QueryTransport::Record(path) => Some(Arc::new(fixture::Session::record(path))),
+        QueryTransport::Replay(path) =>
+          Some(Arc::new(fixture::Session::replay(path).map_err(CheckError::Fixture)?)),
+      };
+      records.set_hooks(hooks.clone());
+
+      records.action_loop_with(after_round);
+
+      if self.identify {
+        probe::probe_identities(&mut records);
+      }
+      if self.check_axfr {
+        probe::probe_zone_transfers(&mut records);
+      }
+      if self.check_edns {
+        edns::probe_edns(&mut records);
+      }
+      if let Some(probe) = &self.recursion_probe {
+        probe::probe_recursion(&mut records, probe);
+      }
+      for zone_file in &self.checks.zone_files {
+        zonefile::query_zone_file(&mut records, zone_file);
+      }
+
+      if let Some(fixture) = &hooks.fixture {
+        if let Err(e) = fixture.save() {
+          error!("Can't save fixture: {}", e);
+        }
+      }
+    }
+
+    let findings = findings::check_all(&records, &self.checks);
+    Ok(Report { records, findings })
+  }
+}
+
+impl Default for Checker {
+  fn default() -> Checker {
+    Checker::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::env;
+  use std::fs;
+  use std::str::FromStr;
+
+  use data_encoding::HEXLOWER;
+  use trust_dns_client::op::{Message, MessageType};
+  use trust_dns_client::rr::rdata::SOA;
+  use trust_dns_client::rr::RData;
+
+  use super::*;
+  use crate::fixture::{Exchange, Fixture, Response};
+
+  const ROOT: &str = "198.41.0.4";
+
+  fn root_answer(rtype: rr::RecordType, rdata: RData) -> Exchange {
+    let mut message = Message::new();
+    message.set_message_type(MessageType::Response);
+    message.set_authoritative(true);
+    message.add_answer(rr::Record::from_rdata(rr::Name::root(), 86400, rdata));
+    Exchange {
+      server: ROOT.parse().unwrap(),
+      name: snapshot::encode_name(&rr::Name::root()),
+      rtype: snapshot::encode_type(rtype),
+      transport: db::Transport::Udp,
+      responses: vec![Response::Message(HEXLOWER.encode(&message.to_vec().unwrap()))],
+    }
+  }
+
+  fn replay(fixture: &Path) -> Report {
+    Checker::new()
+      .root_hints(vec![(rr::Name::from_str("a.root-servers.net.").unwrap(), ROOT.parse().unwrap())])
+      .target(&rr::Name::root(), rr::RecordType::SOA)
+      .transport(QueryTransport::Replay(fixture.to_path_buf()))
+      .run()
+      .unwrap()
+  }
+
+  #[test]
+  fn replay_is_deterministic() {
+    let soa = SOA::new(rr::Name::from_str("a.root-servers.net.").unwrap(),
+                       rr::Name::from_str("nstld.verisign-grs.com.").unwrap(),
+                       2026101800, 1800, 900, 604800, 86400);
+    let ns = rr::Name::from_str("a.root-servers.net.").unwrap();
+    let fixture = Fixture {
+      version: fixture::VERSION,
+      exchanges: vec![
+        root_answer(rr::RecordType::SOA, RData::SOA(soa)),
+        root_answer(rr::RecordType::NS, RData::NS(ns)),
+      ],
+    };
+    let path = env::temp_dir()
+      .join(format!("dns-delegation-check-replay-{}.json", std::process::id()));
+    fs::write(&path, serde_json::to_string(&fixture).unwrap()).unwrap();
+
+    let first = replay(&path);
+    let second = replay(&path);
+    fs::remove_file(&path).unwrap();
+
+    let serials: Vec<_> = first.records.get_record_set(&rr::Name::root(), rr::RecordType::SOA)
+      .iter()
+      .filter_map(|rdata| rdata.as_soa().map(|soa| soa.serial()))
+      .collect();
+    assert_eq!(serials, vec![2026101800]);
+    assert_eq!(first.findings, second.findings);
+  }
+}
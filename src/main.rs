@@ -1,26 +1,165 @@
-use std::str::FromStr;
+use std::env;
+use std::io;
+use std::path::Path;
+use std::process;
 
-use env_logger;
+use log::error;
 use trust_dns_client::rr;
 
-mod db;
-mod dns;
+use dns_delegation_check::{
+  daemon, diff, dot, findings, html, junit, nagios, prometheus, server, snapshot, trace, Checker,
+  QueryTransport, RecordDB,
+};
+
+mod options;
 
 fn main() {
   env_logger::init();
-  // Create record database.
-  let mut records = db::RecordDB::new();
 
-  // Bootstrap database with root hints.
-  records.add_root_hints(dns::root_hints());
+  let options = match options::parse(env::args().skip(1)) {
+    Ok(Some(options)) => options,
+    Ok(None) => {
+      print!("{}", options::USAGE);
+      return;
+    },
+    Err(e) => {
+      eprintln!("error: {}\n\n{}", e, options::USAGE);
+      process::exit(2);
+    },
+  };
+
+  let load = |path: &Path| match snapshot::load(path) {
+    Ok(records) => records,
+    Err(e) => {
+      eprintln!("error: can't load {}: {}", path.display(), e);
+      // Monitoring plugins can't tell what's wrong when the check fails.
+      if options.format == options::Format::Nagios {
+        process::exit(nagios::Status::Unknown as i32);
+      }
+      process::exit(1);
+    },
+  };
+
+  if options.serve {
+    if let Err(e) = server::run(&options.server_config, options.checks.clone()) {
+      eprintln!("error: can't serve on {}: {}", options.server_config.listen, e);
+      process::exit(1);
+    }
+    return;
+  }
+
+  if let Some((old, new)) = &options.diff {
+    let changes = diff::diff(&load(old), &load(new), &options.checks);
+    if options.json {
+      diff::dump_json(&changes);
+    } else {
+      diff::dump(&changes);
+    }
+    return;
+  }
+
+  // Everything but the names to check, and where to start from.
+  let checker = || {
+    let mut checker = Checker::new().checks(options.checks.clone());
+    if options.identify {
+      checker = checker.identify();
+    }
+    if options.check_axfr {
+      checker = checker.check_axfr();
+    }
+    if options.check_edns {
+      checker = checker.check_edns();
+    }
+    if options.check_recursion {
+      checker = checker.check_recursion(&options.recursion_probe);
+    }
+    checker
+  };
+
+  if options.daemon {
+    // Check each name on its own, with all of its types.
+    let mut domains: Vec<(rr::Name, Vec<rr::RecordType>)> = Vec::new();
+    for (name, rtype) in &options.targets {
+      match domains.iter_mut().find(|(domain, _)| domain == name) {
+        Some((_, rtypes)) => rtypes.push(*rtype),
+        None => domains.push((name.clone(), vec![*rtype])),
+      }
+    }
+    daemon::run(&domains, checker, &options.daemon_config);
+  }
 
-  records.add_answer_target(&rr::Name::from_str("google.com").unwrap(), rr::RecordType::AAAA);
-  records.add_answer_target(&rr::Name::from_str("cloudflare.com").unwrap(), rr::RecordType::A);
+  let mut checker = checker();
+  if let Some(path) = &options.load {
+    checker = checker.records(load(path));
+  }
+  for (name, rtype) in &options.targets {
+    checker = checker.target(name, *rtype);
+  }
+  if options.no_queries {
+    checker = checker.no_queries();
+  }
+  if let Some(path) = &options.pcap {
+    checker = checker.pcap(path);
+  }
+  if let Some(path) = &options.record {
+    checker = checker.transport(QueryTransport::Record(path.clone()));
+  }
+  if let Some(path) = &options.replay {
+    checker = checker.transport(QueryTransport::Replay(path.clone()));
+  }
 
-  // println!("{:#?}", records.find_closest_domain(&rr::Name::from_str("google.com.").unwrap()));
+  let save = |records: &RecordDB| {
+    if let Some(path) = &options.save {
+      if let Err(e) = snapshot::save(records, path) {
+        error!("Can't save {}: {}", path.display(), e);
+      }
+    }
+  };
 
-  records.action_loop();
-  records.dump();
+  let report = match checker.run_with(&save) {
+    Ok(report) => report,
+    Err(e) => {
+      eprintln!("error: {}", e);
+      if options.format == options::Format::Nagios {
+        process::exit(nagios::Status::Unknown as i32);
+      }
+      process::exit(1);
+    },
+  };
+  save(&report.records);
+  let (records, findings) = (&report.records, &report.findings);
 
-  //println!("{:#?}", records);
+  let mut out = io::stdout().lock();
+  let written = match options.format {
+    options::Format::Text => {
+      trace::dump(records, findings);
+      Ok(())
+    },
+    options::Format::Html => html::dump(&mut out, records, findings),
+    options::Format::Dump => {
+      records.dump();
+      findings::dump(records, findings);
+      Ok(())
+    },
+    options::Format::Dot => {
+      dot::dump(records);
+      Ok(())
+    },
+    options::Format::Nagios => match nagios::report(&mut out, records, findings, &options.thresholds) {
+      Ok(status) => process::exit(status as i32),
+      Err(e) => Err(e),
+    },
+    options::Format::Prometheus => prometheus::dump(&mut out, records, findings),
+    options::Format::Junit => {
+      junit::dump(records, findings);
+      Ok(())
+    },
+  };
+  if let Err(e) = written {
+    eprintln!("error: can't write report: {}", e);
+    if options.format == options::Format::Nagios {
+      process::exit(nagios::Status::Unknown as i32);
+    }
+    process::exit(1);
+  }
 }
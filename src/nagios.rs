@@ -0,0 +1,389 @@
+use std::fmt;
+use std::io::{self, Write};
+use std::time::Duration;
+
+use trust_dns_client::rr;
+
+use crate::db::{Health, RecordDB};
+use crate::findings::{Finding, Severity};
+
+/// Plugin status, as an exit code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Status {
+  Ok = 0,
+  Warning = 1,
+  Critical = 2,
+  Unknown = 3,
+}
+
+impl fmt::Display for Status {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Status::Ok => write!(f, "OK"),
+      Status::Warning => write!(f, "WARNING"),
+      Status::Critical => write!(f, "CRITICAL"),
+      Status::Unknown => write!(f, "UNKNOWN"),
+    }
+  }
+}
+
+/// Limits past which a zone is a problem. Unset limits aren't checked.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Limits {
+  /// Servers timing out, at or above.
+  pub timeouts: Option<usize>,
+  /// Lame servers, at or above.
+  pub lame: Option<usize>,
+  /// Slowest answer of any server, in milliseconds, at or above.
+  pub latency: Option<u64>,
+  /// Servers answering, below.
+  pub responding: Option<usize>,
+}
+
+impl Limits {
+  /// Set a limit from a NAME=VALUE argument.
+  pub fn set(&mut self, spec: &str) -> Result<(), String> {
+    let mut parts = spec.splitn(2, '=');
+    let name = parts.next().unwrap();
+    let value = parts.next()
+      .and_then(|value| value.parse::<u64>().ok())
+      .ok_or_else(|| format!("invalid threshold '{}', expected NAME=NUMBER", spec))?;
+
+    match name {
+      "timeouts" => self.timeouts = Some(value as usize),
+      "lame" => self.lame = Some(value as usize),
+      "latency" => self.latency = Some(value),
+      "responding" => self.responding = Some(value as usize),
+      _ => return Err(format!("unknown threshold '{}'", name)),
+    }
+    Ok(())
+  }
+}
+
+/// Warning and critical limits.
+#[derive(Debug, Clone, Copy)]
+pub struct Thresholds {
+  pub warning: Limits,
+  pub critical: Limits,
+}
+
+impl Default for Thresholds {
+  fn default() -> Thresholds {
+    Thresholds {
+      warning: Limits {
+        timeouts: Some(1),
+        lame: Some(1),
+        latency: Some(1000),
+        responding: None,
+      },
+      critical: Limits {
+        timeouts: None,
+        lame: None,
+        latency: Some(3000),
+        responding: Some(1),
+      },
+    }
+  }
+}
+
+/// Perfdata threshold, alerting at or above the limit.
+fn limit<T: fmt::Display>(limit: Option<T>) -> String {
+  limit.map(|limit| limit.to_string()).unwrap_or_default()
+}
+
+/// Perfdata threshold, alerting below the limit.
+fn at_least<T: fmt::Display>(limit: Option<T>) -> String {
+  limit.map(|limit| format!("{}:", limit)).unwrap_or_default()
+}
+
+/// Server counts of an answer zone.
+struct ZoneStats {
+  zone: rr::Name,
+  nameservers: usize,
+  responding: usize,
+  timeouts: usize,
+  lame: usize,
+  /// Slowest answer of any of the zone's servers, to any query.
+  latency: Option<Duration>,
+}
+
+impl ZoneStats {
+  fn new(record_db: &RecordDB, zone: &rr::Name) -> ZoneStats {
+    let mut stats = ZoneStats {
+      zone: zone.clone(),
+      nameservers: record_db.get_record_set(zone, rr::RecordType::NS).len(),
+      responding: 0,
+      timeouts: 0,
+      lame: 0,
+      latency: None,
+    };
+
+    for server in record_db.get_zone_servers(zone) {
+      match record_db.get_health(zone, &server) {
+        Health::Ok => stats.responding += 1,
+        Health::Lame => {
+          stats.responding += 1;
+          stats.lame += 1;
+        },
+        Health::TimeOut => stats.timeouts += 1,
+        Health::Unknown => (),
+      }
+      stats.latency = stats.latency.max(record_db.get_max_rtt(&server));
+    }
+    stats
+  }
+
+  fn latency_ms(&self) -> u64 {
+    self.latency.map_or(0, |rtt| rtt.as_millis() as u64)
+  }
+
+  /// Problems past the given limits.
+  fn problems(&self, limits: &Limits) -> Vec<String> {
+    let mut problems = Vec::new();
+    if limits.timeouts.is_some_and(|limit| self.timeouts >= limit) {
+      problems.push(format!("{} servers timed out", self.timeouts));
+    }
+    if limits.lame.is_some_and(|limit| self.lame >= limit) {
+      problems.push(format!("{} servers lame", self.lame));
+    }
+    if limits.latency.is_some_and(|limit| self.latency.is_some() && self.latency_ms() >= limit) {
+      problems.push(format!("slowest answer {} ms", self.latency_ms()));
+    }
+    if limits.responding.is_some_and(|limit| self.responding < limit) {
+      problems.push(format!("{} servers responding", self.responding));
+    }
+    problems
+  }
+
+  /// Performance data, in plugin format.
+  fn perfdata(&self, thresholds: &Thresholds) -> String {
+    let label = |what: &str| format!("'{} {}'", self.zone.to_string().replace('\'', "''"), what);
+    let (warning, critical) = (&thresholds.warning, &thresholds.critical);
+
+    [
+      format!("{}={};;;0", label("nameservers"), self.nameservers),
+      format!("{}={};{};{};0", label("responding"), self.responding,
+              at_least(warning.responding), at_least(critical.responding)),
+      format!("{}={};{};{};0", label("timeouts"), self.timeouts,
+              limit(warning.timeouts), limit(critical.timeouts)),
+      format!("{}={};{};{};0", label("lame"), self.lame,
+              limit(warning.lame), limit(critical.lame)),
+      format!("{}={}ms;{};{};0", label("latency"), self.latency_ms(),
+              limit(warning.latency), limit(critical.latency)),
+    ].join(" ")
+  }
+}
+
+/// Write a monitoring plugin status line with perfdata, for Nagios,
+/// Icinga and the like.
+///
+/// The status is the worst of the findings (errors are critical) and of
+/// each answer zone's server counts against the thresholds. Returns the
+/// status, to exit with.
+pub fn report(out: &mut impl Write, record_db: &RecordDB, findings: &[Finding],
+              thresholds: &Thresholds) -> io::Result<Status> {
+  let zones: Vec<_> = record_db.get_answer_zones().iter()
+    .map(|zone| ZoneStats::new(record_db, zone))
+    .collect();
+
+  if zones.is_empty() {
+    writeln!(out, "DELEGATION {} - no zones were resolved", Status::Unknown)?;
+    return Ok(Status::Unknown);
+  }
+
+  let mut status = Status::Ok;
+  let mut summary = Vec::new();
+
+  let errors = findings.iter().filter(|finding| finding.severity == Severity::Error).count();
+  let warnings = findings.iter().filter(|finding| finding.severity == Severity::Warning).count();
+  if errors > 0 {
+    status = status.max(Status::Critical);
+    summary.push(format!("{} errors", errors));
+  }
+  if warnings > 0 {
+    status = status.max(Status::Warning);
+    summary.push(format!("{} warnings", warnings));
+  }
+
+  for stats in &zones {
+    let mut problems = stats.problems(&thresholds.critical);
+    if !problems.is_empty() {
+      status = status.max(Status::Critical);
+    } else {
+      problems = stats.problems(&thresholds.warning);
+      if !problems.is_empty() {
+        status = status.max(Status::Warning);
+      }
+    }
+    if !problems.is_empty() {
+      summary.push(format!("{} {}", stats.zone, problems.join(", ")));
+    }
+  }
+
+  if summary.is_empty() {
+    let servers: usize = zones.iter().map(|stats| stats.responding).sum();
+    summary.push(format!("{} zones, {} servers responding", zones.len(), servers));
+  }
+
+  let perfdata: Vec<_> = zones.iter().map(|stats| stats.perfdata(thresholds)).collect();
+  writeln!(out, "DELEGATION {} - {} | {}", status, summary.join("; "), perfdata.join(" "))?;
+  Ok(status)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::net::IpAddr;
+  use std::str::FromStr;
+  use std::time::SystemTime;
+
+  use trust_dns_client::op::ResponseCode;
+
+  use super::*;
+  use crate::db::{LoggedQuery, QueryResponse, REntry, Transport};
+  use crate::findings::Check;
+
+  fn name(name: &str) -> rr::Name {
+    rr::Name::from_str(name).unwrap()
+  }
+
+  #[test]
+  fn limits_parsed() {
+    let mut limits = Limits::default();
+    limits.set("timeouts=2").unwrap();
+    limits.set("lame=0").unwrap();
+    limits.set("latency=500").unwrap();
+    limits.set("responding=3").unwrap();
+    assert_eq!(limits.timeouts, Some(2));
+    assert_eq!(limits.lame, Some(0));
+    assert_eq!(limits.latency, Some(500));
+    assert_eq!(limits.responding, Some(3));
+
+    assert_eq!(limits.set("latency"),
+               Err("invalid threshold 'latency', expected NAME=NUMBER".to_string()));
+    assert_eq!(limits.set("latency=fast"),
+               Err("invalid threshold 'latency=fast', expected NAME=NUMBER".to_string()));
+    assert_eq!(limits.set("latency=-1"),
+               Err("invalid threshold 'latency=-1', expected NAME=NUMBER".to_string()));
+    assert_eq!(limits.set("ttl=300"), Err("unknown threshold 'ttl'".to_string()));
+  }
+
+  /// Database with www.example.com referred from the root, served by
+  /// a.root-servers.net, to example.com, served by ns1 and ns2.
+  ///
+  /// The root server and ns1 answer for their zones, ns2 answers as given,
+  /// and ns1 is the slowest server, taking the given time.
+  fn record_db(ns2: Health, latency_ms: u64) -> RecordDB {
+    let mut db = RecordDB::new();
+    let www = name("www.example.com.");
+    db.add_answer_target(&www, rr::RecordType::A);
+    db.add_target(&www, rr::RecordType::A, &name("example.com."));
+
+    // NS and address records, and the server that gave them.
+    let servers = [(".", "a.root-servers.net.", "198.41.0.4", "198.41.0.4"),
+                   ("example.com.", "ns1.example.com.", "192.0.2.1", "192.0.2.1"),
+                   ("example.com.", "ns2.example.com.", "192.0.2.2", "192.0.2.1")];
+    for (zone, ns, address, server) in &servers {
+      let server: IpAddr = server.parse().unwrap();
+      db.add_record(&rr::Record::from_rdata(name(zone), 3600, rr::RData::NS(name(ns))), server);
+      db.add_record(&rr::Record::from_rdata(name(ns), 3600,
+                                            rr::RData::A(address.parse().unwrap())), server);
+    }
+    let soa = rr::RData::SOA(rr::rdata::SOA::new(
+      name("ns1.example.com."), name("hostmaster.example.com."), 1, 3600, 600, 86400, 300));
+    let health = [(".", "198.41.0.4", Health::Ok),
+                  ("example.com.", "192.0.2.1", Health::Ok),
+                  ("example.com.", "192.0.2.2", ns2)];
+    for (zone, ip, health) in &health {
+      let entry = match health {
+        Health::Ok => REntry::Entries(vec![(soa.clone(), 3600)]),
+        Health::Lame => REntry::NoEntry,
+        Health::TimeOut | Health::Unknown => REntry::TimeOut,
+      };
+      db.add_rentry(&name(zone), entry, rr::RecordType::SOA, ip.parse().unwrap());
+    }
+
+    db.add_logged_query(LoggedQuery {
+      name: www,
+      rtype: rr::RecordType::A,
+      server: "192.0.2.1".parse().unwrap(),
+      transport: Transport::Udp,
+      start: SystemTime::now(),
+      attempts: 1,
+      response: Some(QueryResponse {
+        rtt: Duration::from_millis(latency_ms),
+        size: 100,
+        rcode: ResponseCode::NoError,
+        flags: "qr aa".to_string(),
+      }),
+    });
+    db
+  }
+
+  fn run(record_db: &RecordDB, findings: &[Finding]) -> (Status, String) {
+    let mut out = Vec::new();
+    let status = report(&mut out, record_db, findings, &Thresholds::default()).unwrap();
+    (status, String::from_utf8(out).unwrap())
+  }
+
+  fn summary(output: &str) -> &str {
+    output.split(" | ").next().unwrap()
+  }
+
+  #[test]
+  fn healthy_zones_ok() {
+    let (status, output) = run(&record_db(Health::Ok, 50), &[]);
+    assert_eq!(status, Status::Ok);
+    assert_eq!(summary(&output), "DELEGATION OK - 2 zones, 3 servers responding");
+    assert!(output.ends_with(" 'example.com. nameservers'=2;;;0 'example.com. responding'=2;;1:;0 \
+                              'example.com. timeouts'=0;1;;0 'example.com. lame'=0;1;;0 \
+                              'example.com. latency'=50ms;1000;3000;0\n"));
+  }
+
+  #[test]
+  fn server_problems_warn() {
+    let (status, output) = run(&record_db(Health::TimeOut, 50), &[]);
+    assert_eq!(status, Status::Warning);
+    assert_eq!(summary(&output), "DELEGATION WARNING - example.com. 1 servers timed out");
+
+    let (status, output) = run(&record_db(Health::Lame, 1500), &[]);
+    assert_eq!(status, Status::Warning);
+    assert_eq!(summary(&output),
+               "DELEGATION WARNING - example.com. 1 servers lame, slowest answer 1500 ms");
+  }
+
+  #[test]
+  fn critical_limits_win() {
+    // Critical problems are shown alone, without the warnings.
+    let (status, output) = run(&record_db(Health::TimeOut, 3000), &[]);
+    assert_eq!(status, Status::Critical);
+    assert_eq!(summary(&output), "DELEGATION CRITICAL - example.com. slowest answer 3000 ms");
+  }
+
+  #[test]
+  fn findings_count() {
+    let db = record_db(Health::Ok, 50);
+    let warning = Finding::new(Severity::Warning, Check::Alias, &name("example.com."), None,
+                               "warning".to_string());
+    let error = Finding::new(Severity::Error, Check::Alias, &name("example.com."), None,
+                             "error".to_string());
+    let info = Finding::new(Severity::Info, Check::Alias, &name("example.com."), None,
+                            "info".to_string());
+
+    let (status, output) = run(&db, std::slice::from_ref(&info));
+    assert_eq!(status, Status::Ok);
+    assert_eq!(summary(&output), "DELEGATION OK - 2 zones, 3 servers responding");
+    let (status, output) = run(&db, &[info.clone(), warning.clone()]);
+    assert_eq!(status, Status::Warning);
+    assert_eq!(summary(&output), "DELEGATION WARNING - 1 warnings");
+    let (status, output) = run(&db, &[info, warning, error]);
+    assert_eq!(status, Status::Critical);
+    assert_eq!(summary(&output), "DELEGATION CRITICAL - 1 errors; 1 warnings");
+  }
+
+  #[test]
+  fn nothing_resolved_unknown() {
+    let (status, output) = run(&RecordDB::new(), &[]);
+    assert_eq!(status, Status::Unknown);
+    assert_eq!(output, "DELEGATION UNKNOWN - no zones were resolved\n");
+  }
+}
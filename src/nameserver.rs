@@ -0,0 +1,172 @@
+use std::collections::BTreeSet;
+
+use trust_dns_client::rr;
+
+use crate::db::{REntry, RServer, RecordDB};
+use crate::findings::{Check, Finding, Severity};
+
+/// Check the NS names of every zone holding an answer target point at
+/// usable hosts.
+///
+/// Both the parent side (referral) and child side (authoritative) NS sets
+/// are checked. NS names must not be aliases (RFC 2181, section 10.3), must
+/// exist, and must have at least one A or AAAA record.
+pub fn check_ns_targets(record_db: &RecordDB) -> Vec<Finding> {
+  let mut findings = Vec::new();
+
+  for zone in record_db.get_target_zones() {
+    let sides = vec![
+      ("parent", record_db.get_parent_ns(&zone)),
+      ("child", record_db.get_child_ns(&zone)),
+    ];
+
+    for (side, names) in sides {
+      for ns in names {
+        if let Some((severity, problem)) = check_ns_target(record_db, &ns) {
+          findings.push(Finding::new(
+            severity, Check::NsTarget, &zone, None,
+            format!("{} side NS {} {}", side, ns, problem)
+          ));
+        }
+      }
+    }
+  }
+
+  findings
+}
+
+/// Servers of the zone each address of an NS name was resolved in.
+///
+/// Referrals on the way carry glue for the name, which says nothing about
+/// whether the name itself resolves, so only these servers' answers count.
+fn own_servers(record_db: &RecordDB, ns: &rr::Name) -> BTreeSet<RServer> {
+  [rr::RecordType::A, rr::RecordType::AAAA].iter()
+    .filter_map(|rtype| record_db.get_target_zone(ns, *rtype))
+    .flat_map(|zone| record_db.get_zone_servers(&zone))
+    .collect()
+}
+
+/// Check a single NS name, returning the problem with it if any.
+fn check_ns_target(record_db: &RecordDB, ns: &rr::Name) -> Option<(Severity, String)> {
+  let servers = own_servers(record_db, ns);
+  let records: Vec<_> = record_db.get_records(ns).into_iter()
+    .filter(|(server, _)| servers.contains(server))
+    .map(|(_, entries)| entries)
+    .collect();
+
+  let is_alias = records.iter()
+    .any(|entries| matches!(entries.get(&rr::RecordType::CNAME), Some(REntry::Entries(_))));
+  if is_alias {
+    return Some((Severity::Error, "is an alias (CNAME)".to_string()));
+  }
+
+  let negatives = |rtype| record_db.get_negative_answers().get(&(ns.clone(), rtype))
+    .into_iter()
+    .flat_map(|answers| answers.iter())
+    .filter(|(server, _)| servers.contains(server))
+    .map(|(_, negative)| negative);
+  let address_types = [rr::RecordType::A, rr::RecordType::AAAA];
+  if address_types.iter().any(|rtype| negatives(*rtype).any(|negative| negative.nxdomain)) {
+    return Some((Severity::Error, "doesn't exist (NXDOMAIN)".to_string()));
+  }
+
+  // Only complain about missing addresses once both lookups were answered.
+  let has_address = records.iter()
+    .any(|entries| address_types.iter()
+         .any(|rtype| matches!(entries.get(rtype), Some(REntry::Entries(_)))));
+  let both_answered = address_types.iter().all(|rtype| negatives(*rtype).next().is_some());
+  if !has_address && both_answered {
+    return Some((Severity::Error, "has no A or AAAA records".to_string()));
+  }
+
+  None
+}
+
+#[cfg(test)]
+mod tests {
+  use std::net::IpAddr;
+  use std::str::FromStr;
+
+  use super::*;
+  use crate::db::NegativeAnswer;
+
+  fn name(name: &str) -> rr::Name {
+    rr::Name::from_str(name).unwrap()
+  }
+
+  fn ip(ip: &str) -> IpAddr {
+    ip.parse().unwrap()
+  }
+
+  /// Database with example.net served from 192.0.2.53, and the addresses of
+  /// ns1.example.net looked up there.
+  ///
+  /// The parent's servers at 198.51.100.1 gave glue for ns1.example.net.
+  fn record_db() -> RecordDB {
+    let mut db = RecordDB::new();
+    let zone = name("example.net.");
+    db.add_record(&rr::Record::from_rdata(zone.clone(), 3600,
+      rr::RData::NS(name("ns.example.net."))), ip("192.0.2.53"));
+    db.add_record(&rr::Record::from_rdata(name("ns.example.net."), 3600,
+      rr::RData::A("192.0.2.53".parse().unwrap())), ip("192.0.2.53"));
+    db.add_record(&rr::Record::from_rdata(name("ns1.example.net."), 3600,
+      rr::RData::A("192.0.2.1".parse().unwrap())), ip("198.51.100.1"));
+    for rtype in &[rr::RecordType::A, rr::RecordType::AAAA] {
+      db.add_address_target(&name("ns1.example.net."), *rtype);
+      db.add_target(&name("ns1.example.net."), *rtype, &zone);
+    }
+    db
+  }
+
+  fn add_negative(db: &mut RecordDB, rtype: rr::RecordType, nxdomain: bool) {
+    db.add_negative_answer(&name("ns1.example.net."), rtype, ip("192.0.2.53"),
+                           NegativeAnswer { nxdomain, authority: Vec::new() });
+  }
+
+  fn problem(db: &RecordDB) -> Option<String> {
+    check_ns_target(db, &name("ns1.example.net.")).map(|(_, problem)| problem)
+  }
+
+  #[test]
+  fn resolving_name_passes() {
+    let mut db = record_db();
+    db.add_record(&rr::Record::from_rdata(name("ns1.example.net."), 3600,
+      rr::RData::A("192.0.2.1".parse().unwrap())), ip("192.0.2.53"));
+    add_negative(&mut db, rr::RecordType::AAAA, false);
+    assert_eq!(problem(&db), None);
+  }
+
+  #[test]
+  fn alias_flagged() {
+    let mut db = record_db();
+    db.add_record(&rr::Record::from_rdata(name("ns1.example.net."), 3600,
+      rr::RData::CNAME(name("host.example.net."))), ip("192.0.2.53"));
+    assert_eq!(problem(&db), Some("is an alias (CNAME)".to_string()));
+  }
+
+  #[test]
+  fn missing_name_flagged_despite_glue() {
+    let mut db = record_db();
+    add_negative(&mut db, rr::RecordType::A, true);
+    assert_eq!(problem(&db), Some("doesn't exist (NXDOMAIN)".to_string()));
+  }
+
+  #[test]
+  fn missing_addresses_flagged_despite_glue() {
+    let mut db = record_db();
+    add_negative(&mut db, rr::RecordType::A, false);
+    assert_eq!(problem(&db), None);
+    add_negative(&mut db, rr::RecordType::AAAA, false);
+    assert_eq!(problem(&db), Some("has no A or AAAA records".to_string()));
+  }
+
+  #[test]
+  fn other_servers_ignored() {
+    let mut db = record_db();
+    db.add_record(&rr::Record::from_rdata(name("ns1.example.net."), 3600,
+      rr::RData::CNAME(name("host.example.net."))), ip("198.51.100.1"));
+    db.add_negative_answer(&name("ns1.example.net."), rr::RecordType::A, ip("198.51.100.1"),
+                           NegativeAnswer { nxdomain: true, authority: Vec::new() });
+    assert_eq!(problem(&db), None);
+  }
+}
@@ -0,0 +1,154 @@
+use trust_dns_client::rr::{self, RData};
+
+use crate::db::{REntry, RecordDB};
+use crate::findings::{Check, CheckConfig, Finding, Severity};
+
+/// Check SOA values used for negative caching (RFC 2308).
+///
+/// The SOA minimum and SOA TTL of every answer zone must be within the
+/// configured ranges, and every negative answer must include the SOA with
+/// a TTL of the lower of the two.
+pub fn check_negative_caching(record_db: &RecordDB, config: &CheckConfig) -> Vec<Finding> {
+  let mut findings = Vec::new();
+
+  for zone in record_db.get_answer_zones() {
+    for (server, entries) in record_db.get_records(&zone) {
+      let items = match entries.get(&rr::RecordType::SOA) {
+        Some(REntry::Entries(items)) => items,
+        _ => continue,
+      };
+
+      for (rdata, ttl) in items {
+        let soa = match rdata {
+          RData::SOA(soa) => soa,
+          _ => continue,
+        };
+
+        let minimum = soa.minimum();
+        if minimum < config.soa_minimum.0 || minimum > config.soa_minimum.1 {
+          findings.push(Finding::new(
+            Severity::Warning, Check::NegativeCaching, &zone, Some(&server),
+            format!("SOA minimum {} outside of {} to {}",
+                    minimum, config.soa_minimum.0, config.soa_minimum.1)
+          ));
+        }
+
+        if *ttl < config.soa_ttl.0 || *ttl > config.soa_ttl.1 {
+          findings.push(Finding::new(
+            Severity::Warning, Check::NegativeCaching, &zone, Some(&server),
+            format!("SOA TTL {} outside of {} to {}", ttl, config.soa_ttl.0, config.soa_ttl.1)
+          ));
+        }
+      }
+    }
+  }
+
+  for ((name, rtype), servers) in record_db.get_negative_answers() {
+    for (server, negative) in servers {
+      let kind = if negative.nxdomain { "NXDOMAIN" } else { "NODATA" };
+
+      let soa_record = negative.authority.iter()
+        .find(|rec| rec.rr_type() == rr::RecordType::SOA);
+      let (soa_record, soa) = match soa_record.map(|rec| (rec, rec.rdata())) {
+        Some((rec, RData::SOA(soa))) => (rec, soa),
+        _ => {
+          findings.push(Finding::new(
+            Severity::Error, Check::NegativeCaching, name, Some(server),
+            format!("{} for {} has no SOA in the authority section", kind, rtype)
+          ));
+          continue;
+        },
+      };
+
+      // The negative TTL is the lower of the SOA TTL and minimum, prefer
+      // the SOA TTL this server gave when asked for it directly.
+      let zone = soa_record.name();
+      let soa_ttl = record_db.get_ttls(zone, rr::RecordType::SOA).get(server).cloned()
+        .unwrap_or_else(|| soa_record.ttl());
+      let expected = soa_ttl.min(soa.minimum());
+
+      if soa_record.ttl() != expected {
+        findings.push(Finding::new(
+          Severity::Warning, Check::NegativeCaching, name, Some(server),
+          format!("{} for {} has SOA TTL {}, expected {}", kind, rtype, soa_record.ttl(), expected)
+        ));
+      }
+    }
+  }
+
+  findings
+}
+
+#[cfg(test)]
+mod tests {
+  use std::net::IpAddr;
+  use std::str::FromStr;
+
+  use trust_dns_client::rr::rdata::SOA;
+
+  use super::*;
+  use crate::db::NegativeAnswer;
+
+  const SERVER: &str = "192.0.2.1";
+
+  fn name(name: &str) -> rr::Name {
+    rr::Name::from_str(name).unwrap()
+  }
+
+  fn soa(ttl: u32, minimum: u32) -> rr::Record {
+    let soa = SOA::new(name("ns1.example.com."), name("hostmaster.example.com."),
+                       1, 7200, 3600, 1209600, minimum);
+    rr::Record::from_rdata(name("example.com."), ttl, RData::SOA(soa))
+  }
+
+  /// example.com answered by one server, with the SOA given.
+  fn zone(soa_record: &rr::Record) -> RecordDB {
+    let mut db = RecordDB::new();
+    db.add_answer_target(&name("example.com."), rr::RecordType::SOA);
+    db.add_target(&name("example.com."), rr::RecordType::SOA, &name("example.com."));
+    db.add_record(soa_record, SERVER.parse::<IpAddr>().unwrap());
+    db
+  }
+
+  fn add_negative(db: &mut RecordDB, authority: Vec<rr::Record>) {
+    let negative = NegativeAnswer { nxdomain: true, authority };
+    db.add_negative_answer(&name("missing.example.com."), rr::RecordType::A,
+                           SERVER.parse().unwrap(), negative);
+  }
+
+  fn messages(db: &RecordDB) -> Vec<String> {
+    check_negative_caching(db, &CheckConfig::default()).into_iter()
+      .map(|finding| finding.message)
+      .collect()
+  }
+
+  #[test]
+  fn sane_soa_passes() {
+    let mut db = zone(&soa(3600, 900));
+    add_negative(&mut db, vec![soa(900, 900)]);
+    assert!(messages(&db).is_empty());
+  }
+
+  #[test]
+  fn soa_out_of_range() {
+    let db = zone(&soa(60, 604800));
+    assert_eq!(messages(&db), vec![
+      "SOA minimum 604800 outside of 300 to 86400".to_string(),
+      "SOA TTL 60 outside of 300 to 86400".to_string(),
+    ]);
+  }
+
+  #[test]
+  fn negative_answer_without_soa() {
+    let mut db = zone(&soa(3600, 900));
+    add_negative(&mut db, Vec::new());
+    assert_eq!(messages(&db), vec!["NXDOMAIN for A has no SOA in the authority section"]);
+  }
+
+  #[test]
+  fn negative_answer_soa_ttl() {
+    let mut db = zone(&soa(3600, 900));
+    add_negative(&mut db, vec![soa(3600, 900)]);
+    assert_eq!(messages(&db), vec!["NXDOMAIN for A has SOA TTL 3600, expected 900"]);
+  }
+}
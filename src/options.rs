@@ -0,0 +1,397 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use trust_dns_client::rr;
+
+use dns_delegation_check::daemon;
+use dns_delegation_check::findings::CheckConfig;
+use dns_delegation_check::nagios::Thresholds;
+use dns_delegation_check::server;
+use dns_delegation_check::{policy, zonefile};
+
+pub const USAGE: &str = "\
+Usage: dns-delegation-check [OPTIONS] NAME[/TYPE]...
+       dns-delegation-check [OPTIONS] --daemon NAME[/TYPE]...
+       dns-delegation-check [OPTIONS] --diff OLD NEW
+       dns-delegation-check [OPTIONS] --serve ADDR
+
+Check the delegation of each NAME, resolving TYPE (default A) from the root.
+Names can be left out when loading a saved database, checking a policy or
+comparing zone files. With --daemon, keep checking the names, alerting when
+their findings change. With --diff, compare two saved databases instead.
+With --serve, take checks over an HTTP API instead.
+
+Options:
+  --check-axfr               Check whether answer zones can be transferred
+  --check-edns               Run EDNS compliance tests against servers
+  --check-recursion          Check whether authoritative servers recurse
+  --identify                 Ask servers for their NSID and CHAOS identifiers
+  --soa-minimum MIN:MAX      Allowed SOA minimum values (default 300:86400)
+  --soa-ttl MIN:MAX          Allowed SOA TTLs (default 300:86400)
+  --slow-query MS            Flag servers taking this long to answer
+                             (default 1000)
+  --policy FILE              Check delegations against the expected state in
+                             a TOML file, and check each of its zones
+  --zone-file ZONE=FILE      Compare what the servers of ZONE serve with its
+                             master file: apex SOA/NS, and in-zone NS addresses
+  --zone-name NAME           Also compare every RRset of NAME, in the zone
+                             file whose zone it's in
+  --recursion-probe NAME     Name to ask for when checking recursion
+                             (default www.example.com.)
+  --format FORMAT            Output format: text (default) for a dig +trace
+                             style report, html for a self-contained report,
+                             dump for the whole database, dot for a
+                             Graphviz graph of the delegations, nagios for
+                             a monitoring plugin status line, prometheus
+                             for metrics in the Prometheus text format, or
+                             junit for a JUnit XML report of the checks
+  --warning NAME=VALUE       Monitoring plugin warning threshold, per answer
+                             zone: timeouts, lame (servers, at or above),
+                             latency (ms, at or above) or responding
+                             (servers, below); defaults timeouts=1, lame=1,
+                             latency=1000
+  --critical NAME=VALUE      Monitoring plugin critical threshold, as above;
+                             defaults latency=3000, responding=1
+  --load FILE                Start from a saved database, resuming its queries
+  --save FILE                Save the database to FILE, after every round of
+                             queries and once finished
+  --pcap FILE                Write every query and response to a pcap file
+  --record FILE              Record every response to a fixture file
+  --replay FILE              Answer queries from a recorded fixture file,
+                             instead of sending them
+  --no-queries               Only analyse the loaded database, sending no
+                             queries (needs --load)
+  --daemon                   Check the names every interval, forever, and
+                             alert when the findings of a name change
+  --interval SECS            Time between checks (default 300, with --daemon)
+  --syslog                   Also send alerts to syslog (with --daemon)
+  --webhook URL              Also POST alerts as JSON to URL (with --daemon)
+  --serve ADDR               Serve an HTTP API on ADDR (such as
+                             127.0.0.1:8053): POST /checks to submit a check,
+                             GET /checks/ID for its status and
+                             GET /checks/ID/report for its JSON report
+  --workers N                Checks run at the same time (default 4, with
+                             --serve)
+  --queue N                  Checks waiting for a worker before more are
+                             refused (default 100, with --serve)
+  --diff OLD NEW             Show changes between two saved databases
+  --json                     Show changes as JSON (with --diff)
+  -h, --help                 Show this help
+";
+
+/// How to show the results.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+  /// Referral chain of each answer target, with findings inline.
+  Text,
+  /// Self-contained HTML report.
+  Html,
+  /// The record database, then findings.
+  Dump,
+  /// Graphviz DOT graph of the delegations.
+  Dot,
+  /// Monitoring plugin status line and perfdata.
+  Nagios,
+  /// Prometheus metrics.
+  Prometheus,
+  /// JUnit XML report, a test case per check.
+  Junit,
+}
+
+impl FromStr for Format {
+  type Err = String;
+
+  fn from_str(format: &str) -> Result<Format, String> {
+    match format {
+      "text" => Ok(Format::Text),
+      "html" => Ok(Format::Html),
+      "dump" => Ok(Format::Dump),
+      "dot" => Ok(Format::Dot),
+      "nagios" => Ok(Format::Nagios),
+      "prometheus" => Ok(Format::Prometheus),
+      "junit" => Ok(Format::Junit),
+      _ => Err(format!("unknown format '{}'", format)),
+    }
+  }
+}
+
+/// Command line options.
+#[derive(Debug, Clone)]
+pub struct Options {
+  /// Names and record types to resolve.
+  pub targets: Vec<(rr::Name, rr::RecordType)>,
+  /// Check whether answer zones can be transferred by anyone.
+  pub check_axfr: bool,
+  /// Run EDNS compliance tests against authoritative servers.
+  pub check_edns: bool,
+  /// Check whether authoritative servers are open resolvers.
+  pub check_recursion: bool,
+  /// Ask servers to identify themselves.
+  pub identify: bool,
+  /// Unrelated name to ask for when checking recursion.
+  pub recursion_probe: rr::Name,
+  /// Settings for checks.
+  pub checks: CheckConfig,
+  /// How to show the results.
+  pub format: Format,
+  /// Monitoring plugin thresholds.
+  pub thresholds: Thresholds,
+  /// Saved database to start from.
+  pub load: Option<PathBuf>,
+  /// File to save the database to.
+  pub save: Option<PathBuf>,
+  /// File to capture queries and responses to.
+  pub pcap: Option<PathBuf>,
+  /// Fixture file to record responses to.
+  pub record: Option<PathBuf>,
+  /// Fixture file to answer queries from.
+  pub replay: Option<PathBuf>,
+  /// Don't send any queries, only analyse the loaded database.
+  pub no_queries: bool,
+  /// Keep checking the targets, alerting on changes.
+  pub daemon: bool,
+  /// Interval and alerting of the daemon.
+  pub daemon_config: daemon::Config,
+  /// Serve an HTTP API for checks.
+  pub serve: bool,
+  /// Address and workers of the HTTP API.
+  pub server_config: server::Config,
+  /// Saved databases to compare, instead of checking names.
+  pub diff: Option<(PathBuf, PathBuf)>,
+  /// Show changes as JSON.
+  pub json: bool,
+}
+
+impl Default for Options {
+  fn default() -> Options {
+    Options {
+      targets: Vec::new(),
+      check_axfr: false,
+      check_edns: false,
+      check_recursion: false,
+      identify: false,
+      recursion_probe: rr::Name::from_str("www.example.com.").unwrap(),
+      checks: CheckConfig::default(),
+      format: Format::Text,
+      thresholds: Thresholds::default(),
+      load: None,
+      save: None,
+      pcap: None,
+      record: None,
+      replay: None,
+      no_queries: false,
+      daemon: false,
+      daemon_config: daemon::Config::default(),
+      serve: false,
+      server_config: server::Config::default(),
+      diff: None,
+      json: false,
+    }
+  }
+}
+
+/// Parse a domain name argument.
+fn parse_name(arg: &str) -> Result<rr::Name, String> {
+  rr::Name::from_str(arg).map_err(|e| format!("invalid name '{}': {}", arg, e))
+}
+
+/// Parse a NAME[/TYPE] target argument.
+fn parse_target(arg: &str) -> Result<(rr::Name, rr::RecordType), String> {
+  let mut parts = arg.splitn(2, '/');
+  let name = parse_name(parts.next().unwrap())?;
+  let rtype = match parts.next() {
+    Some(rtype) => rr::RecordType::from_str(&rtype.to_uppercase())
+      .map_err(|e| format!("invalid record type '{}': {}", rtype, e))?,
+    None => rr::RecordType::A,
+  };
+  Ok((name, rtype))
+}
+
+/// Parse a MIN:MAX range argument.
+fn parse_range(arg: &str) -> Result<(u32, u32), String> {
+  let mut parts = arg.splitn(2, ':');
+  let min = parts.next().unwrap().parse::<u32>();
+  let max = parts.next().map(|max| max.parse::<u32>());
+  match (min, max) {
+    (Ok(min), Some(Ok(max))) if min <= max => Ok((min, max)),
+    _ => Err(format!("invalid range '{}', expected MIN:MAX", arg)),
+  }
+}
+
+/// Parse a positive count argument.
+fn parse_count(arg: &str) -> Result<usize, String> {
+  arg.parse::<usize>().ok().filter(|count| *count > 0)
+    .ok_or_else(|| format!("invalid count '{}', expected N", arg))
+}
+
+/// Get the value following an option.
+fn value<I: Iterator<Item = String>>(args: &mut I, option: &str) -> Result<String, String> {
+  args.next().ok_or_else(|| format!("{} needs a value", option))
+}
+
+/// Parse command line arguments, excluding the program name.
+///
+/// Returns Ok(None) when help was asked for.
+pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Option<Options>, String> {
+  let mut options = Options::default();
+  let mut zone_files = Vec::new();
+  let mut zone_names = Vec::new();
+  let mut interval_set = false;
+  let mut pool_set = false;
+
+  while let Some(arg) = args.next() {
+    match arg.as_str() {
+      "-h" | "--help" => return Ok(None),
+      "--check-axfr" => options.check_axfr = true,
+      "--check-edns" => options.check_edns = true,
+      "--check-recursion" => options.check_recursion = true,
+      "--identify" => options.identify = true,
+      "--recursion-probe" =>
+        options.recursion_probe = parse_name(&value(&mut args, &arg)?)?,
+      "--soa-minimum" =>
+        options.checks.soa_minimum = parse_range(&value(&mut args, &arg)?)?,
+      "--soa-ttl" =>
+        options.checks.soa_ttl = parse_range(&value(&mut args, &arg)?)?,
+      "--slow-query" => {
+        let ms = value(&mut args, &arg)?;
+        let ms = ms.parse::<u64>().map_err(|_| format!("invalid time '{}', expected MS", ms))?;
+        options.checks.slow_query = Duration::from_millis(ms);
+      },
+      "--policy" => {
+        let path = PathBuf::from(value(&mut args, &arg)?);
+        let policy = policy::load(&path)
+          .map_err(|e| format!("can't load policy {}: {}", path.display(), e))?;
+        options.checks.policy = Some(policy);
+      },
+      "--zone-file" => {
+        let spec = value(&mut args, &arg)?;
+        let mut parts = spec.splitn(2, '=');
+        let zone = parse_name(parts.next().unwrap())?;
+        let path = parts.next()
+          .ok_or_else(|| format!("invalid zone file '{}', expected ZONE=FILE", spec))?;
+        zone_files.push((zone, PathBuf::from(path)));
+      },
+      "--zone-name" => zone_names.push(parse_name(&value(&mut args, &arg)?)?),
+      "--format" => options.format = value(&mut args, &arg)?.parse()?,
+      "--warning" => options.thresholds.warning.set(&value(&mut args, &arg)?)?,
+      "--critical" => options.thresholds.critical.set(&value(&mut args, &arg)?)?,
+      "--load" => options.load = Some(PathBuf::from(value(&mut args, &arg)?)),
+      "--save" => options.save = Some(PathBuf::from(value(&mut args, &arg)?)),
+      "--pcap" => options.pcap = Some(PathBuf::from(value(&mut args, &arg)?)),
+      "--record" => options.record = Some(PathBuf::from(value(&mut args, &arg)?)),
+      "--replay" => options.replay = Some(PathBuf::from(value(&mut args, &arg)?)),
+      "--no-queries" => options.no_queries = true,
+      "--daemon" => options.daemon = true,
+      "--interval" => {
+        let secs = value(&mut args, &arg)?;
+        let secs = secs.parse::<u64>().ok().filter(|secs| *secs > 0)
+          .ok_or_else(|| format!("invalid interval '{}', expected SECS", secs))?;
+        options.daemon_config.interval = Duration::from_secs(secs);
+        interval_set = true;
+      },
+      "--syslog" => options.daemon_config.syslog = true,
+      "--webhook" => options.daemon_config.webhook = Some(value(&mut args, &arg)?),
+      "--serve" => {
+        options.server_config.listen = value(&mut args, &arg)?;
+        options.serve = true;
+      },
+      "--workers" => {
+        options.server_config.workers = parse_count(&value(&mut args, &arg)?)?;
+        pool_set = true;
+      },
+      "--queue" => {
+        options.server_config.queue = parse_count(&value(&mut args, &arg)?)?;
+        pool_set = true;
+      },
+      "--diff" => {
+        let old = PathBuf::from(value(&mut args, &arg)?);
+        let new = PathBuf::from(value(&mut args, &arg)?);
+        options.diff = Some((old, new));
+      },
+      "--json" => options.json = true,
+      _ if arg.starts_with('-') => return Err(format!("unknown option '{}'", arg)),
+      _ => options.targets.push(parse_target(&arg)?),
+    }
+  }
+
+  for name in &zone_names {
+    if !zone_files.iter().any(|(zone, _)| zone.zone_of(name)) {
+      return Err(format!("{} isn't in any --zone-file zone", name));
+    }
+  }
+  for (zone, path) in &zone_files {
+    let names: Vec<_> = zone_names.iter().filter(|name| zone.zone_of(name)).cloned().collect();
+    let zone_file = zonefile::load(zone, path, &names)
+      .map_err(|e| format!("can't load zone file {}: {}", path.display(), e))?;
+    options.checks.zone_files.push(zone_file);
+  }
+
+  if !options.daemon && (interval_set || options.daemon_config.syslog
+                         || options.daemon_config.webhook.is_some()) {
+    return Err("--interval, --syslog and --webhook need --daemon".to_string());
+  }
+
+  if !options.serve && pool_set {
+    return Err("--workers and --queue need --serve".to_string());
+  }
+  if options.serve {
+    if !options.targets.is_empty() || options.load.is_some() || options.save.is_some()
+        || options.no_queries || options.pcap.is_some() || options.record.is_some()
+        || options.replay.is_some() || options.daemon || options.diff.is_some()
+        || options.checks.policy.is_some() || !options.checks.zone_files.is_empty() {
+      return Err("--serve can't be used with names, --load, --save, --no-queries, --pcap, \
+                  --record, --replay, --daemon, --diff, --policy or --zone-file".to_string());
+    }
+    if options.format != Format::Text || options.json {
+      return Err("--serve only answers with JSON, it can't be used with --format or --json"
+                 .to_string());
+    }
+    return Ok(Some(options));
+  }
+
+  if options.diff.is_some() {
+    if !options.targets.is_empty() || options.load.is_some() || options.daemon {
+      return Err("--diff can't be used with names, --load or --daemon".to_string());
+    }
+    return Ok(Some(options));
+  }
+  if options.json {
+    return Err("--json needs --diff".to_string());
+  }
+
+  if options.targets.is_empty() && options.load.is_none() && options.checks.policy.is_none()
+      && options.checks.zone_files.is_empty() {
+    return Err("no names to check".to_string());
+  }
+  if options.daemon {
+    if options.targets.is_empty() {
+      return Err("--daemon needs names to check".to_string());
+    }
+    if options.load.is_some() || options.save.is_some() || options.no_queries
+        || options.pcap.is_some() || options.record.is_some() || options.replay.is_some()
+        || options.checks.policy.is_some() || !options.checks.zone_files.is_empty() {
+      return Err("--daemon can't be used with --load, --save, --no-queries, --pcap, --record, \
+                  --replay, --policy or --zone-file".to_string());
+    }
+    if options.format != Format::Text {
+      return Err("--daemon only writes alerts, it can't be used with --format".to_string());
+    }
+  }
+  if options.replay.is_some() && (options.record.is_some() || options.pcap.is_some()) {
+    return Err("--replay can't be used with --record or --pcap".to_string());
+  }
+  if options.no_queries {
+    if options.load.is_none() {
+      return Err("--no-queries needs a database to --load".to_string());
+    }
+    if !options.targets.is_empty() || options.check_axfr || options.check_edns
+        || options.check_recursion || options.identify || options.pcap.is_some()
+        || options.record.is_some() || options.replay.is_some() {
+      return Err("--no-queries can't be used with names, probes, --pcap, --record or --replay"
+                 .to_string());
+    }
+  }
+
+  Ok(Some(options))
+}
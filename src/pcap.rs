@@ -0,0 +1,270 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{error, warn};
+
+/// Raw IPv4 or IPv6 packets, without a link layer header.
+const LINKTYPE_RAW: u32 = 101;
+
+/// Largest packet written, the most an IPv4 length field can hold.
+const MAX_PACKET: usize = 65535;
+
+const UDP_HEADER: usize = 8;
+const TCP_HEADER: usize = 20;
+
+const PROTO_TCP: u8 = 6;
+const PROTO_UDP: u8 = 17;
+
+/// TCP PSH and ACK flags, set on every synthesized segment.
+const TCP_PSH_ACK: u8 = 0x18;
+
+/// Where packets are being written, and the next TCP sequence number of
+/// each direction of each connection.
+#[derive(Debug)]
+struct CaptureFile {
+  file: File,
+  tcp_seq: HashMap<(SocketAddr, SocketAddr), u32>,
+}
+
+/// A pcap file every DNS message sent or received by a check is written
+/// to.
+///
+/// Messages are wrapped in synthesized IP and UDP or TCP headers, TCP
+/// connections only get their data segments. Capturing stops on the first
+/// error writing the file.
+#[derive(Debug)]
+pub struct Capture {
+  file: Mutex<Option<CaptureFile>>,
+}
+
+/// Internet checksum (RFC 1071) over the given chunks.
+fn checksum(chunks: &[&[u8]]) -> u16 {
+  let mut sum = 0u32;
+  for chunk in chunks {
+    for pair in chunk.chunks(2) {
+      let word = match pair {
+        [high, low] => u16::from_be_bytes([*high, *low]),
+        [high] => u16::from_be_bytes([*high, 0]),
+        _ => 0,
+      };
+      sum += word as u32;
+    }
+  }
+  while sum > 0xffff {
+    sum = (sum & 0xffff) + (sum >> 16);
+  }
+  !(sum as u16)
+}
+
+fn ip_header_len(ip: IpAddr) -> usize {
+  match ip {
+    IpAddr::V4(_) => 20,
+    IpAddr::V6(_) => 40,
+  }
+}
+
+fn ip_bytes(ip: IpAddr) -> Vec<u8> {
+  match ip {
+    IpAddr::V4(ip) => ip.octets().to_vec(),
+    IpAddr::V6(ip) => ip.octets().to_vec(),
+  }
+}
+
+/// Wrap a transport segment in an IP header, filling in the transport
+/// checksum at the given offset.
+///
+/// The packet must fit in `MAX_PACKET`, so lengths fit their fields.
+fn ip_packet(src: IpAddr, dst: IpAddr, proto: u8, mut segment: Vec<u8>,
+             checksum_at: usize) -> Vec<u8> {
+  let (src_bytes, dst_bytes) = (ip_bytes(src), ip_bytes(dst));
+  let len = segment.len();
+
+  // Checksums cover a pseudo header of the addresses, protocol and length.
+  let pseudo = match src {
+    IpAddr::V4(_) => {
+      let mut pseudo = vec![0, proto];
+      pseudo.extend_from_slice(&(len as u16).to_be_bytes());
+      pseudo
+    },
+    IpAddr::V6(_) => {
+      let mut pseudo = (len as u32).to_be_bytes().to_vec();
+      pseudo.extend_from_slice(&[0, 0, 0, proto]);
+      pseudo
+    },
+  };
+  let sum = checksum(&[&src_bytes, &dst_bytes, &pseudo, &segment]);
+  segment[checksum_at..checksum_at + 2].copy_from_slice(&sum.to_be_bytes());
+
+  let mut packet = Vec::new();
+  match src {
+    IpAddr::V4(_) => {
+      packet.extend_from_slice(&[0x45, 0]);
+      packet.extend_from_slice(&((20 + len) as u16).to_be_bytes());
+      // Identification, then don't fragment.
+      packet.extend_from_slice(&[0, 0, 0x40, 0]);
+      packet.extend_from_slice(&[64, proto, 0, 0]);
+      packet.extend_from_slice(&src_bytes);
+      packet.extend_from_slice(&dst_bytes);
+      let sum = checksum(&[&packet]);
+      packet[10..12].copy_from_slice(&sum.to_be_bytes());
+    },
+    IpAddr::V6(_) => {
+      packet.extend_from_slice(&[0x60, 0, 0, 0]);
+      packet.extend_from_slice(&(len as u16).to_be_bytes());
+      packet.extend_from_slice(&[proto, 64]);
+      packet.extend_from_slice(&src_bytes);
+      packet.extend_from_slice(&dst_bytes);
+    },
+  }
+  packet.extend_from_slice(&segment);
+  packet
+}
+
+impl CaptureFile {
+  fn write(&mut self, packet: &[u8]) -> io::Result<()> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+
+    let mut record = Vec::new();
+    record.extend_from_slice(&(now.as_secs() as u32).to_le_bytes());
+    record.extend_from_slice(&now.subsec_micros().to_le_bytes());
+    record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    record.extend_from_slice(packet);
+    self.file.write_all(&record)
+  }
+
+  fn udp(&mut self, src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> io::Result<()> {
+    // Datagrams can't be split without IP fragments, leave out the few that
+    // don't fit in a packet.
+    if ip_header_len(src.ip()) + UDP_HEADER + payload.len() > MAX_PACKET {
+      warn!("Not capturing {} byte UDP message from {} to {}, it's too large for a packet",
+            payload.len(), src, dst);
+      return Ok(());
+    }
+
+    let mut segment = Vec::new();
+    segment.extend_from_slice(&src.port().to_be_bytes());
+    segment.extend_from_slice(&dst.port().to_be_bytes());
+    segment.extend_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+    segment.extend_from_slice(&[0, 0]);
+    segment.extend_from_slice(payload);
+    self.write(&ip_packet(src.ip(), dst.ip(), PROTO_UDP, segment, 6))
+  }
+
+  /// Write TCP data, split into as many segments as needed.
+  fn tcp(&mut self, src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> io::Result<()> {
+    let max_segment = MAX_PACKET - ip_header_len(src.ip()) - TCP_HEADER;
+    for chunk in payload.chunks(max_segment) {
+      self.tcp_segment(src, dst, chunk)?;
+    }
+    Ok(())
+  }
+
+  fn tcp_segment(&mut self, src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> io::Result<()> {
+    let seq = *self.tcp_seq.entry((src, dst)).or_insert(1);
+    let ack = *self.tcp_seq.entry((dst, src)).or_insert(1);
+    self.tcp_seq.insert((src, dst), seq.wrapping_add(payload.len() as u32));
+
+    let mut segment = Vec::new();
+    segment.extend_from_slice(&src.port().to_be_bytes());
+    segment.extend_from_slice(&dst.port().to_be_bytes());
+    segment.extend_from_slice(&seq.to_be_bytes());
+    segment.extend_from_slice(&ack.to_be_bytes());
+    segment.extend_from_slice(&[5 << 4, TCP_PSH_ACK]);
+    segment.extend_from_slice(&65535u16.to_be_bytes());
+    segment.extend_from_slice(&[0, 0, 0, 0]);
+    segment.extend_from_slice(payload);
+    self.write(&ip_packet(src.ip(), dst.ip(), PROTO_TCP, segment, 16))
+  }
+}
+
+impl Capture {
+  /// Create a pcap file, truncating any existing one.
+  pub fn create(path: &Path) -> io::Result<Capture> {
+    let mut file = File::create(path)?;
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&0xa1b2_c3d4u32.to_le_bytes());
+    header.extend_from_slice(&2u16.to_le_bytes());
+    header.extend_from_slice(&4u16.to_le_bytes());
+    header.extend_from_slice(&0i32.to_le_bytes());
+    header.extend_from_slice(&0u32.to_le_bytes());
+    header.extend_from_slice(&(MAX_PACKET as u32).to_le_bytes());
+    header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+    file.write_all(&header)?;
+
+    let file = CaptureFile { file, tcp_seq: HashMap::new() };
+    Ok(Capture { file: Mutex::new(Some(file)) })
+  }
+
+  /// Run a write against the file, unless capturing stopped.
+  fn with_file<F: FnOnce(&mut CaptureFile) -> io::Result<()>>(&self, write: F) {
+    let mut file = self.file.lock().unwrap();
+    if let Some(active) = file.as_mut() {
+      if let Err(e) = write(active) {
+        error!("Can't write packet capture, stopping it: {}", e);
+        *file = None;
+      }
+    }
+  }
+
+  /// Capture a DNS message sent or received over UDP.
+  pub fn udp(&self, src: SocketAddr, dst: SocketAddr, message: &[u8]) {
+    self.with_file(|file| file.udp(src, dst, message));
+  }
+
+  /// Capture a DNS message sent or received over TCP, with its length
+  /// prefix.
+  pub fn tcp(&self, src: SocketAddr, dst: SocketAddr, message: &[u8]) {
+    self.with_file(|file| {
+      let mut framed = (message.len() as u16).to_be_bytes().to_vec();
+      framed.extend_from_slice(message);
+      file.tcp(src, dst, &framed)
+    });
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::env;
+  use std::fs;
+
+  use super::*;
+
+  /// Lengths of the packets in a capture file.
+  fn packet_lengths(path: &Path) -> Vec<usize> {
+    let data = fs::read(path).unwrap();
+    let mut lengths = Vec::new();
+    let mut at = 24;
+    while at < data.len() {
+      let len = u32::from_le_bytes([data[at + 8], data[at + 9], data[at + 10], data[at + 11]]);
+      lengths.push(len as usize);
+      at += 16 + len as usize;
+    }
+    lengths
+  }
+
+  #[test]
+  fn large_messages() {
+    let path = env::temp_dir().join(format!("dns-delegation-check-{}.pcap", std::process::id()));
+    let client: SocketAddr = "192.0.2.1:5353".parse().unwrap();
+    let server: SocketAddr = "192.0.2.53:53".parse().unwrap();
+
+    let capture = Capture::create(&path).unwrap();
+    capture.tcp(server, client, &vec![0; 65535]);
+    capture.udp(server, client, &vec![0; 65535]);
+    capture.udp(server, client, &vec![0; 512]);
+    drop(capture);
+    let lengths = packet_lengths(&path);
+    fs::remove_file(&path).unwrap();
+
+    // The TCP message and its length prefix take two segments, the UDP
+    // message too large for a packet is left out.
+    assert_eq!(lengths, vec![MAX_PACKET, 40 + 65537 - (MAX_PACKET - 40), 20 + 8 + 512]);
+  }
+}
@@ -0,0 +1,355 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+
+use serde::Deserialize;
+use trust_dns_client::proto::rr::dnssec::rdata::{DNSSECRData, DNSSECRecordType};
+use trust_dns_client::rr::{self, RData};
+
+use crate::db::{REntry, RecordDB};
+use crate::findings::{Check, Finding, Severity};
+
+/// Policy file, as written.
+///
+/// ```toml
+/// [zones."example.com."]
+/// ns = ["ns1.example.com.", "ns2.example.net."]
+/// min_ns = 2
+/// ds = ["12345 13 2 3F2B...A1"]
+///
+/// [zones."example.com.".glue]
+/// "ns1.example.com." = ["192.0.2.1", "2001:db8::1"]
+///
+/// [zones."example.com.".ttl]
+/// NS = [3600, 172800]
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PolicyFile {
+  #[serde(default)]
+  zones: BTreeMap<String, ZoneFile>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ZoneFile {
+  ns: Option<Vec<String>>,
+  min_ns: Option<usize>,
+  glue: Option<BTreeMap<String, Vec<IpAddr>>>,
+  ds: Option<Vec<String>>,
+  #[serde(default)]
+  ttl: BTreeMap<String, (u32, u32)>,
+}
+
+/// Expected state of a zone's delegation. Anything left out isn't checked.
+#[derive(Debug, Clone, Default)]
+pub struct ZonePolicy {
+  /// NS names, at the parent and at the zone itself.
+  pub ns: Option<BTreeSet<rr::Name>>,
+  /// Least number of NS names the parent must refer to.
+  pub min_ns: Option<usize>,
+  /// Glue addresses the parent must give, by NS name.
+  pub glue: Option<BTreeMap<rr::Name, BTreeSet<IpAddr>>>,
+  /// DS records at the parent, as "key-tag algorithm digest-type digest".
+  pub ds: Option<BTreeSet<String>>,
+  /// Allowed TTLs of records at the zone name, from any server, by record
+  /// type.
+  pub ttl: BTreeMap<rr::RecordType, (u32, u32)>,
+}
+
+/// Expected state of delegations, by zone.
+#[derive(Debug, Clone, Default)]
+pub struct Policy {
+  pub zones: BTreeMap<rr::Name, ZonePolicy>,
+}
+
+fn parse_name(name: &str) -> Result<rr::Name, String> {
+  rr::Name::from_str(name).map_err(|e| format!("invalid name '{}': {}", name, e))
+}
+
+/// Normalise a DS record as written, so digests compare case insensitively.
+fn parse_ds(ds: &str) -> Result<String, String> {
+  let fields: Vec<_> = ds.split_whitespace().collect();
+  let valid = fields.len() >= 4
+    && fields[0].parse::<u16>().is_ok()
+    && fields[1].parse::<u8>().is_ok()
+    && fields[2].parse::<u8>().is_ok();
+  if !valid {
+    return Err(format!("invalid DS '{}', expected KEYTAG ALGORITHM DIGESTTYPE DIGEST", ds));
+  }
+  Ok(format!("{} {} {} {}", fields[0], fields[1], fields[2], fields[3..].concat().to_uppercase()))
+}
+
+/// DS record data, in the same form as the policy file.
+fn ds_text(rdata: &RData) -> Option<String> {
+  match rdata {
+    RData::DNSSEC(DNSSECRData::DS(ds)) => {
+      let digest: String = ds.digest().iter().map(|b| format!("{:02X}", b)).collect();
+      Some(format!("{} {} {} {}", ds.key_tag(), u8::from(ds.algorithm()),
+                   u8::from(ds.digest_type()), digest))
+    },
+    _ => None,
+  }
+}
+
+/// Load a policy file.
+pub fn load(path: &Path) -> Result<Policy, String> {
+  parse(&fs::read_to_string(path).map_err(|e| e.to_string())?)
+}
+
+/// Parse a policy, as written in a policy file.
+fn parse(text: &str) -> Result<Policy, String> {
+  let file: PolicyFile = toml::from_str(text).map_err(|e| e.to_string())?;
+
+  let mut policy = Policy::default();
+  for (zone, spec) in file.zones {
+    let ns = match spec.ns {
+      Some(ns) => Some(ns.iter().map(|n| parse_name(n)).collect::<Result<_, _>>()?),
+      None => None,
+    };
+    let glue = match spec.glue {
+      Some(glue) => Some(glue.into_iter()
+        .map(|(ns, ips)| Ok((parse_name(&ns)?, ips.into_iter().collect())))
+        .collect::<Result<_, String>>()?),
+      None => None,
+    };
+    let ds = match spec.ds {
+      Some(ds) => Some(ds.iter().map(|ds| parse_ds(ds)).collect::<Result<_, _>>()?),
+      None => None,
+    };
+    let mut ttl = BTreeMap::new();
+    for (rtype, (min, max)) in spec.ttl {
+      let rtype = rr::RecordType::from_str(&rtype.to_uppercase())
+        .map_err(|e| format!("invalid record type '{}': {}", rtype, e))?;
+      if min > max {
+        return Err(format!("invalid {} TTL range {} to {}", rtype, min, max));
+      }
+      ttl.insert(rtype, (min, max));
+    }
+
+    policy.zones.insert(parse_name(&zone)?, ZonePolicy { ns, min_ns: spec.min_ns, glue, ds, ttl });
+  }
+
+  Ok(policy)
+}
+
+/// Expected values missing, and unexpected values present, as a message.
+fn mismatch<T: Ord + ToString>(what: &str, expected: &BTreeSet<T>, actual: &BTreeSet<T>)
+    -> Option<String> {
+  let join = |items: Vec<&T>| items.iter().map(|i| i.to_string()).collect::<Vec<_>>().join(", ");
+  let missing: Vec<_> = expected.difference(actual).collect();
+  let extra: Vec<_> = actual.difference(expected).collect();
+
+  match (missing.is_empty(), extra.is_empty()) {
+    (true, true) => None,
+    (false, true) => Some(format!("{} missing {}", what, join(missing))),
+    (true, false) => Some(format!("{} has unexpected {}", what, join(extra))),
+    (false, false) => Some(format!("{} missing {}, and has unexpected {}",
+                                   what, join(missing), join(extra))),
+  }
+}
+
+/// Check the gathered records against a policy.
+///
+/// NS names are checked on both sides of the zone cut, while glue and DS
+/// records are taken from the parent zone's servers.
+pub fn check_policy(record_db: &RecordDB, policy: &Policy) -> Vec<Finding> {
+  let mut findings = Vec::new();
+
+  for (zone, expected) in &policy.zones {
+    let parent_ns = record_db.get_parent_ns(zone);
+    let parent_servers = record_db.get_parent_zone(zone)
+      .map(|parent| record_db.get_zone_servers(&parent))
+      .unwrap_or_default();
+
+    if parent_ns.is_empty() {
+      findings.push(Finding::new(
+        Severity::Warning, Check::Policy, zone, None,
+        "no referral to the zone was seen, can't check policy".to_string()
+      ));
+      continue;
+    }
+
+    if let Some(ns) = &expected.ns {
+      let sides = [("parent NS", &parent_ns), ("zone NS", &record_db.get_child_ns(zone))];
+      for (what, actual) in sides.iter() {
+        if let Some(message) = mismatch(what, ns, actual) {
+          findings.push(Finding::new(Severity::Error, Check::Policy, zone, None, message));
+        }
+      }
+    }
+
+    if let Some(min_ns) = expected.min_ns {
+      if parent_ns.len() < min_ns {
+        findings.push(Finding::new(
+          Severity::Error, Check::Policy, zone, None,
+          format!("parent has {} NS names, expected at least {}", parent_ns.len(), min_ns)
+        ));
+      }
+    }
+
+    if let Some(glue) = &expected.glue {
+      for (ns, expected_ips) in glue {
+        let mut actual = BTreeSet::new();
+        for (server, entries) in record_db.get_records(ns) {
+          if !parent_servers.contains(&server) {
+            continue;
+          }
+          for rtype in &[rr::RecordType::A, rr::RecordType::AAAA] {
+            if let Some(REntry::Entries(items)) = entries.get(rtype) {
+              actual.extend(items.iter().filter_map(|(rdata, _)| rdata.to_ip_addr()));
+            }
+          }
+        }
+
+        if let Some(message) = mismatch(&format!("glue for {}", ns), expected_ips, &actual) {
+          findings.push(Finding::new(Severity::Error, Check::Policy, zone, None, message));
+        }
+      }
+    }
+
+    if let Some(ds) = &expected.ds {
+      let ds_type = rr::RecordType::DNSSEC(DNSSECRecordType::DS);
+      let mut actual = BTreeSet::new();
+      let mut answered = false;
+      for (server, entries) in record_db.get_records(zone) {
+        if !parent_servers.contains(&server) {
+          continue;
+        }
+        match entries.get(&ds_type) {
+          Some(REntry::Entries(items)) => {
+            answered = true;
+            actual.extend(items.iter().filter_map(|(rdata, _)| ds_text(rdata)));
+          },
+          Some(REntry::NoEntry) => answered = true,
+          _ => (),
+        }
+      }
+
+      if !answered {
+        findings.push(Finding::new(
+          Severity::Warning, Check::Policy, zone, None,
+          "no parent server answered for DS records, can't check them".to_string()
+        ));
+      } else if let Some(message) = mismatch("DS", ds, &actual) {
+        findings.push(Finding::new(Severity::Error, Check::Policy, zone, None, message));
+      }
+    }
+
+    for (rtype, (min, max)) in &expected.ttl {
+      for (server, ttl) in record_db.get_ttls(zone, *rtype) {
+        if ttl < *min || ttl > *max {
+          findings.push(Finding::new(
+            Severity::Warning, Check::Policy, zone, Some(&server),
+            format!("{} TTL {} outside of {} to {}", rtype, ttl, min, max)
+          ));
+        }
+      }
+    }
+  }
+
+  findings
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const PARENT: &str = "192.0.2.100";
+  const NS1: &str = "192.0.2.1";
+
+  fn name(name: &str) -> rr::Name {
+    rr::Name::from_str(name).unwrap()
+  }
+
+  fn add(db: &mut RecordDB, server: &str, owner: &str, ttl: u32, rdata: RData) {
+    db.add_record(&rr::Record::from_rdata(name(owner), ttl, rdata),
+                  server.parse().unwrap());
+  }
+
+  /// example.com delegated from com to ns1 and ns2, with glue for ns1,
+  /// and ns1 answering for the zone.
+  fn delegation() -> RecordDB {
+    let zone = name("example.com.");
+    let mut db = RecordDB::new();
+    add(&mut db, PARENT, "com.", 172800, RData::NS(name("a.gtld-servers.net.")));
+    add(&mut db, PARENT, "a.gtld-servers.net.", 172800, RData::A(PARENT.parse().unwrap()));
+    for ns in &["ns1.example.com.", "ns2.example.com."] {
+      db.add_delegation(&zone, &name("com."), &zone, &name(ns));
+      add(&mut db, PARENT, "example.com.", 172800, RData::NS(name(ns)));
+      add(&mut db, NS1, "example.com.", 3600, RData::NS(name(ns)));
+    }
+    add(&mut db, PARENT, "ns1.example.com.", 172800, RData::A(NS1.parse().unwrap()));
+    db
+  }
+
+  fn messages(policy: &str) -> Vec<String> {
+    check_policy(&delegation(), &parse(policy).unwrap()).into_iter()
+      .map(|finding| finding.message)
+      .collect()
+  }
+
+  #[test]
+  fn parse_policy() {
+    let policy = parse(r#"
+      [zones."example.com."]
+      ns = ["ns1.example.com.", "ns2.example.com."]
+      ds = ["12345 13 2 3f2b a1"]
+
+      [zones."example.com.".ttl]
+      ns = [3600, 172800]
+    "#).unwrap();
+
+    let zone = &policy.zones[&name("example.com.")];
+    assert_eq!(zone.ns.as_ref().unwrap().len(), 2);
+    assert!(zone.ds.as_ref().unwrap().contains("12345 13 2 3F2BA1"));
+    assert_eq!(zone.ttl[&rr::RecordType::NS], (3600, 172800));
+  }
+
+  #[test]
+  fn parse_errors() {
+    assert!(parse("[zones.\"example.com.\"]\nmax_ns = 2").is_err());
+    assert!(parse("[zones.\"example.com.\"]\nds = [\"12345 13\"]").is_err());
+    assert!(parse("[zones.\"example.com.\".ttl]\nNS = [600, 60]").is_err());
+  }
+
+  #[test]
+  fn matching_delegation_passes() {
+    assert_eq!(messages(r#"
+      [zones."example.com."]
+      ns = ["ns1.example.com.", "ns2.example.com."]
+      min_ns = 2
+
+      [zones."example.com.".glue]
+      "ns1.example.com." = ["192.0.2.1"]
+    "#), Vec::<String>::new());
+  }
+
+  #[test]
+  fn mismatches() {
+    let messages = messages(r#"
+      [zones."example.com."]
+      ns = ["ns1.example.com.", "ns3.example.com."]
+      min_ns = 3
+
+      [zones."example.com.".glue]
+      "ns1.example.com." = ["192.0.2.1", "2001:db8::1"]
+
+      [zones."example.com.".ttl]
+      NS = [300, 86400]
+    "#);
+    assert!(messages.contains(&"parent NS missing ns3.example.com., and has unexpected \
+                                 ns2.example.com.".to_string()));
+    assert!(messages.contains(&"parent has 2 NS names, expected at least 3".to_string()));
+    assert!(messages.contains(&"glue for ns1.example.com. missing 2001:db8::1".to_string()));
+    assert!(messages.contains(&"NS TTL 172800 outside of 300 to 86400".to_string()));
+  }
+
+  #[test]
+  fn zone_not_referred() {
+    assert_eq!(messages("[zones.\"example.net.\"]\nmin_ns = 2"),
+               vec!["no referral to the zone was seen, can't check policy"]);
+  }
+}
@@ -0,0 +1,339 @@
+use log::{debug, warn};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use trust_dns_client::op::{Message, ResponseCode};
+use trust_dns_client::rr;
+use trust_dns_client::rr::rdata::opt::{EdnsCode, EdnsOption};
+
+use crate::db::{Identity, RecordDB, Recursion, ZoneTransfer};
+use crate::dns::{self, Hooks, QueryError, QueryOptions};
+
+/// Whether a response to a recursion desired query shows the server
+/// recursed: recursion is available and the answer isn't authoritative.
+fn recursion_of(msg: &Message) -> Recursion {
+  if msg.recursion_available() && !msg.authoritative() && !msg.answers().is_empty() {
+    Recursion::Open
+  } else {
+    Recursion::Closed
+  }
+}
+
+/// Check whether each authoritative server will recurse for anyone.
+///
+/// A recursion desired query for an unrelated name is sent to every
+/// server address discovered. A server is open if it claims recursion is
+/// available and returns a non-authoritative answer.
+pub fn probe_recursion(record_db: &mut RecordDB, probe: &rr::Name) {
+  let options = QueryOptions {
+    recursion_desired: true,
+    ..QueryOptions::default()
+  };
+  let hooks = record_db.get_hooks();
+
+  for server in record_db.get_servers() {
+    let ip = match server.ip() {
+      Some(ip) => ip,
+      None => continue,
+    };
+    debug!("Probe recursion {}, {}", probe, ip);
+
+    let recursion = match dns::do_dns_query(ip, probe, rr::RecordType::A, &options, &hooks) {
+      Ok(msg) => recursion_of(&msg),
+      Err(QueryError::Timeout) => Recursion::NoAnswer,
+      Err(e) => {
+        warn!("Recursion probe of {} failed: {}", ip, e);
+        Recursion::NoAnswer
+      },
+    };
+
+    record_db.server_info_mut(&server).recursion = Some(recursion);
+  }
+}
+
+/// Check whether each server of an answer zone allows zone transfers.
+///
+/// An AXFR of each zone holding an answer target is attempted against
+/// every address of every NS name of the zone.
+pub fn probe_zone_transfers(record_db: &mut RecordDB) {
+  let hooks = record_db.get_hooks();
+  for zone in record_db.get_target_zones() {
+    for server in record_db.get_zone_servers(&zone) {
+      let ip = match server.ip() {
+        Some(ip) => ip,
+        None => continue,
+      };
+      debug!("Probe zone transfer {}, {}", zone, ip);
+
+      let transfer = transfer_result(dns::do_zone_transfer(ip, &zone, &hooks));
+      record_db.server_info_mut(&server).zone_transfers.insert(zone.clone(), transfer);
+    }
+  }
+}
+
+/// Classify a zone transfer attempt.
+///
+/// Records are counted without the closing SOA, so a zone is as large
+/// as its zone file.
+fn transfer_result(result: Result<dns::Transfer, QueryError>) -> ZoneTransfer {
+  let transfer = match result {
+    Ok(transfer) => transfer,
+    Err(e) => return ZoneTransfer::Failed(e.to_string()),
+  };
+  let records: usize = transfer.messages.iter().map(|msg| msg.answers().len()).sum();
+  let rcode = transfer.messages.last().map_or(ResponseCode::NoError, |msg| msg.response_code());
+
+  if let Some(e) = transfer.error {
+    ZoneTransfer::Partial(records, e.to_string())
+  } else if rcode != ResponseCode::NoError && records == 0 {
+    ZoneTransfer::Refused(rcode)
+  } else if rcode != ResponseCode::NoError {
+    ZoneTransfer::Partial(records, format!("server answered {}", rcode))
+  } else if records == 0 {
+    ZoneTransfer::Failed("empty response".to_string())
+  } else if !transfer.complete {
+    ZoneTransfer::Partial(records, "no closing SOA record".to_string())
+  } else {
+    ZoneTransfer::Allowed(records - 1)
+  }
+}
+
+/// Make a server provided identifier printable.
+///
+/// Identifiers are usually text, anything else is shown as hex.
+fn printable(bytes: &[u8]) -> String {
+  if bytes.iter().all(|b| (0x20..0x7f).contains(b)) {
+    String::from_utf8_lossy(bytes).into_owned()
+  } else {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+  }
+}
+
+/// Ask a server for its NSID, with a query for the root SOA.
+fn query_nsid(ip: IpAddr, hooks: &Hooks) -> Option<String> {
+  let options = QueryOptions {
+    edns_options: vec![EdnsOption::Unknown(EdnsCode::NSID.into(), Vec::new())],
+    ..QueryOptions::default()
+  };
+
+  match dns::do_dns_query(ip, &rr::Name::root(), rr::RecordType::SOA, &options, hooks) {
+    Ok(msg) => msg.edns()
+      .and_then(|edns| edns.option(EdnsCode::NSID))
+      .map(|nsid| printable(&Vec::<u8>::from(nsid))),
+    Err(e) => {
+      debug!("NSID query to {} failed: {}", ip, e);
+      None
+    },
+  }
+}
+
+/// Ask a server for a CHAOS TXT record, such as hostname.bind.
+fn query_chaos(ip: IpAddr, name: &str, hooks: &Hooks) -> Option<String> {
+  let options = QueryOptions {
+    class: rr::DNSClass::CH,
+    ..QueryOptions::default()
+  };
+  let name = rr::Name::from_str(name).unwrap();
+
+  match dns::do_dns_query(ip, &name, rr::RecordType::TXT, &options, hooks) {
+    Ok(msg) => msg.answers().iter()
+      .filter_map(|rec| rec.rdata().as_txt())
+      .map(|txt| {
+        txt.txt_data().iter()
+          .map(|data| printable(data))
+          .collect::<Vec<_>>()
+          .join("")
+      })
+      .next(),
+    Err(e) => {
+      debug!("CHAOS {} query to {} failed: {}", name, ip, e);
+      None
+    },
+  }
+}
+
+/// Ask every server discovered to identify itself.
+///
+/// The EDNS NSID option, and the CHAOS TXT names hostname.bind, id.server
+/// and version.bind are queried. Anycast instances usually answer with a
+/// name or code specific to the instance.
+pub fn probe_identities(record_db: &mut RecordDB) {
+  let hooks = record_db.get_hooks();
+  for server in record_db.get_servers() {
+    let ip = match server.ip() {
+      Some(ip) => ip,
+      None => continue,
+    };
+    debug!("Probe identity {}", ip);
+
+    let identity = Identity {
+      nsid: query_nsid(ip, &hooks),
+      hostname_bind: query_chaos(ip, "hostname.bind.", &hooks),
+      id_server: query_chaos(ip, "id.server.", &hooks),
+      version_bind: query_chaos(ip, "version.bind.", &hooks),
+    };
+
+    record_db.server_info_mut(&server).identity = Some(identity);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::env;
+  use std::fs;
+  use std::io::{Read, Write};
+  use std::net::{SocketAddr, TcpListener};
+  use std::sync::Arc;
+  use std::thread;
+
+  use data_encoding::HEXLOWER;
+  use trust_dns_client::op::MessageType;
+
+  use super::*;
+  use crate::db::{REntry, RServer, Transport};
+  use crate::fixture::{self, Exchange, Fixture, Session};
+  use crate::snapshot;
+
+  fn response(recursion_available: bool, authoritative: bool, answer: bool) -> Message {
+    let name = rr::Name::from_str("www.example.com.").unwrap();
+    let mut msg = Message::new();
+    msg.set_recursion_available(recursion_available);
+    msg.set_authoritative(authoritative);
+    if answer {
+      msg.add_answer(rr::Record::from_rdata(
+        name, 300, rr::RData::A("192.0.2.80".parse().unwrap())));
+    }
+    msg
+  }
+
+  #[test]
+  fn recursive_answer_is_open() {
+    assert_eq!(recursion_of(&response(true, false, true)), Recursion::Open);
+  }
+
+  #[test]
+  fn refusal_or_own_data_is_closed() {
+    // Recursion available but nothing answered, as for a REFUSED query.
+    assert_eq!(recursion_of(&response(true, false, false)), Recursion::Closed);
+    // A server authoritative for the probe name answers it itself.
+    assert_eq!(recursion_of(&response(true, true, true)), Recursion::Closed);
+    assert_eq!(recursion_of(&response(false, false, true)), Recursion::Closed);
+  }
+
+  /// Hooks answering recursion probes of each server from a fixture.
+  fn replay(probe: &rr::Name, answers: Vec<(IpAddr, Message)>) -> Hooks {
+    let fixture = Fixture {
+      version: fixture::VERSION,
+      exchanges: answers.into_iter()
+        .map(|(server, message)| Exchange {
+          server,
+          name: snapshot::encode_name(probe),
+          rtype: snapshot::encode_type(rr::RecordType::A),
+          transport: Transport::Udp,
+          responses: vec![fixture::Response::Message(HEXLOWER.encode(&message.to_vec().unwrap()))],
+        })
+        .collect(),
+    };
+    let path = env::temp_dir()
+      .join(format!("dns-delegation-check-recursion-{}.json", std::process::id()));
+    fs::write(&path, serde_json::to_string(&fixture).unwrap()).unwrap();
+    let session = Session::replay(&path);
+    fs::remove_file(&path).unwrap();
+    Hooks { fixture: Some(Arc::new(session.unwrap())), ..Hooks::default() }
+  }
+
+  #[test]
+  fn probe_servers() {
+    let probe = rr::Name::from_str("recursion-probe.example.net.").unwrap();
+    let open: IpAddr = "192.0.2.1".parse().unwrap();
+    let refusing: IpAddr = "192.0.2.2".parse().unwrap();
+    let silent: IpAddr = "192.0.2.3".parse().unwrap();
+
+    let mut refused = response(true, false, false);
+    refused.set_response_code(ResponseCode::Refused);
+
+    let mut db = RecordDB::new();
+    for server in &[open, refusing, silent] {
+      db.add_rentry(&rr::Name::from_str("example.com.").unwrap(), REntry::NoEntry,
+                    rr::RecordType::SOA, *server);
+    }
+    db.set_hooks(replay(&probe, vec![(open, response(true, false, true)), (refusing, refused)]));
+
+    probe_recursion(&mut db, &probe);
+    let recursion = |ip: IpAddr| db.get_server_info()[&RServer::from(ip)].recursion;
+    assert_eq!(recursion(open), Some(Recursion::Open));
+    assert_eq!(recursion(refusing), Some(Recursion::Closed));
+    assert_eq!(recursion(silent), Some(Recursion::NoAnswer));
+  }
+
+  /// Serve one zone transfer on a local port, closing the connection
+  /// after the given messages.
+  fn axfr_responder(messages: Vec<Message>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+      let (mut stream, _) = listener.accept().unwrap();
+      let mut len = [0; 2];
+      stream.read_exact(&mut len).unwrap();
+      let mut query = vec![0; u16::from_be_bytes(len) as usize];
+      stream.read_exact(&mut query).unwrap();
+      let id = Message::from_vec(&query).unwrap().id();
+      for mut msg in messages {
+        let bytes = msg.set_id(id).to_vec().unwrap();
+        stream.write_all(&(bytes.len() as u16).to_be_bytes()).unwrap();
+        stream.write_all(&bytes).unwrap();
+      }
+    });
+    addr
+  }
+
+  fn axfr_message(records: Vec<rr::Record>) -> Message {
+    let mut msg = Message::new();
+    msg.set_message_type(MessageType::Response);
+    msg.set_authoritative(true);
+    msg.insert_answers(records);
+    msg
+  }
+
+  fn transfer_from(messages: Vec<Message>) -> ZoneTransfer {
+    let zone = rr::Name::from_str("example.com.").unwrap();
+    transfer_result(dns::zone_transfer(axfr_responder(messages), &zone, &Hooks::default()))
+  }
+
+  #[test]
+  fn zone_transfers() {
+    let zone = rr::Name::from_str("example.com.").unwrap();
+    let soa = rr::Record::from_rdata(zone.clone(), 3600, rr::RData::SOA(rr::rdata::SOA::new(
+      rr::Name::from_str("ns1.example.com.").unwrap(),
+      rr::Name::from_str("hostmaster.example.com.").unwrap(),
+      1, 3600, 600, 86400, 300)));
+    let ns = rr::Record::from_rdata(zone.clone(), 3600,
+      rr::RData::NS(rr::Name::from_str("ns1.example.com.").unwrap()));
+    let a = rr::Record::from_rdata(rr::Name::from_str("www.example.com.").unwrap(), 3600,
+      rr::RData::A("192.0.2.80".parse().unwrap()));
+
+    // SOA, NS and A, then the closing SOA in a message of its own.
+    assert_eq!(transfer_from(vec![
+      axfr_message(vec![soa.clone(), ns.clone(), a.clone()]),
+      axfr_message(vec![soa.clone()]),
+    ]), ZoneTransfer::Allowed(3));
+    assert_eq!(transfer_from(vec![
+      axfr_message(vec![soa.clone(), ns.clone(), a.clone(), soa.clone()]),
+    ]), ZoneTransfer::Allowed(3));
+
+    // The connection closes before the closing SOA.
+    match transfer_from(vec![axfr_message(vec![soa.clone(), ns.clone()])]) {
+      ZoneTransfer::Partial(2, _) => (),
+      other => panic!("expected a partial transfer, got {:?}", other),
+    }
+
+    let mut refused = axfr_message(Vec::new());
+    refused.set_response_code(ResponseCode::Refused);
+    assert_eq!(transfer_from(vec![refused]), ZoneTransfer::Refused(ResponseCode::Refused));
+
+    match transfer_from(Vec::new()) {
+      ZoneTransfer::Failed(_) => (),
+      other => panic!("expected a failed transfer, got {:?}", other),
+    }
+  }
+}
@@ -0,0 +1,277 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use trust_dns_client::rr;
+
+use crate::db::{Health, REntry, RecordDB, RServer};
+use crate::findings::{Finding, Severity};
+
+/// Escape a label value.
+fn escape(value: &str) -> String {
+  value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn seconds(time: SystemTime) -> f64 {
+  time.duration_since(UNIX_EPOCH).map_or(0.0, |since| since.as_secs_f64())
+}
+
+/// Samples of one gauge, by label set.
+struct Gauge {
+  name: &'static str,
+  help: &'static str,
+  samples: Vec<(String, f64)>,
+}
+
+impl Gauge {
+  fn new(name: &'static str, help: &'static str) -> Gauge {
+    Gauge { name, help, samples: Vec::new() }
+  }
+
+  fn add(&mut self, labels: &[(&str, &str)], value: f64) {
+    let labels: Vec<_> = labels.iter()
+      .map(|(label, value)| format!("{}=\"{}\"", label, escape(value)))
+      .collect();
+    self.samples.push((labels.join(","), value));
+  }
+
+  fn write(&self, out: &mut String) {
+    writeln!(out, "# HELP {} {}", self.name, self.help).unwrap();
+    writeln!(out, "# TYPE {} gauge", self.name).unwrap();
+    for (labels, value) in &self.samples {
+      writeln!(out, "{}{{{}}} {}", self.name, labels, value).unwrap();
+    }
+  }
+}
+
+/// Render metrics in the Prometheus text format.
+///
+/// Per server of each answer zone: whether it answered, whether it answered
+/// for the zone itself, and the SOA serial it gave. Per server, its slowest
+/// answer and the queries sent to it by response code. Per zone, the
+/// number of findings by severity, and when a server last answered for it.
+pub fn render(record_db: &RecordDB, findings: &[Finding]) -> String {
+  let mut up = Gauge::new(
+    "dns_delegation_server_up", "Whether the server answered for the zone.");
+  let mut authoritative = Gauge::new(
+    "dns_delegation_server_authoritative", "Whether the server answered with the zone's SOA or NS.");
+  let mut serial = Gauge::new(
+    "dns_delegation_soa_serial", "SOA serial the server gave for the zone.");
+  let mut latency = Gauge::new(
+    "dns_delegation_server_latency_seconds", "Slowest answer of the server to any query.");
+  let mut query_count = Gauge::new(
+    "dns_delegation_queries", "Number of queries sent to the server, by response code.");
+  let mut finding_count = Gauge::new(
+    "dns_delegation_findings", "Number of findings about the zone, by severity.");
+  let mut last_success = Gauge::new(
+    "dns_delegation_last_success_timestamp_seconds", "When a server last answered for the zone.");
+
+  let zones = record_db.get_answer_zones();
+
+  for zone in &zones {
+    let zone_label = zone.to_string();
+    let records = record_db.get_records(zone);
+    let mut last = None;
+
+    for server in record_db.get_zone_servers(zone) {
+      let ip = match server.ip() {
+        Some(ip) => ip.to_string(),
+        None => continue,
+      };
+      let labels = [("zone", zone_label.as_str()), ("server", ip.as_str())];
+
+      let health = record_db.get_health(zone, &server);
+      if health == Health::Unknown {
+        continue;
+      }
+      up.add(&labels, if health == Health::TimeOut { 0.0 } else { 1.0 });
+      authoritative.add(&labels, if health == Health::Ok { 1.0 } else { 0.0 });
+
+      let soa = records.get(&server).and_then(|entries| entries.get(&rr::RecordType::SOA));
+      if let Some(REntry::Entries(items)) = soa {
+        if let Some(soa) = items.iter().filter_map(|(rdata, _)| rdata.as_soa()).next() {
+          serial.add(&labels, soa.serial() as f64);
+        }
+      }
+
+      if health == Health::Ok {
+        last = last.max(record_db.get_last_answer(&server));
+      }
+    }
+
+    if let Some(last) = last {
+      last_success.add(&[("zone", &zone_label)], seconds(last));
+    }
+  }
+
+  let mut queries: BTreeMap<(IpAddr, String), usize> = BTreeMap::new();
+  for query in record_db.get_query_log() {
+    let rcode = match &query.response {
+      Some(response) => response.rcode.to_string(),
+      None => "timeout".to_string(),
+    };
+    *queries.entry((query.server, rcode)).or_insert(0) += 1;
+  }
+  for ((ip, rcode), count) in &queries {
+    query_count.add(&[("server", &ip.to_string()), ("rcode", rcode)], *count as f64);
+  }
+  let servers: BTreeSet<_> = queries.keys().map(|(ip, _)| *ip).collect();
+  for ip in servers {
+    if let Some(rtt) = record_db.get_max_rtt(&RServer::from(ip)) {
+      latency.add(&[("server", &ip.to_string())], rtt.as_secs_f64());
+    }
+  }
+
+  // Findings count towards the deepest answer zone holding their name.
+  let mut counts: BTreeMap<(rr::Name, Severity), usize> = BTreeMap::new();
+  for zone in &zones {
+    for severity in &[Severity::Info, Severity::Warning, Severity::Error] {
+      counts.insert((zone.clone(), *severity), 0);
+    }
+  }
+  for finding in findings {
+    let zone = zones.iter()
+      .filter(|zone| zone.zone_of(&finding.name))
+      .max_by_key(|zone| zone.num_labels());
+    if let Some(zone) = zone {
+      *counts.entry((zone.clone(), finding.severity)).or_insert(0) += 1;
+    }
+  }
+  for ((zone, severity), count) in &counts {
+    let severity = severity.to_string().to_lowercase();
+    finding_count.add(&[("zone", &zone.to_string()), ("severity", &severity)], *count as f64);
+  }
+
+  let mut out = String::new();
+  for gauge in &[up, authoritative, serial, latency, query_count, finding_count, last_success] {
+    gauge.write(&mut out);
+  }
+  out
+}
+
+/// Write metrics in the Prometheus text format.
+///
+/// Redirect stdout to a file in the node_exporter textfile directory to
+/// have them collected.
+pub fn dump(out: &mut impl Write, record_db: &RecordDB, findings: &[Finding]) -> io::Result<()> {
+  out.write_all(render(record_db, findings).as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+  use std::str::FromStr;
+  use std::time::Duration;
+
+  use trust_dns_client::op::ResponseCode;
+
+  use super::*;
+  use crate::db::{LoggedQuery, QueryResponse, Transport};
+  use crate::findings::Check;
+
+  fn name(name: &str) -> rr::Name {
+    rr::Name::from_str(name).unwrap()
+  }
+
+  fn query(server: &str, response: Option<(u64, ResponseCode)>) -> LoggedQuery {
+    LoggedQuery {
+      name: name("www.example.com."),
+      rtype: rr::RecordType::A,
+      server: server.parse().unwrap(),
+      transport: Transport::Udp,
+      start: UNIX_EPOCH + Duration::from_secs(1_600_000_000),
+      attempts: 1,
+      response: response.map(|(rtt, rcode)| QueryResponse {
+        rtt: Duration::from_millis(rtt),
+        size: 100,
+        rcode,
+        flags: "qr aa".to_string(),
+      }),
+    }
+  }
+
+  /// Database with www.example.com in example.com, served by ns1 answering
+  /// with serial 7 and ns2 timing out.
+  fn record_db() -> RecordDB {
+    let mut db = RecordDB::new();
+    let www = name("www.example.com.");
+    let zone = name("example.com.");
+    db.add_answer_target(&www, rr::RecordType::A);
+    db.add_target(&www, rr::RecordType::A, &zone);
+
+    let ns1: IpAddr = "192.0.2.1".parse().unwrap();
+    for (ns, address) in &[("ns1.example.com.", "192.0.2.1"), ("ns2.example.com.", "192.0.2.2")] {
+      db.add_record(&rr::Record::from_rdata(zone.clone(), 3600, rr::RData::NS(name(ns))), ns1);
+      db.add_record(&rr::Record::from_rdata(name(ns), 3600,
+                                            rr::RData::A(address.parse().unwrap())), ns1);
+    }
+    db.add_record(&rr::Record::from_rdata(zone.clone(), 3600, rr::RData::SOA(rr::rdata::SOA::new(
+      name("ns1.example.com."), name("hostmaster.example.com."), 7, 3600, 600, 86400, 300))), ns1);
+    db.add_rentry(&zone, REntry::TimeOut, rr::RecordType::SOA, "192.0.2.2".parse().unwrap());
+
+    db.add_logged_query(query("192.0.2.1", Some((20, ResponseCode::NoError))));
+    db.add_logged_query(query("192.0.2.1", Some((250, ResponseCode::NXDomain))));
+    db.add_logged_query(query("192.0.2.2", None));
+    db
+  }
+
+  /// Samples of a metric, without the HELP and TYPE lines.
+  fn samples<'a>(metrics: &'a str, metric: &str) -> Vec<&'a str> {
+    metrics.lines()
+      .filter(|line| line.starts_with(&format!("{}{{", metric)))
+      .collect()
+  }
+
+  #[test]
+  fn metrics_rendered() {
+    let findings = vec![
+      Finding::new(Severity::Error, Check::Alias, &name("www.example.com."), None,
+                   "error".to_string()),
+      Finding::new(Severity::Warning, Check::Alias, &name("example.org."), None,
+                   "outside every zone".to_string()),
+    ];
+    let mut out = Vec::new();
+    dump(&mut out, &record_db(), &findings).unwrap();
+    let metrics = String::from_utf8(out).unwrap();
+
+    assert!(metrics.starts_with("# HELP dns_delegation_server_up Whether the server answered \
+                                 for the zone.\n# TYPE dns_delegation_server_up gauge\n"));
+    assert_eq!(samples(&metrics, "dns_delegation_server_up"), vec![
+      r#"dns_delegation_server_up{zone="example.com.",server="192.0.2.1"} 1"#,
+      r#"dns_delegation_server_up{zone="example.com.",server="192.0.2.2"} 0"#,
+    ]);
+    assert_eq!(samples(&metrics, "dns_delegation_server_authoritative"), vec![
+      r#"dns_delegation_server_authoritative{zone="example.com.",server="192.0.2.1"} 1"#,
+      r#"dns_delegation_server_authoritative{zone="example.com.",server="192.0.2.2"} 0"#,
+    ]);
+    assert_eq!(samples(&metrics, "dns_delegation_soa_serial"), vec![
+      r#"dns_delegation_soa_serial{zone="example.com.",server="192.0.2.1"} 7"#,
+    ]);
+    assert_eq!(samples(&metrics, "dns_delegation_server_latency_seconds"), vec![
+      r#"dns_delegation_server_latency_seconds{server="192.0.2.1"} 0.25"#,
+    ]);
+    assert_eq!(samples(&metrics, "dns_delegation_queries"), vec![
+      r#"dns_delegation_queries{server="192.0.2.1",rcode="No Error"} 1"#,
+      r#"dns_delegation_queries{server="192.0.2.1",rcode="Non-Existent Domain"} 1"#,
+      r#"dns_delegation_queries{server="192.0.2.2",rcode="timeout"} 1"#,
+    ]);
+    // Findings outside example.com count towards the root.
+    assert_eq!(samples(&metrics, "dns_delegation_findings"), vec![
+      r#"dns_delegation_findings{zone=".",severity="info"} 0"#,
+      r#"dns_delegation_findings{zone=".",severity="warning"} 1"#,
+      r#"dns_delegation_findings{zone=".",severity="error"} 0"#,
+      r#"dns_delegation_findings{zone="example.com.",severity="info"} 0"#,
+      r#"dns_delegation_findings{zone="example.com.",severity="warning"} 0"#,
+      r#"dns_delegation_findings{zone="example.com.",severity="error"} 1"#,
+    ]);
+    assert_eq!(samples(&metrics, "dns_delegation_last_success_timestamp_seconds"), vec![
+      r#"dns_delegation_last_success_timestamp_seconds{zone="example.com."} 1600000000.25"#,
+    ]);
+  }
+
+  #[test]
+  fn labels_escaped() {
+    assert_eq!(escape("a\\b\"c\nd"), "a\\\\b\\\"c\\nd");
+  }
+}
@@ -0,0 +1,484 @@
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::io::{self, Cursor, Read};
+use std::panic::{self, AssertUnwindSafe};
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use tiny_http::{Header, Method, Request, Response};
+use trust_dns_client::rr;
+
+use crate::cache::ReferralCache;
+use crate::findings::{CheckConfig, Finding};
+use crate::snapshot::{self, Snapshot};
+use crate::Checker;
+
+/// Finished checks kept for polling, the oldest are dropped first.
+const MAX_FINISHED: usize = 1000;
+
+/// Largest check request body accepted.
+const MAX_REQUEST_SIZE: u64 = 64 * 1024;
+
+/// Where to listen, and how many checks to run and queue.
+#[derive(Debug, Clone)]
+pub struct Config {
+  /// Address and port to listen on.
+  pub listen: String,
+  /// Checks run at the same time.
+  pub workers: usize,
+  /// Checks waiting for a worker, before more are refused.
+  pub queue: usize,
+}
+
+impl Default for Config {
+  fn default() -> Config {
+    Config {
+      listen: "127.0.0.1:8053".to_string(),
+      workers: 4,
+      queue: 100,
+    }
+  }
+}
+
+/// Probes to run with a check, all off unless asked for.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CheckOptions {
+  pub identify: bool,
+  pub check_axfr: bool,
+  pub check_edns: bool,
+  /// Name to ask for to check whether servers recurse.
+  pub recursion_probe: Option<String>,
+}
+
+/// A check, as submitted.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CheckRequest {
+  pub name: String,
+  /// Record types to resolve, A if none are given.
+  #[serde(default)]
+  pub types: Vec<String>,
+  #[serde(default)]
+  pub options: CheckOptions,
+}
+
+/// Where a check is at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+  Queued,
+  Running,
+  Done,
+  Failed,
+}
+
+/// A finding, as reported.
+#[derive(Debug, Serialize)]
+pub struct FindingReport {
+  pub severity: String,
+  pub check: String,
+  pub name: String,
+  pub server: Option<String>,
+  pub message: String,
+}
+
+impl FindingReport {
+  fn new(finding: &Finding) -> FindingReport {
+    FindingReport {
+      severity: finding.severity.to_string().to_lowercase(),
+      check: finding.check.to_string(),
+      name: snapshot::encode_name(&finding.name),
+      server: finding.server.as_ref().map(snapshot::encode_server),
+      message: finding.message.clone(),
+    }
+  }
+}
+
+/// What a check found, as reported.
+#[derive(Debug, Serialize)]
+pub struct CheckReport {
+  /// Severity of the most serious finding, None if there are none.
+  pub worst: Option<String>,
+  pub findings: Vec<FindingReport>,
+  /// Everything gathered, as saved with --save.
+  pub records: Snapshot,
+}
+
+/// A check to run, parsed from its request.
+#[derive(Debug, Clone)]
+struct Spec {
+  name: rr::Name,
+  types: Vec<rr::RecordType>,
+  options: CheckOptions,
+  recursion_probe: Option<rr::Name>,
+}
+
+impl Spec {
+  fn parse(request: CheckRequest) -> Result<Spec, String> {
+    let name = rr::Name::from_str(&request.name)
+      .map_err(|e| format!("invalid name '{}': {}", request.name, e))?;
+
+    let mut types = Vec::new();
+    for rtype in &request.types {
+      types.push(rr::RecordType::from_str(&rtype.to_uppercase())
+                 .map_err(|e| format!("invalid record type '{}': {}", rtype, e))?);
+    }
+    if types.is_empty() {
+      types.push(rr::RecordType::A);
+    }
+
+    let recursion_probe = match &request.options.recursion_probe {
+      Some(probe) => Some(rr::Name::from_str(probe)
+                          .map_err(|e| format!("invalid name '{}': {}", probe, e))?),
+      None => None,
+    };
+
+    Ok(Spec { name, types, options: request.options, recursion_probe })
+  }
+}
+
+/// A check submitted, with its report as JSON once done.
+struct Job {
+  spec: Spec,
+  status: Status,
+  error: Option<String>,
+  report: Option<String>,
+}
+
+/// Status of a check, as reported.
+#[derive(Serialize)]
+struct JobStatus<'a> {
+  id: u64,
+  name: String,
+  types: Vec<String>,
+  status: Status,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  error: Option<&'a str>,
+}
+
+impl<'a> JobStatus<'a> {
+  fn new(id: u64, job: &'a Job) -> JobStatus<'a> {
+    JobStatus {
+      id,
+      name: snapshot::encode_name(&job.spec.name),
+      types: job.spec.types.iter().map(|rtype| snapshot::encode_type(*rtype)).collect(),
+      status: job.status,
+      error: job.error.as_deref(),
+    }
+  }
+}
+
+/// Checks submitted, by ID.
+struct Jobs {
+  next_id: u64,
+  jobs: BTreeMap<u64, Job>,
+}
+
+impl Jobs {
+  /// Add a check, dropping the oldest finished ones beyond the limit.
+  fn add(&mut self, spec: Spec) -> u64 {
+    let finished: Vec<u64> = self.jobs.iter()
+      .filter(|(_, job)| job.status == Status::Done || job.status == Status::Failed)
+      .map(|(id, _)| *id)
+      .collect();
+    for id in finished.iter().take(finished.len().saturating_sub(MAX_FINISHED - 1)) {
+      self.jobs.remove(id);
+    }
+
+    self.next_id += 1;
+    let job = Job { spec, status: Status::Queued, error: None, report: None };
+    self.jobs.insert(self.next_id, job);
+    self.next_id
+  }
+}
+
+/// State shared by the workers and the request handler.
+struct Shared {
+  jobs: Mutex<Jobs>,
+  checks: CheckConfig,
+  cache: Arc<ReferralCache>,
+}
+
+impl Shared {
+  fn new(checks: CheckConfig) -> Shared {
+    Shared {
+      jobs: Mutex::new(Jobs { next_id: 0, jobs: BTreeMap::new() }),
+      checks,
+      cache: Arc::new(ReferralCache::new()),
+    }
+  }
+}
+
+/// Run a check, giving its report as JSON.
+fn run_check(shared: &Shared, spec: &Spec) -> Result<String, String> {
+  let mut checker = Checker::new()
+    .checks(shared.checks.clone())
+    .referral_cache(shared.cache.clone());
+  for rtype in &spec.types {
+    checker = checker.target(&spec.name, *rtype);
+  }
+  if spec.options.identify {
+    checker = checker.identify();
+  }
+  if spec.options.check_axfr {
+    checker = checker.check_axfr();
+  }
+  if spec.options.check_edns {
+    checker = checker.check_edns();
+  }
+  if let Some(probe) = &spec.recursion_probe {
+    checker = checker.check_recursion(probe);
+  }
+
+  let report = checker.run().map_err(|e| e.to_string())?;
+  let report = CheckReport {
+    worst: report.worst().map(|severity| severity.to_string().to_lowercase()),
+    findings: report.findings.iter().map(FindingReport::new).collect(),
+    records: report.records.to_snapshot().map_err(|e| e.to_string())?,
+  };
+  serde_json::to_string(&report).map_err(|e| e.to_string())
+}
+
+/// Error message of a check that panicked, with the panic's message when
+/// it has one.
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+  let message = payload.downcast_ref::<&str>().map(|message| message.to_string())
+    .or_else(|| payload.downcast_ref::<String>().cloned());
+  match message {
+    Some(message) => format!("check failed unexpectedly: {}", message),
+    None => "check failed unexpectedly".to_string(),
+  }
+}
+
+/// Run checks from the queue until it's closed.
+fn worker(shared: Arc<Shared>, queue: Arc<Mutex<Receiver<u64>>>) {
+  loop {
+    let id = match queue.lock().unwrap().recv() {
+      Ok(id) => id,
+      Err(_) => return,
+    };
+    let spec = match shared.jobs.lock().unwrap().jobs.get_mut(&id) {
+      Some(job) => {
+        job.status = Status::Running;
+        job.spec.clone()
+      },
+      None => continue,
+    };
+
+    info!("Checking {} (check {})", spec.name, id);
+    // A check blowing up shouldn't take the worker with it.
+    let result = panic::catch_unwind(AssertUnwindSafe(|| run_check(&shared, &spec)))
+      .unwrap_or_else(|payload| Err(panic_message(payload)));
+
+    if let Some(job) = shared.jobs.lock().unwrap().jobs.get_mut(&id) {
+      match result {
+        Ok(report) => {
+          job.status = Status::Done;
+          job.report = Some(report);
+        },
+        Err(e) => {
+          error!("Check {} of {} failed: {}", id, spec.name, e);
+          job.status = Status::Failed;
+          job.error = Some(e);
+        },
+      }
+    }
+  }
+}
+
+fn json_response(status: u16, body: String) -> Response<Cursor<Vec<u8>>> {
+  let content_type = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap();
+  Response::from_string(body).with_status_code(status).with_header(content_type)
+}
+
+fn error_response(status: u16, message: &str) -> Response<Cursor<Vec<u8>>> {
+  json_response(status, serde_json::json!({ "error": message }).to_string())
+}
+
+/// Queue a check from a request body.
+fn submit(shared: &Shared, queue: &SyncSender<u64>, request: &mut Request)
+    -> Response<Cursor<Vec<u8>>> {
+  let mut body = Vec::new();
+  if let Err(e) = request.as_reader().take(MAX_REQUEST_SIZE + 1).read_to_end(&mut body) {
+    return error_response(400, &format!("can't read request: {}", e));
+  }
+  if body.len() as u64 > MAX_REQUEST_SIZE {
+    return error_response(413, "request too large");
+  }
+  let spec = match serde_json::from_slice(&body).map_err(|e| e.to_string()).and_then(Spec::parse) {
+    Ok(spec) => spec,
+    Err(e) => return error_response(400, &e),
+  };
+
+  let id = shared.jobs.lock().unwrap().add(spec);
+  if let Err(e) = queue.try_send(id) {
+    shared.jobs.lock().unwrap().jobs.remove(&id);
+    return match e {
+      TrySendError::Full(_) => error_response(503, "too many checks queued, try again later"),
+      TrySendError::Disconnected(_) => error_response(503, "no workers left"),
+    };
+  }
+
+  let jobs = shared.jobs.lock().unwrap();
+  let status = serde_json::to_string(&JobStatus::new(id, &jobs.jobs[&id])).unwrap();
+  let location = Header::from_bytes(&b"Location"[..], format!("/checks/{}", id).as_bytes()).unwrap();
+  json_response(202, status).with_header(location)
+}
+
+/// Answer a request to the API.
+///
+/// POST /checks submits a check, GET /checks/ID gives its status, and
+/// GET /checks/ID/report its report once done.
+fn handle(shared: &Shared, queue: &SyncSender<u64>, request: &mut Request)
+    -> Response<Cursor<Vec<u8>>> {
+  let path = request.url().split('?').next().unwrap_or("").trim_matches('/').to_string();
+  let path: Vec<&str> = path.split('/').collect();
+  let id = path.get(1).and_then(|id| id.parse::<u64>().ok());
+
+  match (request.method(), path.as_slice()) {
+    (Method::Post, ["checks"]) => submit(shared, queue, request),
+    (Method::Get, ["checks", _]) | (Method::Get, ["checks", _, "report"]) => {
+      let jobs = shared.jobs.lock().unwrap();
+      let (id, job) = match id.and_then(|id| jobs.jobs.get(&id).map(|job| (id, job))) {
+        Some(found) => found,
+        None => return error_response(404, "no such check"),
+      };
+      if path.len() == 2 {
+        return json_response(200, serde_json::to_string(&JobStatus::new(id, job)).unwrap());
+      }
+      match (&job.report, job.status) {
+        (Some(report), _) => json_response(200, report.clone()),
+        (None, Status::Failed) => error_response(409, "check failed"),
+        (None, _) => error_response(409, "check isn't done yet"),
+      }
+    },
+    (_, ["checks"]) | (_, ["checks", _]) | (_, ["checks", _, "report"]) =>
+      error_response(405, "method not allowed"),
+    _ => error_response(404, "not found"),
+  }
+}
+
+/// Serve the HTTP API until the listener fails.
+///
+/// Checks are queued and run by a pool of workers, sharing a referral
+/// cache, and use the given settings for checks.
+pub fn run(config: &Config, checks: CheckConfig) -> io::Result<()> {
+  let server = tiny_http::Server::http(&config.listen)
+    .map_err(|e| io::Error::other(e.to_string()))?;
+  info!("Listening on {}", config.listen);
+
+  serve(&server, config, Arc::new(Shared::new(checks)));
+  Ok(())
+}
+
+/// Start the workers, and answer requests until the listener fails.
+fn serve(server: &tiny_http::Server, config: &Config, shared: Arc<Shared>) {
+  let (sender, receiver) = mpsc::sync_channel(config.queue);
+  let receiver = Arc::new(Mutex::new(receiver));
+  for _ in 0..config.workers {
+    let (shared, receiver) = (shared.clone(), receiver.clone());
+    thread::spawn(move || worker(shared, receiver));
+  }
+
+  for mut request in server.incoming_requests() {
+    let response = handle(&shared, &sender, &mut request);
+    if let Err(e) = request.respond(response) {
+      error!("Can't send response: {}", e);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Serve the API on a local port, without workers so checks stay queued.
+  fn start(queue: usize) -> (String, Arc<Shared>) {
+    let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/checks", server.server_addr().to_ip().unwrap());
+    let shared = Arc::new(Shared::new(CheckConfig::default()));
+    let config = Config { workers: 0, queue, ..Config::default() };
+    let serving = shared.clone();
+    thread::spawn(move || serve(&server, &config, serving));
+    (url, shared)
+  }
+
+  /// Status code and JSON body of a response, errors included.
+  fn call(request: ureq::Request, body: Option<&str>) -> (u16, serde_json::Value) {
+    let result = match body {
+      Some(body) => request.send_string(body),
+      None => request.call(),
+    };
+    let response = match result {
+      Ok(response) => response,
+      Err(ureq::Error::Status(_, response)) => response,
+      Err(e) => panic!("request failed: {}", e),
+    };
+    (response.status(), serde_json::from_str(&response.into_string().unwrap()).unwrap())
+  }
+
+  #[test]
+  fn submit_until_full() {
+    let (url, _) = start(1);
+    let (status, body) = call(ureq::post(&url), Some(r#"{"name": "example.com", "types": ["aaaa"]}"#));
+    assert_eq!(status, 202);
+    assert_eq!(body["id"], 1);
+    assert_eq!(body["name"], "example.com");
+    assert_eq!(body["types"], serde_json::json!(["AAAA"]));
+    assert_eq!(body["status"], "queued");
+
+    let (status, body) = call(ureq::post(&url), Some(r#"{"name": "example.org"}"#));
+    assert_eq!(status, 503);
+    assert_eq!(body["error"], "too many checks queued, try again later");
+
+    let (status, _) = call(ureq::post(&url), Some(r#"{"name": "example.org", "ttl": 1}"#));
+    assert_eq!(status, 400);
+  }
+
+  #[test]
+  fn poll_and_report() {
+    let (url, shared) = start(1);
+    let (_, body) = call(ureq::post(&url), Some(r#"{"name": "example.com"}"#));
+    let status_url = format!("{}/{}", url, body["id"]);
+    let report_url = format!("{}/report", status_url);
+
+    let (status, body) = call(ureq::get(&status_url), None);
+    assert_eq!(status, 200);
+    assert_eq!(body["status"], "queued");
+    let (status, body) = call(ureq::get(&report_url), None);
+    assert_eq!(status, 409);
+    assert_eq!(body["error"], "check isn't done yet");
+
+    // Finish the check, as a worker would.
+    {
+      let mut jobs = shared.jobs.lock().unwrap();
+      let job = jobs.jobs.get_mut(&1).unwrap();
+      job.status = Status::Done;
+      job.report = Some(r#"{"worst": null, "findings": []}"#.to_string());
+    }
+    let (_, body) = call(ureq::get(&status_url), None);
+    assert_eq!(body["status"], "done");
+    let (status, body) = call(ureq::get(&report_url), None);
+    assert_eq!(status, 200);
+    assert_eq!(body["findings"], serde_json::json!([]));
+
+    let (status, _) = call(ureq::get(&format!("{}/2", url)), None);
+    assert_eq!(status, 404);
+    let (status, _) = call(ureq::delete(&status_url), None);
+    assert_eq!(status, 405);
+  }
+
+  #[test]
+  fn panic_messages() {
+    let message = |f: fn()| panic_message(panic::catch_unwind(f).unwrap_err());
+    assert_eq!(message(|| panic!("no servers")), "check failed unexpectedly: no servers");
+    assert_eq!(message(|| panic!("no servers for {}", "example.com.")),
+               "check failed unexpectedly: no servers for example.com.");
+    assert_eq!(message(|| panic::panic_any(42)), "check failed unexpectedly");
+  }
+}
@@ -0,0 +1,542 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, UNIX_EPOCH};
+
+use data_encoding::HEXLOWER;
+use serde::{Deserialize, Serialize};
+use trust_dns_client::op::ResponseCode;
+use trust_dns_client::proto::error::ProtoError;
+use trust_dns_client::proto::serialize::binary::{BinDecodable, BinDecoder, BinEncodable, BinEncoder, Restrict};
+use trust_dns_client::rr::{self, RData};
+
+use crate::db::{self, AliasKind, EdnsResult, EdnsTest, Identity, RServer, Recursion, RecordDB, Transport};
+
+/// Version of the snapshot format, bumped whenever it changes.
+///
+/// Version 1 had no query log.
+pub const VERSION: u32 = 2;
+
+/// Oldest version of the snapshot format that can still be loaded.
+pub const OLDEST_VERSION: u32 = 1;
+
+/// Error returned when a snapshot can't be saved or loaded.
+#[derive(Debug)]
+pub enum SnapshotError {
+  /// The file couldn't be read or written.
+  Io(io::Error),
+  /// The file isn't valid JSON, or doesn't look like a snapshot.
+  Json(serde_json::Error),
+  /// The snapshot was written by an incompatible version.
+  Version(u32),
+  /// A name, type or record in the snapshot couldn't be decoded.
+  Invalid(String),
+}
+
+impl From<io::Error> for SnapshotError {
+  fn from(e: io::Error) -> SnapshotError {
+    SnapshotError::Io(e)
+  }
+}
+
+impl From<serde_json::Error> for SnapshotError {
+  fn from(e: serde_json::Error) -> SnapshotError {
+    SnapshotError::Json(e)
+  }
+}
+
+impl From<ProtoError> for SnapshotError {
+  fn from(e: ProtoError) -> SnapshotError {
+    SnapshotError::Invalid(e.to_string())
+  }
+}
+
+impl fmt::Display for SnapshotError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      SnapshotError::Io(e) => write!(f, "io error: {}", e),
+      SnapshotError::Json(e) => write!(f, "invalid snapshot: {}", e),
+      SnapshotError::Version(v) =>
+        write!(f, "unsupported snapshot version {}, expected {} to {}", v, OLDEST_VERSION, VERSION),
+      SnapshotError::Invalid(e) => write!(f, "invalid snapshot: {}", e),
+    }
+  }
+}
+
+/// The whole record database, in a form that can be written to disk.
+///
+/// Names are stored as text, and records in wire format (hex encoded), so
+/// any record type trust-dns can parse survives a round trip.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+  pub version: u32,
+  pub records: Vec<RecordSet>,
+  pub answer_targets: Vec<Target>,
+  pub address_targets: Vec<Target>,
+  pub targets: Vec<ZoneTarget>,
+  pub delegations: Vec<Delegation>,
+  pub negative_answers: Vec<Negative>,
+  pub aliases: Vec<Alias>,
+  pub servers: Vec<Server>,
+  /// Queries not yet sent when the snapshot was taken.
+  pub query_queue: Vec<Query>,
+  /// Queries sent, older snapshots don't have them.
+  #[serde(default)]
+  pub query_log: Vec<LoggedQuery>,
+}
+
+/// Records of one type for a name, from one server.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RecordSet {
+  pub name: String,
+  pub server: String,
+  pub rtype: String,
+  pub entry: Entry,
+}
+
+// Variants follow db::REntry.
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Entry {
+  NoEntry,
+  TimeOut,
+  /// Hex encoded record data, with its TTL.
+  Entries(Vec<(String, u32)>),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Target {
+  pub name: String,
+  pub rtype: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ZoneTarget {
+  pub name: String,
+  pub rtype: String,
+  pub zone: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Delegation {
+  pub name: String,
+  pub zone: String,
+  pub auth_zone: String,
+  pub ns: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Negative {
+  pub name: String,
+  pub rtype: String,
+  pub server: String,
+  pub nxdomain: bool,
+  /// Hex encoded authority section records.
+  pub authority: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Alias {
+  pub name: String,
+  pub rtype: String,
+  pub target: String,
+  pub kind: AliasKind,
+}
+
+/// Probe results for a server.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Server {
+  pub server: String,
+  pub edns: Option<Edns>,
+  pub identity: Option<Identity>,
+  pub recursion: Option<Recursion>,
+  pub zone_transfers: Vec<(String, ZoneTransfer)>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Edns {
+  pub zone: String,
+  pub results: BTreeMap<EdnsTest, EdnsResult>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ZoneTransfer {
+  Refused(u16),
+  Failed(String),
+  Partial(usize, String),
+  Allowed(usize),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Query {
+  pub name: String,
+  pub rtype: String,
+  pub server: IpAddr,
+  pub zone: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LoggedQuery {
+  pub name: String,
+  pub rtype: String,
+  pub server: IpAddr,
+  pub transport: Transport,
+  /// Milliseconds since the epoch.
+  pub start: u64,
+  pub attempts: u32,
+  pub response: Option<QueryResponse>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QueryResponse {
+  /// Round trip time, in microseconds.
+  pub rtt: u64,
+  pub size: usize,
+  pub rcode: u16,
+  pub flags: String,
+}
+
+/// Check a snapshot of the given version can be loaded.
+///
+/// Older versions are migrated by the serde defaults of fields added since.
+pub fn check_version(version: u32) -> Result<(), SnapshotError> {
+  if (OLDEST_VERSION..=VERSION).contains(&version) {
+    Ok(())
+  } else {
+    Err(SnapshotError::Version(version))
+  }
+}
+
+pub fn encode_name(name: &rr::Name) -> String {
+  name.to_string()
+}
+
+pub fn decode_name(name: &str) -> Result<rr::Name, SnapshotError> {
+  rr::Name::from_str(name)
+    .map_err(|e| SnapshotError::Invalid(format!("bad name '{}': {}", name, e)))
+}
+
+/// Record types are stored by name, or as TYPEnnn (RFC 3597) for types
+/// trust-dns has no name for.
+pub fn encode_type(rtype: rr::RecordType) -> String {
+  let name = rtype.to_string();
+  match rr::RecordType::from_str(&name) {
+    Ok(parsed) if parsed == rtype => name,
+    _ => format!("TYPE{}", u16::from(rtype)),
+  }
+}
+
+pub fn decode_type(rtype: &str) -> Result<rr::RecordType, SnapshotError> {
+  let invalid = || SnapshotError::Invalid(format!("bad record type '{}'", rtype));
+  if let Some(code) = rtype.strip_prefix("TYPE") {
+    let code = code.parse::<u16>().map_err(|_| invalid())?;
+    return Ok(rr::RecordType::from(code));
+  }
+  rr::RecordType::from_str(rtype).map_err(|_| invalid())
+}
+
+pub fn encode_server(server: &RServer) -> String {
+  match server.ip() {
+    Some(ip) => ip.to_string(),
+    None => "hint".to_string(),
+  }
+}
+
+pub fn decode_server(server: &str) -> Result<RServer, SnapshotError> {
+  if server == "hint" {
+    return Ok(RServer::Hint);
+  }
+  IpAddr::from_str(server)
+    .map(RServer::from)
+    .map_err(|_| SnapshotError::Invalid(format!("bad server '{}'", server)))
+}
+
+pub fn encode_rdata(rdata: &RData) -> Result<String, SnapshotError> {
+  let mut bytes = Vec::new();
+  rdata.emit(&mut BinEncoder::new(&mut bytes))?;
+  Ok(HEXLOWER.encode(&bytes))
+}
+
+pub fn decode_rdata(rtype: rr::RecordType, rdata: &str) -> Result<RData, SnapshotError> {
+  let bytes = decode_hex(rdata)?;
+  let mut decoder = BinDecoder::new(&bytes);
+  Ok(RData::read(&mut decoder, rtype, Restrict::new(bytes.len() as u16))?)
+}
+
+pub fn encode_record(record: &rr::Record) -> Result<String, SnapshotError> {
+  Ok(HEXLOWER.encode(&record.to_bytes()?))
+}
+
+pub fn decode_record(record: &str) -> Result<rr::Record, SnapshotError> {
+  Ok(rr::Record::from_bytes(&decode_hex(record)?)?)
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>, SnapshotError> {
+  HEXLOWER.decode(hex.as_bytes())
+    .map_err(|e| SnapshotError::Invalid(format!("bad hex '{}': {}", hex, e)))
+}
+
+impl Server {
+  pub fn new(server: &RServer, info: &db::ServerInfo) -> Server {
+    Server {
+      server: encode_server(server),
+      edns: info.edns.as_ref().map(|edns| Edns {
+        zone: encode_name(&edns.zone),
+        results: edns.results.clone(),
+      }),
+      identity: info.identity.clone(),
+      recursion: info.recursion,
+      zone_transfers: info.zone_transfers.iter()
+        .map(|(zone, transfer)| {
+          let transfer = match transfer {
+            db::ZoneTransfer::Refused(code) => ZoneTransfer::Refused(u16::from(*code)),
+            db::ZoneTransfer::Failed(e) => ZoneTransfer::Failed(e.clone()),
+            db::ZoneTransfer::Partial(records, e) => ZoneTransfer::Partial(*records, e.clone()),
+            db::ZoneTransfer::Allowed(records) => ZoneTransfer::Allowed(*records),
+          };
+          (encode_name(zone), transfer)
+        })
+        .collect(),
+    }
+  }
+
+  pub fn restore(&self) -> Result<(RServer, db::ServerInfo), SnapshotError> {
+    let edns = match &self.edns {
+      Some(edns) => Some(db::EdnsCompliance {
+        zone: decode_name(&edns.zone)?,
+        results: edns.results.clone(),
+      }),
+      None => None,
+    };
+
+    let mut zone_transfers = BTreeMap::new();
+    for (zone, transfer) in &self.zone_transfers {
+      let transfer = match transfer {
+        ZoneTransfer::Refused(code) => db::ZoneTransfer::Refused(
+          ResponseCode::from((*code >> 4) as u8, (*code & 0x0f) as u8)),
+        ZoneTransfer::Failed(e) => db::ZoneTransfer::Failed(e.clone()),
+        ZoneTransfer::Partial(records, e) => db::ZoneTransfer::Partial(*records, e.clone()),
+        ZoneTransfer::Allowed(records) => db::ZoneTransfer::Allowed(*records),
+      };
+      zone_transfers.insert(decode_name(zone)?, transfer);
+    }
+
+    let info = db::ServerInfo {
+      edns,
+      identity: self.identity.clone(),
+      recursion: self.recursion,
+      zone_transfers,
+    };
+    Ok((decode_server(&self.server)?, info))
+  }
+}
+
+impl LoggedQuery {
+  pub fn new(query: &db::LoggedQuery) -> LoggedQuery {
+    LoggedQuery {
+      name: encode_name(&query.name),
+      rtype: encode_type(query.rtype),
+      server: query.server,
+      transport: query.transport,
+      start: query.start.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64),
+      attempts: query.attempts,
+      response: query.response.as_ref().map(|response| QueryResponse {
+        rtt: response.rtt.as_micros() as u64,
+        size: response.size,
+        rcode: u16::from(response.rcode),
+        flags: response.flags.clone(),
+      }),
+    }
+  }
+
+  pub fn restore(&self) -> Result<db::LoggedQuery, SnapshotError> {
+    Ok(db::LoggedQuery {
+      name: decode_name(&self.name)?,
+      rtype: decode_type(&self.rtype)?,
+      server: self.server,
+      transport: self.transport,
+      start: UNIX_EPOCH + Duration::from_millis(self.start),
+      attempts: self.attempts,
+      response: self.response.as_ref().map(|response| db::QueryResponse {
+        rtt: Duration::from_micros(response.rtt),
+        size: response.size,
+        rcode: ResponseCode::from((response.rcode >> 4) as u8, (response.rcode & 0x0f) as u8),
+        flags: response.flags.clone(),
+      }),
+    })
+  }
+}
+
+/// Save the record database to a file.
+///
+/// The snapshot is written next to the file and renamed over it, so an
+/// interrupted save leaves the previous snapshot intact.
+pub fn save(record_db: &RecordDB, path: &Path) -> Result<(), SnapshotError> {
+  let snapshot = record_db.to_snapshot()?;
+
+  let mut tmp = path.as_os_str().to_owned();
+  tmp.push(".tmp");
+  {
+    let mut writer = BufWriter::new(File::create(&tmp)?);
+    serde_json::to_writer_pretty(&mut writer, &snapshot)?;
+    writer.flush()?;
+  }
+  fs::rename(&tmp, path)?;
+  Ok(())
+}
+
+/// Load a record database from a file.
+pub fn load(path: &Path) -> Result<RecordDB, SnapshotError> {
+  // Check the version before the layout, which may have changed.
+  let value: serde_json::Value = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+  let version = value.get("version").and_then(|v| v.as_u64())
+    .ok_or_else(|| SnapshotError::Invalid("no version".to_string()))?;
+  check_version(u32::try_from(version).unwrap_or(u32::MAX))?;
+
+  let snapshot: Snapshot = serde_json::from_value(value)?;
+  RecordDB::from_snapshot(&snapshot)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::env;
+  use std::time::SystemTime;
+
+  use trust_dns_client::rr::rdata::SOA;
+
+  use super::*;
+  use crate::db::{NegativeAnswer, REntry};
+
+  const NS1: &str = "192.0.2.1";
+
+  fn name(name: &str) -> rr::Name {
+    rr::Name::from_str(name).unwrap()
+  }
+
+  fn temp_path(test: &str) -> std::path::PathBuf {
+    env::temp_dir().join(format!("dns-delegation-check-{}-{}.json", test, std::process::id()))
+  }
+
+  /// A database with something in every part of a snapshot.
+  fn run() -> RecordDB {
+    let ns1: IpAddr = NS1.parse().unwrap();
+    let zone = name("example.com.");
+    let mut db = RecordDB::new();
+    db.add_answer_target(&name("www.example.com."), rr::RecordType::A);
+    db.add_address_target(&name("ns1.example.com."), rr::RecordType::AAAA);
+    db.add_target(&name("www.example.com."), rr::RecordType::A, &zone);
+    db.add_delegation(&zone, &name("com."), &zone, &name("ns1.example.com."));
+
+    db.add_record(&rr::Record::from_rdata(zone.clone(), 3600,
+                                          RData::NS(name("ns1.example.com."))), ns1);
+    db.add_record(&rr::Record::from_rdata(name("ns1.example.com."), 600,
+                                          RData::A(NS1.parse().unwrap())), ns1);
+    db.add_record(&rr::Record::from_rdata(name("www.example.com."), 300,
+                                          RData::CNAME(name("web.example.net."))), ns1);
+    db.add_rentry(&name("ns1.example.com."), REntry::TimeOut, rr::RecordType::AAAA, ns1);
+    db.add_alias(&name("www.example.com."), rr::RecordType::A, &name("web.example.net."),
+                 AliasKind::CName);
+
+    let soa = SOA::new(name("ns1.example.com."), name("hostmaster.example.com."),
+                       10, 7200, 3600, 1209600, 300);
+    db.add_rentry(&name("nx.example.com."), REntry::NoEntry, rr::RecordType::A, ns1);
+    db.add_negative_answer(&name("nx.example.com."), rr::RecordType::A, ns1, NegativeAnswer {
+      nxdomain: true,
+      authority: vec![rr::Record::from_rdata(zone.clone(), 300, RData::SOA(soa))],
+    });
+
+    let info = db.server_info_mut(&RServer::from(ns1));
+    info.recursion = Some(Recursion::Closed);
+    info.identity = Some(Identity { nsid: Some("ns1-ams".to_string()), ..Identity::default() });
+    info.edns = Some(db::EdnsCompliance {
+      zone: zone.clone(),
+      results: vec![(EdnsTest::Edns0, EdnsResult::Ok),
+                    (EdnsTest::UnknownFlag, EdnsResult::Deviates("flag echoed".to_string()))]
+        .into_iter().collect(),
+    });
+    info.zone_transfers.insert(zone.clone(), db::ZoneTransfer::Refused(ResponseCode::Refused));
+
+    db.queue_query(&name("web.example.net."), rr::RecordType::A, ns1, Some(&zone));
+    db.add_logged_query(db::LoggedQuery {
+      name: name("www.example.com."),
+      rtype: rr::RecordType::A,
+      server: ns1,
+      transport: Transport::Udp,
+      start: UNIX_EPOCH + Duration::from_millis(1_760_000_000_123),
+      attempts: 2,
+      response: Some(db::QueryResponse {
+        rtt: Duration::from_micros(23_456),
+        size: 120,
+        rcode: ResponseCode::NoError,
+        flags: "qr aa".to_string(),
+      }),
+    });
+    db
+  }
+
+  #[test]
+  fn save_and_load() {
+    let path = temp_path("snapshot");
+    let saved = run();
+    save(&saved, &path).unwrap();
+    let loaded = load(&path);
+    fs::remove_file(&path).unwrap();
+    let loaded = loaded.unwrap();
+
+    // Everything saved is restored.
+    let json = |db: &RecordDB| serde_json::to_value(db.to_snapshot().unwrap()).unwrap();
+    assert_eq!(json(&loaded), json(&saved));
+
+    let ns1 = RServer::from(NS1.parse::<IpAddr>().unwrap());
+    assert_eq!(loaded.get_ttls(&name("ns1.example.com."), rr::RecordType::A)[&ns1], 600);
+    assert!(matches!(loaded.get_records(&name("ns1.example.com."))[&ns1][&rr::RecordType::AAAA],
+                     REntry::TimeOut));
+    assert!(loaded.is_answer_target(&name("www.example.com."), rr::RecordType::A));
+    assert!(loaded.is_address_target(&name("ns1.example.com."), rr::RecordType::AAAA));
+    assert_eq!(loaded.alias_chain_length(&name("web.example.net."), rr::RecordType::A), 1);
+
+    let negative = &loaded.get_negative_answers()
+      [&(name("nx.example.com."), rr::RecordType::A)][&ns1];
+    assert!(negative.nxdomain);
+    assert_eq!(negative.authority.len(), 1);
+
+    let info = &loaded.get_server_info()[&ns1];
+    assert_eq!(info.recursion, Some(Recursion::Closed));
+    assert_eq!(info.identity.as_ref().unwrap().nsid.as_deref(), Some("ns1-ams"));
+    assert_eq!(info.edns.as_ref().unwrap().results.len(), 2);
+    assert!(matches!(info.zone_transfers[&name("example.com.")],
+                     db::ZoneTransfer::Refused(ResponseCode::Refused)));
+
+    let logged = &loaded.get_query_log()[0];
+    assert_eq!(logged.start.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis(),
+               1_760_000_000_123);
+    assert_eq!(logged.response.as_ref().unwrap().rtt, Duration::from_micros(23_456));
+  }
+
+  #[test]
+  fn older_versions() {
+    let mut snapshot = run().to_snapshot().unwrap();
+    snapshot.query_log.clear();
+
+    // Version 1 has no query log.
+    let mut value = serde_json::to_value(&snapshot).unwrap();
+    value["version"] = 1.into();
+    value.as_object_mut().unwrap().remove("query_log");
+    let path = temp_path("snapshot-v1");
+    fs::write(&path, value.to_string()).unwrap();
+    let loaded = load(&path);
+    fs::remove_file(&path).unwrap();
+    assert!(loaded.unwrap().get_query_log().is_empty());
+
+    snapshot.version = 0;
+    assert!(matches!(RecordDB::from_snapshot(&snapshot), Err(SnapshotError::Version(0))));
+    snapshot.version = VERSION + 1;
+    assert!(matches!(RecordDB::from_snapshot(&snapshot), Err(SnapshotError::Version(_))));
+  }
+}
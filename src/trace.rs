@@ -0,0 +1,197 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use trust_dns_client::rr::{self, RData};
+
+use crate::db::{REntry, RecordDB, RServer};
+use crate::findings::Finding;
+
+/// Record data as shown in the report.
+pub fn rdata_text(rdata: &RData) -> String {
+  match rdata {
+    RData::A(ip) => format!("A {}", ip),
+    RData::AAAA(ip) => format!("AAAA {}", ip),
+    RData::NS(name) => format!("NS {}", name),
+    RData::CNAME(name) => format!("CNAME {}", name),
+    _ => format!("{} {:?}", rdata.to_record_type(), rdata),
+  }
+}
+
+/// NS names of each server of a zone, to show next to its address.
+pub fn server_names(record_db: &RecordDB, zone: &rr::Name) -> BTreeMap<RServer, BTreeSet<rr::Name>> {
+  let mut names: BTreeMap<RServer, BTreeSet<rr::Name>> = BTreeMap::new();
+  for ns in record_db.get_record_set(zone, rr::RecordType::NS) {
+    let ns = match ns.as_ns() {
+      Some(ns) => ns,
+      None => continue,
+    };
+    for rtype in &[rr::RecordType::A, rr::RecordType::AAAA] {
+      for ip in record_db.get_record_set(ns, *rtype) {
+        if let Some(ip) = ip.to_ip_addr() {
+          names.entry(RServer::from(ip)).or_default().insert(ns.clone());
+        }
+      }
+    }
+  }
+  names
+}
+
+/// A server's address and NS names.
+fn describe_server(server: &RServer, names: &BTreeMap<RServer, BTreeSet<rr::Name>>) -> String {
+  let ip = server.ip().map(|ip| ip.to_string()).unwrap_or_else(|| "hint".to_string());
+  match names.get(server) {
+    Some(names) => {
+      let names: Vec<_> = names.iter().map(|name| name.to_string()).collect();
+      format!("@{} ({})", ip, names.join(", "))
+    },
+    None => format!("@{}", ip),
+  }
+}
+
+/// How the query for a name and type went, if it was sent to the server.
+pub fn describe_query(record_db: &RecordDB, name: &rr::Name, rtype: rr::RecordType,
+                      server: &RServer) -> Option<String> {
+  let query = record_db.get_logged_query(name, rtype, server)?;
+  let mut out = match &query.response {
+    Some(response) => format!("{} ms {}", response.rtt.as_millis(), query.transport),
+    None => "no answer".to_string(),
+  };
+  if query.attempts > 1 {
+    out.push_str(&format!(", {} attempts", query.attempts));
+  }
+  Some(out)
+}
+
+/// What a server of a zone referred to, and the glue it gave.
+fn describe_referral(record_db: &RecordDB, server: &RServer, name: &rr::Name,
+                     rtype: rr::RecordType, child: &rr::Name) -> String {
+  let entries = record_db.get_records(child).remove(server).unwrap_or_default();
+  let ns_names: Vec<rr::Name> = match entries.get(&rr::RecordType::NS) {
+    Some(REntry::Entries(items)) => items.iter().filter_map(|(rdata, _)| rdata.as_ns().cloned()).collect(),
+    _ => Vec::new(),
+  };
+
+  if ns_names.is_empty() {
+    return match record_db.get_records(name).get(server).and_then(|entries| entries.get(&rtype)) {
+      Some(REntry::TimeOut) => "timed out".to_string(),
+      Some(_) => "no referral".to_string(),
+      None => "not asked".to_string(),
+    };
+  }
+
+  let mut glue = Vec::new();
+  for ns in &ns_names {
+    let ns_entries = record_db.get_records(ns).remove(server).unwrap_or_default();
+    for rtype in &[rr::RecordType::A, rr::RecordType::AAAA] {
+      if let Some(REntry::Entries(items)) = ns_entries.get(rtype) {
+        glue.extend(items.iter().map(|(rdata, _)| format!("{} {}", ns, rdata_text(rdata))));
+      }
+    }
+  }
+
+  let ns_names: Vec<_> = ns_names.iter().map(|ns| ns.to_string()).collect();
+  let mut out = format!("-> {} NS {}", child, ns_names.join(", "));
+  if !glue.is_empty() {
+    out.push_str(&format!("; glue {}", glue.join(", ")));
+  }
+  out
+}
+
+/// What an authoritative server answered for the target.
+fn describe_answer(record_db: &RecordDB, server: &RServer, name: &rr::Name,
+                   rtype: rr::RecordType) -> String {
+  let entries = record_db.get_records(name).remove(server).unwrap_or_default();
+  match entries.get(&rtype) {
+    Some(REntry::Entries(items)) => items.iter()
+      .map(|(rdata, ttl)| format!("{} (ttl {})", rdata_text(rdata), ttl))
+      .collect::<Vec<_>>()
+      .join(", "),
+    Some(REntry::TimeOut) => "timed out".to_string(),
+    Some(REntry::NoEntry) => {
+      // Aliases are stored under their own type.
+      if let Some(REntry::Entries(items)) = entries.get(&rr::RecordType::CNAME) {
+        return items.iter().map(|(rdata, _)| rdata_text(rdata)).collect::<Vec<_>>().join(", ");
+      }
+      let negative = record_db.get_negative_answers()
+        .get(&(name.clone(), rtype))
+        .and_then(|servers| servers.get(server));
+      match negative {
+        Some(negative) if negative.nxdomain => "NXDOMAIN".to_string(),
+        Some(_) => "NODATA".to_string(),
+        None => "no answer".to_string(),
+      }
+    },
+    None => "not asked".to_string(),
+  }
+}
+
+/// Print a finding, and the identity of the server it names.
+fn print_finding(record_db: &RecordDB, finding: &Finding, indent: &str) {
+  println!("{}{}", indent, finding);
+  let identity = finding.server.as_ref().and_then(|server| record_db.get_identity(server));
+  if let Some(identity) = identity {
+    println!("{}  Server identity: {}", indent, identity);
+  }
+}
+
+/// Print findings about the given name (and server, if any).
+fn print_findings(record_db: &RecordDB, findings: &[Finding], name: &rr::Name,
+                  server: Option<&RServer>, indent: &str, shown: &mut BTreeSet<usize>) {
+  for (i, finding) in findings.iter().enumerate() {
+    if &finding.name == name && finding.server.as_ref() == server {
+      print_finding(record_db, finding, indent);
+      shown.insert(i);
+    }
+  }
+}
+
+/// Dump a dig +trace style report to stdout.
+///
+/// For each answer target, every zone it was referred through is shown from
+/// the root down, with what each server of the zone referred to (and the
+/// glue it gave), then the answer from each server of the last zone and how
+/// long it took. Findings are shown next to the zone, name or server they're
+/// about, and any left over at the end.
+pub fn dump(record_db: &RecordDB, findings: &[Finding]) {
+  let mut shown = BTreeSet::new();
+
+  for (name, rtype) in record_db.get_answer_targets() {
+    println!("{} {}", name, rtype);
+
+    let chain = record_db.get_referral_chain(&name, rtype);
+    for (i, zone) in chain.iter().enumerate() {
+      println!("  {}", zone);
+      print_findings(record_db, findings, zone, None, "    ", &mut shown);
+
+      let names = server_names(record_db, zone);
+      for server in record_db.get_zone_servers(zone) {
+        let result = match chain.get(i + 1) {
+          Some(child) => describe_referral(record_db, &server, &name, rtype, child),
+          None => describe_answer(record_db, &server, &name, rtype),
+        };
+        let query = describe_query(record_db, &name, rtype, &server)
+          .map(|query| format!(" [{}]", query))
+          .unwrap_or_default();
+        println!("    {} {}{}", describe_server(&server, &names), result, query);
+        print_findings(record_db, findings, zone, Some(&server), "      ", &mut shown);
+        if chain.get(i + 1).is_none() && zone != &name {
+          print_findings(record_db, findings, &name, Some(&server), "      ", &mut shown);
+        }
+      }
+    }
+
+    if !chain.iter().any(|zone| zone == &name) {
+      print_findings(record_db, findings, &name, None, "  ", &mut shown);
+    }
+    println!();
+  }
+
+  let others: Vec<_> = findings.iter().enumerate()
+    .filter(|(i, _)| !shown.contains(i))
+    .collect();
+  if !others.is_empty() {
+    println!("Other findings");
+    for (_, finding) in others {
+      print_finding(record_db, finding, "  ");
+    }
+  }
+}
@@ -0,0 +1,183 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use trust_dns_client::rr;
+
+use crate::db::{RecordDB, RServer};
+use crate::findings::{Check, Finding, Severity};
+
+/// Split record set TTLs into those from the given servers, and the rest.
+///
+/// Hints are left out of both.
+fn split_ttls(ttls: &BTreeMap<RServer, u32>, servers: &BTreeSet<RServer>)
+    -> (BTreeSet<u32>, BTreeSet<u32>) {
+  let mut inside = BTreeSet::new();
+  let mut outside = BTreeSet::new();
+
+  for (server, ttl) in ttls {
+    if *server == RServer::Hint {
+      continue;
+    } else if servers.contains(server) {
+      inside.insert(*ttl);
+    } else {
+      outside.insert(*ttl);
+    }
+  }
+
+  (inside, outside)
+}
+
+fn describe(ttls: &BTreeSet<u32>) -> String {
+  ttls.iter().map(|ttl| ttl.to_string()).collect::<Vec<_>>().join(", ")
+}
+
+/// Check the servers of a zone agree on the TTL of a record set.
+fn check_servers_agree(record_db: &RecordDB, zone: &rr::Name, name: &rr::Name,
+                       rtype: rr::RecordType) -> Option<Finding> {
+  let servers = record_db.get_zone_servers(zone);
+  let (ttls, _) = split_ttls(&record_db.get_ttls(name, rtype), &servers);
+
+  if ttls.len() > 1 {
+    Some(Finding::new(
+      Severity::Warning, Check::Ttl, zone, None,
+      format!("servers disagree on the TTL of {} {}: {}", name, rtype, describe(&ttls))
+    ))
+  } else {
+    None
+  }
+}
+
+/// Check TTLs of delegations and answers.
+///
+/// The parent side NS TTL is compared with the child side one, glue
+/// address TTLs with the authoritative ones, and the servers of a zone
+/// must agree on the TTL of each record set they serve.
+pub fn check_ttls(record_db: &RecordDB) -> Vec<Finding> {
+  let mut findings = Vec::new();
+
+  for zone in record_db.get_answer_zones() {
+    let servers = record_db.get_zone_servers(&zone);
+    let (child, parent) = split_ttls(&record_db.get_ttls(&zone, rr::RecordType::NS), &servers);
+
+    if !zone.is_root() && !child.is_empty() && !parent.is_empty() && child != parent {
+      findings.push(Finding::new(
+        Severity::Warning, Check::Ttl, &zone, None,
+        format!("parent NS TTL {} differs from child NS TTL {}",
+                describe(&parent), describe(&child))
+      ));
+    }
+
+    findings.extend(check_servers_agree(record_db, &zone, &zone, rr::RecordType::NS));
+    findings.extend(check_servers_agree(record_db, &zone, &zone, rr::RecordType::SOA));
+
+    // Glue comes from the parent, compare with what the zone holding the
+    // NS name says.
+    for ns in record_db.get_parent_ns(&zone) {
+      for rtype in &[rr::RecordType::A, rr::RecordType::AAAA] {
+        let ns_zone = match record_db.get_target_zone(&ns, *rtype) {
+          Some(ns_zone) => ns_zone,
+          None => continue,
+        };
+        let ns_servers = record_db.get_zone_servers(&ns_zone);
+        let (auth, glue) = split_ttls(&record_db.get_ttls(&ns, *rtype), &ns_servers);
+
+        if !auth.is_empty() && !glue.is_empty() && auth != glue {
+          findings.push(Finding::new(
+            Severity::Warning, Check::Ttl, &zone, None,
+            format!("glue TTL {} for {} {} differs from authoritative TTL {}",
+                    describe(&glue), ns, rtype, describe(&auth))
+          ));
+        }
+
+        findings.extend(check_servers_agree(record_db, &ns_zone, &ns, *rtype));
+      }
+    }
+  }
+
+  for (name, rtype) in record_db.get_answer_targets() {
+    if let Some(zone) = record_db.get_target_zone(&name, rtype) {
+      findings.extend(check_servers_agree(record_db, &zone, &name, rtype));
+    }
+  }
+
+  findings
+}
+
+#[cfg(test)]
+mod tests {
+  use std::net::IpAddr;
+  use std::str::FromStr;
+
+  use trust_dns_client::rr::rdata::SOA;
+  use trust_dns_client::rr::RData;
+
+  use super::*;
+
+  const PARENT: &str = "192.0.2.100";
+  const NS1: &str = "192.0.2.1";
+  const NS2: &str = "192.0.2.2";
+
+  fn name(name: &str) -> rr::Name {
+    rr::Name::from_str(name).unwrap()
+  }
+
+  fn add(db: &mut RecordDB, server: &str, owner: &str, ttl: u32, rdata: RData) {
+    let record = rr::Record::from_rdata(name(owner), ttl, rdata);
+    db.add_record(&record, server.parse::<IpAddr>().unwrap());
+  }
+
+  /// example.com, delegated from com with long TTLs, served by two servers.
+  fn delegation(child_ttl: u32) -> RecordDB {
+    let mut db = RecordDB::new();
+    db.add_answer_target(&name("example.com."), rr::RecordType::SOA);
+    db.add_target(&name("example.com."), rr::RecordType::SOA, &name("example.com."));
+    db.add_target(&name("ns1.example.com."), rr::RecordType::A, &name("example.com."));
+    db.add_delegation(&name("example.com."), &name("com."),
+                      &name("example.com."), &name("ns1.example.com."));
+
+    add(&mut db, PARENT, "example.com.", 172800, RData::NS(name("ns1.example.com.")));
+    add(&mut db, PARENT, "ns1.example.com.", 172800, RData::A(NS1.parse().unwrap()));
+    for server in &[NS1, NS2] {
+      add(&mut db, server, "example.com.", child_ttl, RData::NS(name("ns1.example.com.")));
+      add(&mut db, server, "example.com.", child_ttl, RData::NS(name("ns2.example.com.")));
+      add(&mut db, server, "ns1.example.com.", child_ttl, RData::A(NS1.parse().unwrap()));
+      add(&mut db, server, "ns2.example.com.", child_ttl, RData::A(NS2.parse().unwrap()));
+    }
+    db
+  }
+
+  fn soa() -> RData {
+    RData::SOA(SOA::new(name("ns1.example.com."), name("hostmaster.example.com."),
+                        1, 7200, 3600, 1209600, 3600))
+  }
+
+  #[test]
+  fn matching_ttls_pass() {
+    let db = delegation(172800);
+    assert!(check_ttls(&db).is_empty());
+  }
+
+  #[test]
+  fn parent_and_glue_mismatch() {
+    let db = delegation(3600);
+    let messages: Vec<_> = check_ttls(&db).into_iter().map(|f| f.message).collect();
+    assert!(messages.contains(
+      &"parent NS TTL 172800 differs from child NS TTL 3600".to_string()));
+    assert!(messages.contains(
+      &"glue TTL 172800 for ns1.example.com. A differs from authoritative TTL 3600".to_string()));
+  }
+
+  #[test]
+  fn servers_disagree() {
+    let mut db = delegation(172800);
+    add(&mut db, NS1, "example.com.", 3600, soa());
+    add(&mut db, NS2, "example.com.", 300, soa());
+
+    // The SOA is both the zone's and an answer target, so it's flagged twice
+    // until findings are deduplicated.
+    let findings = check_ttls(&db);
+    assert!(!findings.is_empty());
+    for finding in findings {
+      assert_eq!(finding.message, "servers disagree on the TTL of example.com. SOA: 300, 3600");
+    }
+  }
+}
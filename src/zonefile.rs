@@ -0,0 +1,302 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+
+use log::debug;
+use trust_dns_client::rr::{self, RData};
+use trust_dns_client::serialize::txt::{Lexer, Parser};
+
+use crate::db::{REntry, RecordDB};
+use crate::findings::{Check, Finding, Severity};
+
+/// RRsets from a master file, that the zone's servers must serve exactly.
+#[derive(Debug, Clone)]
+pub struct ZoneFile {
+  pub zone: rr::Name,
+  /// Expected records with their TTLs, empty where the RRset must not exist.
+  pub rrsets: BTreeMap<(rr::Name, rr::RecordType), Vec<(RData, u32)>>,
+}
+
+/// Load an RFC 1035 master file for a zone.
+///
+/// The apex SOA and NS, addresses of NS names inside the zone, and every
+/// RRset of the given names (along with their addresses, if any) are
+/// compared. DNSSEC records aren't, as servers are queried without DO.
+pub fn load(zone: &rr::Name, path: &Path, names: &[rr::Name]) -> Result<ZoneFile, String> {
+  let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+  parse(zone, &text, names)
+}
+
+/// Parse the text of a master file, as for `load`.
+fn parse(zone: &rr::Name, text: &str, names: &[rr::Name]) -> Result<ZoneFile, String> {
+  let (origin, records) = Parser::new()
+    .parse(Lexer::new(text), Some(zone.clone()))
+    .map_err(|e| e.to_string())?;
+  if &origin != zone {
+    return Err(format!("file is for {}, not {}", origin, zone));
+  }
+
+  let mut all: BTreeMap<(rr::Name, rr::RecordType), Vec<(RData, u32)>> = BTreeMap::new();
+  for rrset in records.values() {
+    if let rr::RecordType::DNSSEC(_) = rrset.record_type() {
+      continue;
+    }
+    let items = rrset.records_without_rrsigs().map(|rec| (rec.rdata().clone(), rec.ttl())).collect();
+    all.insert((rrset.name().clone(), rrset.record_type()), items);
+  }
+
+  let mut wanted = BTreeSet::new();
+  wanted.insert((zone.clone(), rr::RecordType::SOA));
+  wanted.insert((zone.clone(), rr::RecordType::NS));
+
+  let in_zone_ns = all.get(&(zone.clone(), rr::RecordType::NS)).into_iter()
+    .flat_map(|items| items.iter())
+    .filter_map(|(rdata, _)| rdata.as_ns())
+    .filter(|ns| zone.zone_of(ns));
+  for name in in_zone_ns.chain(names.iter()) {
+    if !zone.zone_of(name) {
+      return Err(format!("{} is not in {}", name, zone));
+    }
+    wanted.insert((name.clone(), rr::RecordType::A));
+    wanted.insert((name.clone(), rr::RecordType::AAAA));
+  }
+  for (name, rtype) in all.keys() {
+    if names.contains(name) {
+      wanted.insert((name.clone(), *rtype));
+    }
+  }
+
+  let rrsets = wanted.into_iter()
+    .map(|key| {
+      let items = all.get(&key).cloned().unwrap_or_default();
+      (key, items)
+    })
+    .collect();
+
+  Ok(ZoneFile { zone: zone.clone(), rrsets })
+}
+
+/// Ask every server of the zone for the RRsets of a zone file.
+///
+/// Answers are stored with the rest of the records, RRsets already
+/// answered by a server aren't asked for again.
+pub fn query_zone_file(record_db: &mut RecordDB, zone_file: &ZoneFile) {
+  for server in record_db.get_zone_servers(&zone_file.zone) {
+    let ip = match server.ip() {
+      Some(ip) => ip,
+      None => continue,
+    };
+
+    for (name, rtype) in zone_file.rrsets.keys() {
+      let answered = record_db.get_records(name).get(&server)
+        .is_some_and(|entries| entries.contains_key(rtype));
+      if !answered {
+        debug!("Queue zone file query {}, {}, {}", name, rtype, ip);
+        record_db.queue_query(name, *rtype, ip, None);
+      }
+    }
+  }
+
+  record_db.perform_queries();
+}
+
+/// Record data as shown in findings.
+fn describe(items: &BTreeSet<&RData>) -> String {
+  items.iter().map(|rdata| format!("{:?}", rdata)).collect::<Vec<_>>().join(", ")
+}
+
+/// Check every server of the zone serves exactly the RRsets of the file.
+pub fn check_zone_file(record_db: &RecordDB, zone_file: &ZoneFile) -> Vec<Finding> {
+  let mut findings = Vec::new();
+  let servers = record_db.get_zone_servers(&zone_file.zone);
+
+  if servers.is_empty() {
+    findings.push(Finding::new(
+      Severity::Warning, Check::ZoneFile, &zone_file.zone, None,
+      "no servers known for the zone, can't compare the zone file".to_string()
+    ));
+  }
+
+  for ((name, rtype), expected) in &zone_file.rrsets {
+    let records = record_db.get_records(name);
+
+    for server in &servers {
+      let served = match records.get(server).and_then(|entries| entries.get(rtype)) {
+        Some(REntry::Entries(items)) => items.clone(),
+        Some(REntry::NoEntry) => Vec::new(),
+        Some(REntry::TimeOut) => {
+          findings.push(Finding::new(
+            Severity::Warning, Check::ZoneFile, name, Some(server),
+            format!("timed out asking for {}, can't compare", rtype)
+          ));
+          continue;
+        },
+        None => continue,
+      };
+
+      let expected_set: BTreeSet<_> = expected.iter().map(|(rdata, _)| rdata).collect();
+      let served_set: BTreeSet<_> = served.iter().map(|(rdata, _)| rdata).collect();
+
+      if served_set.is_empty() && !expected_set.is_empty() {
+        findings.push(Finding::new(
+          Severity::Error, Check::ZoneFile, name, Some(server),
+          format!("{} RRset in the zone file isn't served", rtype)
+        ));
+      } else if expected_set.is_empty() && !served_set.is_empty() {
+        findings.push(Finding::new(
+          Severity::Error, Check::ZoneFile, name, Some(server),
+          format!("{} RRset isn't in the zone file, but is served: {}", rtype, describe(&served_set))
+        ));
+      } else if expected_set != served_set {
+        let missing: BTreeSet<_> = expected_set.difference(&served_set).cloned().collect();
+        let extra: BTreeSet<_> = served_set.difference(&expected_set).cloned().collect();
+        findings.push(Finding::new(
+          Severity::Error, Check::ZoneFile, name, Some(server),
+          format!("{} RRset differs from the zone file, missing: {}; extra: {}",
+                  rtype, describe(&missing), describe(&extra))
+        ));
+      } else {
+        let expected_ttl = expected.iter().map(|(_, ttl)| *ttl).min();
+        let served_ttl = served.iter().map(|(_, ttl)| *ttl).min();
+        if expected_ttl != served_ttl {
+          findings.push(Finding::new(
+            Severity::Warning, Check::ZoneFile, name, Some(server),
+            format!("{} TTL is {}, the zone file has {}",
+                    rtype, served_ttl.unwrap_or(0), expected_ttl.unwrap_or(0))
+          ));
+        }
+      }
+    }
+  }
+
+  findings
+}
+
+#[cfg(test)]
+mod tests {
+  use std::env;
+  use std::net::IpAddr;
+  use std::str::FromStr;
+
+  use super::*;
+
+  const NS1: &str = "192.0.2.1";
+
+  const ZONE: &str = "\
+$TTL 3600
+@    IN SOA ns1 hostmaster 10 7200 3600 1209600 300
+@    IN NS  ns1
+@    IN NS  ns2.example.net.
+ns1  IN A   192.0.2.1
+www  IN A   192.0.2.80
+www  IN TXT \"hello\"
+mail IN A   192.0.2.25
+";
+
+  fn name(name: &str) -> rr::Name {
+    rr::Name::from_str(name).unwrap()
+  }
+
+  fn server() -> IpAddr {
+    NS1.parse().unwrap()
+  }
+
+  fn zone_file() -> ZoneFile {
+    parse(&name("example.com."), ZONE, &[name("www.example.com.")]).unwrap()
+  }
+
+  fn add(db: &mut RecordDB, owner: &str, ttl: u32, rdata: RData) {
+    db.add_record(&rr::Record::from_rdata(name(owner), ttl, rdata), server());
+  }
+
+  /// NS1 serving the zone's apex and the address of ns1.
+  fn served() -> RecordDB {
+    let zone = zone_file();
+    let mut db = RecordDB::new();
+    for key in [(name("example.com."), rr::RecordType::SOA),
+                (name("example.com."), rr::RecordType::NS),
+                (name("ns1.example.com."), rr::RecordType::A)].iter() {
+      for (rdata, ttl) in &zone.rrsets[key] {
+        add(&mut db, &key.0.to_string(), *ttl, rdata.clone());
+      }
+    }
+    db.add_rentry(&name("ns1.example.com."), REntry::NoEntry, rr::RecordType::AAAA, server());
+    db
+  }
+
+  fn messages(db: &RecordDB) -> Vec<String> {
+    check_zone_file(db, &zone_file()).into_iter()
+      .map(|finding| format!("{} {}", finding.name, finding.message))
+      .collect()
+  }
+
+  #[test]
+  fn parse_wanted_rrsets() {
+    let zone = zone_file();
+    let keys: Vec<_> = zone.rrsets.keys()
+      .map(|(name, rtype)| format!("{} {}", name, rtype))
+      .collect();
+    // The apex, addresses of ns1, and everything of www; not mail.
+    assert_eq!(keys, vec![
+      "example.com. NS", "example.com. SOA",
+      "ns1.example.com. A", "ns1.example.com. AAAA",
+      "www.example.com. A", "www.example.com. TXT", "www.example.com. AAAA",
+    ]);
+    assert!(zone.rrsets[&(name("www.example.com."), rr::RecordType::AAAA)].is_empty());
+    assert_eq!(zone.rrsets[&(name("example.com."), rr::RecordType::NS)].len(), 2);
+  }
+
+  #[test]
+  fn parse_errors() {
+    let zone = name("example.com.");
+    assert!(parse(&zone, ZONE, &[name("www.example.org.")]).is_err());
+    assert!(parse(&zone, "@ IN SOA ns1", &[]).is_err());
+    assert!(load(&zone, &env::temp_dir().join("dns-delegation-check-missing.zone"), &[]).is_err());
+  }
+
+  #[test]
+  fn matching_servers_pass() {
+    let mut db = served();
+    add(&mut db, "www.example.com.", 3600, RData::A("192.0.2.80".parse().unwrap()));
+    add(&mut db, "www.example.com.", 3600, RData::TXT(rr::rdata::TXT::new(vec!["hello".to_string()])));
+    db.add_rentry(&name("www.example.com."), REntry::NoEntry, rr::RecordType::AAAA, server());
+    assert_eq!(messages(&db), Vec::<String>::new());
+  }
+
+  #[test]
+  fn differences() {
+    let mut db = served();
+    // Served, but with another address.
+    add(&mut db, "www.example.com.", 3600, RData::A("192.0.2.81".parse().unwrap()));
+    // In the file, but not served.
+    db.add_rentry(&name("www.example.com."), REntry::NoEntry, rr::RecordType::TXT, server());
+    // Not in the file, but served.
+    add(&mut db, "www.example.com.", 300, RData::AAAA("2001:db8::80".parse().unwrap()));
+
+    let messages = messages(&db);
+    assert_eq!(messages.len(), 3, "{:?}", messages);
+    assert!(messages[0].starts_with("www.example.com. A RRset differs from the zone file"));
+    assert_eq!(messages[1], "www.example.com. TXT RRset in the zone file isn't served");
+    assert!(messages[2].starts_with("www.example.com. AAAA RRset isn't in the zone file"));
+  }
+
+  #[test]
+  fn ttls_and_timeouts() {
+    let mut db = served();
+    add(&mut db, "www.example.com.", 60, RData::A("192.0.2.80".parse().unwrap()));
+    db.add_rentry(&name("www.example.com."), REntry::TimeOut, rr::RecordType::TXT, server());
+
+    assert_eq!(messages(&db), vec![
+      "www.example.com. A TTL is 60, the zone file has 3600",
+      "www.example.com. timed out asking for TXT, can't compare",
+    ]);
+  }
+
+  #[test]
+  fn no_servers() {
+    let messages = messages(&RecordDB::new());
+    assert_eq!(messages, vec![
+      "example.com. no servers known for the zone, can't compare the zone file",
+    ]);
+  }
+}